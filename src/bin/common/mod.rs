@@ -0,0 +1,246 @@
+//! Volume options shared by the binaries
+
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use vexfatbd::{
+    MapDirectoryOptions, MappingMode, PartitionTable, PartitionedBlockDevice, VexfatBuilder, VirtualExFatBlockDevice,
+};
+
+pub const VOLUME_USAGE: &str = "\
+volume options:
+    --sector-shift N       bytes per sector shift, 9..=12 (default 9)
+    --cluster-shift N      sectors per cluster shift (default 3, recommended one with --size)
+    --cluster-count N      number of clusters in the heap (default 262144)
+    --size BYTES           derive the cluster count from the volume size instead
+    --fat-align BYTES      align the FAT, with --size
+    --heap-align BYTES     align the cluster heap, e.g. to the erase block size, with --size
+    --label LABEL          volume label
+    --serial NUMBER        volume serial number (default random)
+    --write-back           guest writes to mapped files land in the host files
+    --partition mbr|gpt    wrap the volume into a partitioned disk, aligned to 1 MiB
+
+Host files are mapped into the root directory,
+host directories are mapped recursively under their own name.";
+
+pub struct VolumeSpec {
+    bytes_per_sector_shift: u8,
+    sectors_per_cluster_shift: Option<u8>,
+    cluster_count: u32,
+    volume_size: Option<u64>,
+    fat_alignment: Option<u64>,
+    cluster_heap_alignment: Option<u64>,
+    label: Option<String>,
+    volume_serial_number: Option<u32>,
+    write_back: bool,
+    partition_table: Option<PartitionTable>,
+    paths: Vec<PathBuf>,
+}
+
+impl Default for VolumeSpec {
+    fn default() -> Self {
+        Self {
+            bytes_per_sector_shift: 9,
+            sectors_per_cluster_shift: None,
+            cluster_count: 262144,
+            volume_size: None,
+            fat_alignment: None,
+            cluster_heap_alignment: None,
+            label: None,
+            volume_serial_number: None,
+            write_back: false,
+            partition_table: None,
+            paths: Vec::new(),
+        }
+    }
+}
+
+impl VolumeSpec {
+    /// Returns `false` if the argument is not a volume option
+    pub fn parse_arg<I>(&mut self, arg: &str, args: &mut I) -> Result<bool, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        let mut value = || args.next().ok_or(format!("missing value for {arg}"));
+        match arg {
+            "--sector-shift" => self.bytes_per_sector_shift = parse(arg, &value()?)?,
+            "--cluster-shift" => self.sectors_per_cluster_shift = Some(parse(arg, &value()?)?),
+            "--cluster-count" => self.cluster_count = parse(arg, &value()?)?,
+            "--size" => self.volume_size = Some(parse(arg, &value()?)?),
+            "--fat-align" => self.fat_alignment = Some(parse(arg, &value()?)?),
+            "--heap-align" => self.cluster_heap_alignment = Some(parse(arg, &value()?)?),
+            "--label" => self.label = Some(value()?),
+            "--serial" => self.volume_serial_number = Some(parse_serial(&value()?)?),
+            "--write-back" => self.write_back = true,
+            "--partition" => self.partition_table = Some(parse_partition_table(&value()?)?),
+            _ if arg.starts_with("--") => return Ok(false),
+            _ => self.paths.push(arg.into()),
+        }
+
+        Ok(true)
+    }
+
+    /// Volume, wrapped into a partitioned disk if requested
+    pub fn build_device(&self) -> Result<Device, String> {
+        let vexfat = self.build()?;
+        match self.partition_table {
+            Some(table) => PartitionedBlockDevice::new(vexfat, table)
+                .map(Device::Partitioned)
+                .map_err(|err| format!("invalid partition: {err:?}")),
+            None => Ok(Device::Volume(vexfat)),
+        }
+    }
+
+    pub fn build(&self) -> Result<VirtualExFatBlockDevice, String> {
+        let volume_serial_number = self.volume_serial_number.unwrap_or_else(rand::random);
+        let vexfat = match self.volume_size {
+            Some(volume_size) => {
+                let mut builder = VexfatBuilder::with_volume_size(volume_size)
+                    .bytes_per_sector_shift(self.bytes_per_sector_shift)
+                    .volume_serial_number(volume_serial_number);
+                if let Some(sectors_per_cluster_shift) = self.sectors_per_cluster_shift {
+                    builder = builder.sectors_per_cluster_shift(sectors_per_cluster_shift);
+                }
+                if let Some(fat_alignment) = self.fat_alignment {
+                    builder = builder.fat_alignment(fat_alignment);
+                }
+                if let Some(cluster_heap_alignment) = self.cluster_heap_alignment {
+                    builder = builder.cluster_heap_alignment(cluster_heap_alignment);
+                }
+                builder.build()
+            }
+            None if self.fat_alignment.is_some() || self.cluster_heap_alignment.is_some() => {
+                return Err("--fat-align and --heap-align require --size".to_string());
+            }
+            None => VirtualExFatBlockDevice::new_with_serial_number(
+                self.bytes_per_sector_shift,
+                self.sectors_per_cluster_shift.unwrap_or(3),
+                self.cluster_count,
+                volume_serial_number,
+            ),
+        };
+        let mut vexfat = vexfat.map_err(|err| format!("invalid geometry: {err:?}"))?;
+
+        if let Some(label) = &self.label {
+            vexfat.set_volume_label(label).map_err(|err| format!("invalid label: {err:?}"))?;
+        }
+        if self.write_back {
+            vexfat.set_mapping_mode(MappingMode::WriteBack);
+        }
+
+        let root_cluster = vexfat.root_directory_cluster();
+        for path in self.paths.iter() {
+            map_path(&mut vexfat, root_cluster, path).map_err(|err| format!("{}: {err}", path.display()))?;
+        }
+
+        Ok(vexfat)
+    }
+}
+
+fn parse<T>(arg: &str, value: &str) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value.parse().map_err(|err| format!("{arg}: {err}"))
+}
+
+/// Decimal or hexadecimal with `0x` prefix
+fn parse_serial(value: &str) -> Result<u32, String> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|err| format!("--serial: {err}"))
+}
+
+fn parse_partition_table(value: &str) -> Result<PartitionTable, String> {
+    match value {
+        "mbr" => Ok(PartitionTable::Mbr),
+        "gpt" => Ok(PartitionTable::Gpt),
+        _ => Err(format!("--partition: unknown partition table {value}")),
+    }
+}
+
+fn map_path(vexfat: &mut VirtualExFatBlockDevice, root_cluster: u32, path: &Path) -> Result<(), String> {
+    if !path.is_dir() {
+        vexfat.map_file(root_cluster, path).map_err(|err| format!("{err:?}"))?;
+        return Ok(());
+    }
+
+    let name = path
+        .canonicalize()
+        .map_err(|err| err.to_string())?
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or("directory has no name")?;
+    let dir_cluster = vexfat.add_directory(root_cluster, &name).map_err(|err| format!("{err:?}"))?;
+    let report = vexfat
+        .map_directory(dir_cluster, path, &MapDirectoryOptions::default())
+        .map_err(|err| format!("{err:?}"))?;
+    for (skipped, reason) in report.skipped {
+        eprintln!("skipped {}: {reason:?}", skipped.display());
+    }
+
+    Ok(())
+}
+
+pub enum Device {
+    Volume(VirtualExFatBlockDevice),
+    Partitioned(PartitionedBlockDevice),
+}
+
+// not every binary uses all of them
+#[allow(dead_code)]
+impl Device {
+    pub fn bytes_per_sector(&self) -> u16 {
+        match self {
+            Device::Volume(vexfat) => vexfat.bytes_per_sector(),
+            Device::Partitioned(disk) => disk.bytes_per_sector(),
+        }
+    }
+
+    /// In bytes
+    pub fn size(&self) -> u64 {
+        match self {
+            Device::Volume(vexfat) => vexfat.volume_size(),
+            Device::Partitioned(disk) => disk.disk_size(),
+        }
+    }
+}
+
+impl Read for Device {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Device::Volume(vexfat) => vexfat.read(buf),
+            Device::Partitioned(disk) => disk.read(buf),
+        }
+    }
+}
+
+impl Write for Device {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Device::Volume(vexfat) => vexfat.write(buf),
+            Device::Partitioned(disk) => disk.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Device::Volume(vexfat) => vexfat.flush(),
+            Device::Partitioned(disk) => disk.flush(),
+        }
+    }
+}
+
+impl Seek for Device {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Device::Volume(vexfat) => vexfat.seek(pos),
+            Device::Partitioned(disk) => disk.seek(pos),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Serves a virtual exFAT volume over NBD, one client at a time
+//!
+//! ```text
+//! vexfatbd-nbd (--unix PATH | --tcp ADDRESS) [OPTIONS] [HOST PATH]...
+//! ```
+//!
+//! Attach with `nbd-client -unix PATH /dev/nbd0` or `qemu-system-* -drive file=nbd+unix:///?socket=PATH`.
+
+#[cfg(unix)]
+use std::{os::unix::net::UnixListener, path::PathBuf};
+use std::{
+    io::{Read, Write},
+    net::TcpListener,
+    process::ExitCode,
+};
+
+use vexfatbd::nbd;
+
+mod common;
+
+use common::{Device, VolumeSpec, VOLUME_USAGE};
+
+const USAGE: &str = "\
+usage: vexfatbd-nbd (--unix PATH | --tcp ADDRESS) [OPTIONS] [HOST PATH]...
+
+options:
+    --unix PATH            listen on a Unix socket, not available on every platform
+    --tcp ADDRESS          listen on a TCP address, e.g. 127.0.0.1:10809
+    --read-only            refuse writes from the client
+";
+
+enum Listen {
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+struct Args {
+    listen: Listen,
+    read_only: bool,
+    volume: VolumeSpec,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut listen = None;
+    let mut read_only = false;
+    let mut volume = VolumeSpec::default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            #[cfg(unix)]
+            "--unix" => listen = Some(Listen::Unix(args.next().ok_or("missing value for --unix")?.into())),
+            #[cfg(not(unix))]
+            "--unix" => return Err("--unix is not supported on this platform".to_string()),
+            "--tcp" => listen = Some(Listen::Tcp(args.next().ok_or("missing value for --tcp")?)),
+            "--read-only" => read_only = true,
+            "-h" | "--help" => return Err(format!("{USAGE}\n{VOLUME_USAGE}")),
+            _ => {
+                if !volume.parse_arg(&arg, &mut args)? {
+                    return Err(format!("unknown option {arg}"));
+                }
+            }
+        }
+    }
+
+    Ok(Args {
+        listen: listen.ok_or("either --unix or --tcp is required")?,
+        read_only,
+        volume,
+    })
+}
+
+fn serve_client<S: Read + Write>(device: &mut Device, stream: S, read_only: bool) {
+    let block_size = u32::from(device.bytes_per_sector());
+    if let Err(err) = nbd::serve(device, stream, read_only, block_size) {
+        eprintln!("client error: {err:?}");
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut device = match args.volume.build_device() {
+        Ok(device) => device,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let result = match &args.listen {
+        #[cfg(unix)]
+        Listen::Unix(path) => UnixListener::bind(path).and_then(|listener| {
+            for stream in listener.incoming() {
+                serve_client(&mut device, stream?, args.read_only);
+            }
+            Ok(())
+        }),
+        Listen::Tcp(address) => TcpListener::bind(address).and_then(|listener| {
+            for stream in listener.incoming() {
+                let stream = stream?;
+                stream.set_nodelay(true)?;
+                serve_client(&mut device, stream, args.read_only);
+            }
+            Ok(())
+        }),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
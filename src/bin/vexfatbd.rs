@@ -0,0 +1,129 @@
+//! Builds a virtual exFAT volume and dumps it into a raw image
+//!
+//! ```text
+//! vexfatbd [--output PATH] [OPTIONS] [HOST PATH]...
+//! ```
+//!
+//! Zeroed regions are skipped when writing into a file, so the image is sparse.
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+mod common;
+
+use common::{Device, VolumeSpec, VOLUME_USAGE};
+
+const USAGE: &str = "\
+usage: vexfatbd [--output PATH] [OPTIONS] [HOST PATH]...
+
+options:
+    -o, --output PATH      image file to write, standard output if omitted
+";
+
+/// Bytes read from the device at once
+const CHUNK_SIZE: usize = 1 << 20;
+
+struct Args {
+    output: Option<PathBuf>,
+    volume: VolumeSpec,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut output = None;
+    let mut volume = VolumeSpec::default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(args.next().ok_or(format!("missing value for {arg}"))?.into()),
+            "-h" | "--help" => return Err(format!("{USAGE}\n{VOLUME_USAGE}")),
+            _ => {
+                if !volume.parse_arg(&arg, &mut args)? {
+                    return Err(format!("unknown option {arg}"));
+                }
+            }
+        }
+    }
+
+    Ok(Args { output, volume })
+}
+
+/// Copy the whole device, chunks which are all zeroes are seeked over if `sparse`
+fn dump<W: Write + Seek>(device: &mut Device, output: &mut W, sparse: bool) -> io::Result<()> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    device.seek(SeekFrom::Start(0))?;
+
+    loop {
+        let read = device.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+
+        let chunk = &buffer[..read];
+        if sparse && chunk.iter().all(|&byte| byte == 0) {
+            output.seek(SeekFrom::Current(read as i64))?;
+        } else {
+            output.write_all(chunk)?;
+        }
+    }
+
+    output.flush()
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut device = match args.volume.build_device() {
+        Ok(device) => device,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let result = match &args.output {
+        Some(path) => File::create(path).and_then(|mut file| {
+            dump(&mut device, &mut file, true)?;
+            // trailing zeroes were seeked over
+            file.set_len(device.size())
+        }),
+        None => dump(&mut device, &mut NoSeek(io::stdout().lock()), false),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Standard output can't seek, only used without `sparse`
+struct NoSeek<W>(W);
+
+impl<W: Write> Write for NoSeek<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W> Seek for NoSeek<W> {
+    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
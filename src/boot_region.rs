@@ -1,3 +1,5 @@
+use arbitrary_int::u12;
+use bitbybit::bitfield;
 use bytemuck::{Pod, Zeroable};
 
 #[derive(Copy, Clone, Debug, Zeroable, Pod)]
@@ -112,7 +114,7 @@ pub struct BootSector {
     /// The `volume_flags` field shall contain flags which indicate the status of various file system structures on the exFAT volume (see Table 5).
     ///
     /// Implementations shall not include this field when computing its respective Main Boot or Backup Boot region checksum. When referring to the Backup Boot Sector, implementations shall treat this field as stale.
-    pub volume_flags: u16,
+    pub volume_flags: VolumeFlags,
 
     /// The `bytes_per_sector_shift` field shall describe the bytes per sector expressed as log2(N), where N is the number of bytes per sector. For example, for 512 bytes per sector, the value of this field is 9.
     ///
@@ -167,3 +169,150 @@ pub struct BootSector {
     /// The valid value for this field is `AA55h`. Any other value in this field invalidates its respective Boot Sector. Implementations should verify the contents of this field prior to depending on any other field in its respective Boot Sector.
     pub boot_signature: [u8; 2],
 }
+
+#[bitfield(u16)]
+#[derive(Debug, Zeroable, Pod, PartialEq)]
+pub struct VolumeFlags {
+    /// The `active_fat` field shall describe which FAT and Allocation Bitmap are active (and implementations shall use), as follows:
+    /// - `0`, which means the First FAT and First Allocation Bitmap are active
+    /// - `1`, which means the Second FAT and Second Allocation Bitmap are active and is possible only when the `number_of_fats` field contains the value 2
+    ///
+    /// Implementations shall consider the inactive FAT and Allocation Bitmap as stale.
+    /// Only TexFAT-aware implementations shall switch the active FAT and Allocation Bitmaps.
+    #[bit(0, rw)]
+    active_fat: bool,
+
+    /// The `volume_dirty` field shall describe whether the volume is dirty or not, as follows:
+    /// - `0`, which means the volume is probably in a consistent state
+    /// - `1`, which means the volume is probably in an inconsistent state
+    ///
+    /// Implementations should set the value of this field to 1 upon encountering file system metadata inconsistencies which they do not resolve.
+    /// If, upon mounting a volume, the value of this field is 1, only implementations which resolve file system metadata inconsistencies may clear the value of this field to 0.
+    #[bit(1, rw)]
+    volume_dirty: bool,
+
+    /// The `media_failure` field shall describe whether an implementation has discovered media failures or not, as follows:
+    /// - `0`, which means the hosting media has not reported failures or any known failures are already recorded in the FAT as "bad" clusters
+    /// - `1`, which means the hosting media has reported failures (i.e. has failed read or write operations)
+    #[bit(2, rw)]
+    media_failure: bool,
+
+    /// The `clear_to_zero` field does not have significant meaning in this specification.
+    ///
+    /// The valid values for this field are:
+    /// - `0`, which does not have any particular meaning
+    /// - `1`, which means implementations shall clear this field to 0 prior to modifying any file system structures, directories, or files
+    #[bit(3, rw)]
+    clear_to_zero: bool,
+
+    #[bits(4..=15, rw)]
+    reserved: u12,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BootRegionError {
+    /// There are 8 extended boot sectors
+    InvalidExtendedBootSector,
+
+    /// Must be at most `bytes_per_sector - 4` bytes, the rest is the extended boot signature
+    ExtendedBootCodeTooLong,
+
+    /// There are 10 parameters in the OEM parameters sector
+    TooManyOemParameters,
+}
+
+/// Each of the 10 parameters in the OEM parameters sector,
+/// parameters with all-zero `parameters_guid` are null parameters
+#[derive(Copy, Clone, Debug, Zeroable, Pod, PartialEq)]
+#[repr(C)]
+pub struct OemParameters {
+    /// The `parameters_guid` field shall describe a GUID, which determines the layout of the remainder of the given parameters structure.
+    ///
+    /// All possible values for this field are valid; however, manufacturers should use a GUID-generating tool to select a GUID when extending this specification.
+    pub parameters_guid: [u8; 16],
+
+    pub custom_defined: [u8; 32],
+}
+
+impl From<FlashParameters> for OemParameters {
+    fn from(flash_parameters: FlashParameters) -> Self {
+        bytemuck::cast(flash_parameters)
+    }
+}
+
+/// Parameters of flash media, all fields are optional and 0 means the value is not available
+#[derive(Copy, Clone, Debug, Zeroable, Pod, PartialEq)]
+#[repr(C)]
+pub struct FlashParameters {
+    /// The `parameters_guid` field shall conform to the definition provided in the Generic Parameters template.
+    ///
+    /// The valid value for this field, in GUID notation, is {0A0C7E46-3399-4021-90C8-FA6D389C4BA2}.
+    pub parameters_guid: [u8; 16],
+
+    /// The `erase_block_size` field shall describe the size, in bytes, of the flash media's erase block.
+    pub erase_block_size: u32,
+
+    /// The `page_size` field shall describe the size, in bytes of the flash media's page.
+    pub page_size: u32,
+
+    /// The `spare_sectors` field shall describe the number of sectors the flash media has available for its internal sparing operations.
+    pub spare_sectors: u32,
+
+    /// The `random_access_time` field shall describe the flash media's average random access time, in nanoseconds.
+    pub random_access_time: u32,
+
+    /// The `programming_time` field shall describe the flash media's average programming time, in nanoseconds.
+    pub programming_time: u32,
+
+    /// The `read_cycle` field shall describe the flash media's average read cycle time, in nanoseconds.
+    pub read_cycle: u32,
+
+    /// The `write_cycle` field shall describe the average write cycle time, in nanoseconds.
+    pub write_cycle: u32,
+
+    pub reserved: [u8; 4],
+}
+
+impl FlashParameters {
+    /// {0A0C7E46-3399-4021-90C8-FA6D389C4BA2}, as stored on disk
+    pub const GUID: [u8; 16] = [
+        0x46, 0x7E, 0x0C, 0x0A, 0x99, 0x33, 0x21, 0x40, 0x90, 0xC8, 0xFA, 0x6D, 0x38, 0x9C, 0x4B, 0xA2,
+    ];
+}
+
+impl Default for FlashParameters {
+    fn default() -> Self {
+        Self {
+            parameters_guid: Self::GUID,
+            ..Zeroable::zeroed()
+        }
+    }
+}
+
+/// Checksum of the first 11 sectors of a boot region, `volume_flags` and `percent_in_use` are skipped
+pub fn boot_checksum(sectors: &[u8]) -> u32 {
+    let mut checksum = 0u32;
+
+    for (index, byte) in sectors.iter().enumerate() {
+        if index == 106 || index == 107 || index == 112 {
+            continue;
+        }
+
+        checksum = (if checksum & 1 > 0 { 0x80000000 } else { 0 }) + (checksum >> 1) + u32::from(*byte);
+    }
+
+    checksum
+}
+
+#[test]
+fn flash_parameters() {
+    let flash_parameters = FlashParameters {
+        erase_block_size: 128 << 10,
+        page_size: 4096,
+        ..Default::default()
+    };
+    let oem_parameters = OemParameters::from(flash_parameters);
+    assert_eq!(oem_parameters.parameters_guid, FlashParameters::GUID);
+    assert_eq!(&oem_parameters.custom_defined[..8], &[0, 0, 2, 0, 0, 16, 0, 0]);
+    assert_eq!(&oem_parameters.custom_defined[8..], &[0; 24]);
+}
@@ -0,0 +1,280 @@
+use crate::data_region::volume_label::VolumeLabelDirectoryEntry;
+use crate::{Layout, VexfatError, VirtualExFatBlockDevice, MAX_CLUSTER_COUNT};
+
+/// Creates [`VirtualExFatBlockDevice`] from the volume size,
+/// picking cluster size recommended by Microsoft unless specified explicitly
+#[derive(Debug, Clone)]
+pub struct VexfatBuilder {
+    volume_size: u64,
+    bytes_per_sector_shift: u8,
+    sectors_per_cluster_shift: Option<u8>,
+    number_of_fats: u8,
+    fat_alignment: Option<u64>,
+    cluster_heap_alignment: Option<u64>,
+    volume_serial_number: Option<u32>,
+    volume_label: Option<String>,
+}
+
+impl VexfatBuilder {
+    /// `volume_size` in bytes, the emulated volume is at most this big
+    pub fn with_volume_size(volume_size: u64) -> Self {
+        Self {
+            volume_size,
+            bytes_per_sector_shift: 9,
+            sectors_per_cluster_shift: None,
+            number_of_fats: 1,
+            fat_alignment: None,
+            cluster_heap_alignment: None,
+            volume_serial_number: None,
+            volume_label: None,
+        }
+    }
+
+    /// 9..=12, 512 bytes by default
+    pub fn bytes_per_sector_shift(mut self, bytes_per_sector_shift: u8) -> Self {
+        self.bytes_per_sector_shift = bytes_per_sector_shift;
+        self
+    }
+
+    /// Overrides the recommended cluster size
+    pub fn sectors_per_cluster_shift(mut self, sectors_per_cluster_shift: u8) -> Self {
+        self.sectors_per_cluster_shift = Some(sectors_per_cluster_shift);
+        self
+    }
+
+    /// 1 or 2, second FAT and allocation bitmap are used by TexFAT
+    pub fn number_of_fats(mut self, number_of_fats: u8) -> Self {
+        self.number_of_fats = number_of_fats;
+        self
+    }
+
+    /// In bytes, a multiple of the sector size, FAT directly follows the boot regions by default
+    pub fn fat_alignment(mut self, fat_alignment: u64) -> Self {
+        self.fat_alignment = Some(fat_alignment);
+        self
+    }
+
+    /// In bytes, a multiple of the sector size, e.g. erase block size of flash media,
+    /// cluster heap directly follows the FAT by default
+    pub fn cluster_heap_alignment(mut self, cluster_heap_alignment: u64) -> Self {
+        self.cluster_heap_alignment = Some(cluster_heap_alignment);
+        self
+    }
+
+    /// Random by default
+    pub fn volume_serial_number(mut self, volume_serial_number: u32) -> Self {
+        self.volume_serial_number = Some(volume_serial_number);
+        self
+    }
+
+    pub fn volume_label(mut self, volume_label: &str) -> Self {
+        self.volume_label = Some(volume_label.to_string());
+        self
+    }
+
+    /// Cluster size Windows formats the volume with, in bytes
+    fn recommended_bytes_per_cluster(&self) -> u64 {
+        const MIB: u64 = 1 << 20;
+        const GIB: u64 = 1 << 30;
+
+        match self.volume_size {
+            size if size <= 256 * MIB => 4 << 10,
+            size if size <= 32 * GIB => 32 << 10,
+            _ => 128 << 10,
+        }
+    }
+
+    fn sectors_per_cluster_shift_or_recommended(&self) -> u8 {
+        self.sectors_per_cluster_shift.unwrap_or_else(|| {
+            let bytes_per_cluster_shift = self.recommended_bytes_per_cluster().trailing_zeros() as u8;
+            bytes_per_cluster_shift.saturating_sub(self.bytes_per_sector_shift)
+        })
+    }
+
+    /// Alignment in sectors, `None` if it is not a non-zero multiple of the sector size under 2^32 sectors
+    fn alignment_sectors(&self, alignment: Option<u64>) -> Option<u64> {
+        let bytes_per_sector = 1u64 << self.bytes_per_sector_shift;
+        let Some(alignment) = alignment else {
+            return Some(1);
+        };
+
+        Some(alignment / bytes_per_sector).filter(|sectors| {
+            alignment.is_multiple_of(bytes_per_sector) && (1..=u64::from(u32::MAX)).contains(sectors)
+        })
+    }
+
+    fn layout(&self, cluster_count: u64, sectors_per_cluster_shift: u8, fat_alignment: u64, cluster_heap_alignment: u64) -> Layout {
+        Layout::new(
+            self.bytes_per_sector_shift,
+            sectors_per_cluster_shift,
+            cluster_count,
+            self.number_of_fats,
+            fat_alignment,
+            cluster_heap_alignment,
+        )
+    }
+
+    /// Largest even cluster count, with which the volume fits into the volume size
+    fn cluster_count(&self, sectors_per_cluster_shift: u8, fat_alignment: u64, cluster_heap_alignment: u64) -> u64 {
+        let bytes_per_sector = 1u64 << self.bytes_per_sector_shift;
+        let sectors_per_cluster = 1u64 << sectors_per_cluster_shift;
+        let volume_length = self.volume_size / bytes_per_sector;
+        let volume_length_for = |cluster_count| {
+            self.layout(cluster_count, sectors_per_cluster_shift, fat_alignment, cluster_heap_alignment)
+                .volume_length
+        };
+
+        // volume length only grows with the cluster count, `high` never fits
+        let (mut cluster_count, mut high) = (0, volume_length / sectors_per_cluster + 1);
+        while high - cluster_count > 1 {
+            let middle = cluster_count + (high - cluster_count) / 2;
+            if volume_length_for(middle) <= volume_length {
+                cluster_count = middle;
+            } else {
+                high = middle;
+            }
+        }
+
+        cluster_count & !1
+    }
+
+    pub fn build(self) -> Result<VirtualExFatBlockDevice, VexfatError> {
+        if !(9..=12).contains(&self.bytes_per_sector_shift) {
+            return Err(VexfatError::InvalidBytesPerSectorShift);
+        }
+        let sectors_per_cluster_shift = self.sectors_per_cluster_shift_or_recommended();
+        if sectors_per_cluster_shift > 25 - self.bytes_per_sector_shift {
+            return Err(VexfatError::InvalidSectorsPerClusterShift);
+        }
+        if !(1..=2).contains(&self.number_of_fats) {
+            return Err(VexfatError::InvalidNumberOfFats);
+        }
+        let volume_label = self
+            .volume_label
+            .as_deref()
+            .map(VolumeLabelDirectoryEntry::new)
+            .transpose()
+            .map_err(VexfatError::InvalidVolumeLabel)?;
+
+        let fat_alignment = self
+            .alignment_sectors(self.fat_alignment)
+            .ok_or(VexfatError::InvalidAlignment)?;
+        let cluster_heap_alignment = self
+            .alignment_sectors(self.cluster_heap_alignment)
+            .ok_or(VexfatError::InvalidAlignment)?;
+
+        let cluster_count = self.cluster_count(sectors_per_cluster_shift, fat_alignment, cluster_heap_alignment);
+        let cluster_count = u32::try_from(cluster_count)
+            .ok()
+            .filter(|&cluster_count| cluster_count <= MAX_CLUSTER_COUNT)
+            .ok_or(VexfatError::VolumeTooLarge)?;
+
+        let mut vexfat = VirtualExFatBlockDevice::new_with_alignment(
+            self.bytes_per_sector_shift,
+            sectors_per_cluster_shift,
+            cluster_count,
+            self.number_of_fats,
+            fat_alignment,
+            cluster_heap_alignment,
+            self.volume_serial_number.unwrap_or_else(rand::random),
+        )?;
+        if let Some(volume_label) = volume_label {
+            vexfat
+                .heap
+                .set_volume_label(volume_label)
+                .map_err(VexfatError::InvalidVolumeLabel)?;
+        }
+
+        Ok(vexfat)
+    }
+}
+
+#[test]
+fn builder() {
+    const MIB: u64 = 1 << 20;
+    const GIB: u64 = 1 << 30;
+
+    for (volume_size, bytes_per_cluster) in [(64 * MIB, 4096), (GIB, 32 << 10), (64 * GIB, 128 << 10)] {
+        let vexfat = VexfatBuilder::with_volume_size(volume_size)
+            .volume_serial_number(0)
+            .build()
+            .unwrap();
+        assert_eq!(vexfat.bytes_per_cluster(), bytes_per_cluster);
+        assert!(vexfat.volume_size() <= volume_size);
+        assert!(vexfat.volume_size() + 3 * bytes_per_cluster > volume_size);
+        assert!(vexfat.cluster_count().is_multiple_of(2));
+    }
+
+    // sector size changes the cluster size in sectors, not in bytes
+    let vexfat = VexfatBuilder::with_volume_size(64 * MIB)
+        .bytes_per_sector_shift(12)
+        .build()
+        .unwrap();
+    assert_eq!(vexfat.bytes_per_sector(), 4096);
+    assert_eq!(vexfat.bytes_per_cluster(), 4096);
+
+    let vexfat = VexfatBuilder::with_volume_size(64 * MIB)
+        .sectors_per_cluster_shift(0)
+        .volume_label("BUILT")
+        .build()
+        .unwrap();
+    assert_eq!(vexfat.bytes_per_cluster(), 512);
+
+    let ret = VexfatBuilder::with_volume_size(64 * MIB).bytes_per_sector_shift(13).build();
+    assert!(matches!(ret, Err(VexfatError::InvalidBytesPerSectorShift)));
+    let ret = VexfatBuilder::with_volume_size(64 * MIB).sectors_per_cluster_shift(17).build();
+    assert!(matches!(ret, Err(VexfatError::InvalidSectorsPerClusterShift)));
+    let vexfat = VexfatBuilder::with_volume_size(64 * MIB).number_of_fats(2).build().unwrap();
+    assert!(vexfat.volume_size() <= 64 * MIB);
+    let ret = VexfatBuilder::with_volume_size(64 * MIB).number_of_fats(3).build();
+    assert!(matches!(ret, Err(VexfatError::InvalidNumberOfFats)));
+    let ret = VexfatBuilder::with_volume_size(64 * MIB).volume_label("TOO LONG LABEL").build();
+    assert!(matches!(ret, Err(VexfatError::InvalidVolumeLabel(_))));
+    let ret = VexfatBuilder::with_volume_size(MIB - 1).build();
+    assert!(matches!(ret, Err(VexfatError::VolumeTooSmall)));
+    let ret = VexfatBuilder::with_volume_size(MIB).sectors_per_cluster_shift(16).build();
+    assert!(matches!(ret, Err(VexfatError::VolumeTooSmall)));
+    let ret = VexfatBuilder::with_volume_size(4 * MIB).sectors_per_cluster_shift(11).build();
+    assert!(matches!(ret, Err(VexfatError::VolumeTooSmall)));
+    let vexfat = VexfatBuilder::with_volume_size(MIB).build().unwrap();
+    assert!(vexfat.volume_size() >= MIB);
+    let ret = VexfatBuilder::with_volume_size(u64::MAX).sectors_per_cluster_shift(0).build();
+    assert!(matches!(ret, Err(VexfatError::VolumeTooLarge)));
+}
+
+#[test]
+fn alignment() {
+    const MIB: u64 = 1 << 20;
+
+    // SD card layout, FAT in the middle of the first erase block and the heap at the start of the second one
+    let vexfat = VexfatBuilder::with_volume_size(64 * MIB)
+        .fat_alignment(2 * MIB)
+        .cluster_heap_alignment(4 * MIB)
+        .build()
+        .unwrap();
+    assert_eq!(vexfat.fat_offset, 4096);
+    assert_eq!(vexfat.cluster_heap_offset, 8192);
+    assert!(vexfat.volume_size() <= 64 * MIB);
+    assert!(vexfat.volume_size() + 2 * vexfat.bytes_per_cluster() > 64 * MIB);
+
+    // both FATs are aligned
+    let vexfat = VexfatBuilder::with_volume_size(64 * MIB)
+        .number_of_fats(2)
+        .fat_alignment(MIB)
+        .cluster_heap_alignment(4 * MIB)
+        .build()
+        .unwrap();
+    assert_eq!(vexfat.fat_offset, 2048);
+    assert_eq!(vexfat.fat_length, 2048);
+    assert_eq!(vexfat.cluster_heap_offset, 8192);
+
+    for alignment in [0, 1000, 1 << 41] {
+        let ret = VexfatBuilder::with_volume_size(64 * MIB).fat_alignment(alignment).build();
+        assert!(matches!(ret, Err(VexfatError::InvalidAlignment)));
+    }
+    let ret = VexfatBuilder::with_volume_size(64 * MIB)
+        .bytes_per_sector_shift(12)
+        .cluster_heap_alignment(512)
+        .build();
+    assert!(matches!(ret, Err(VexfatError::InvalidAlignment)));
+}
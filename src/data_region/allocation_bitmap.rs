@@ -6,9 +6,22 @@ use bytemuck::{Pod, Zeroable};
 
 use super::EntryType;
 
+/// How [`AllocationBitmap::allocate`] picks a run of free clusters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AllocationStrategy {
+    /// Lowest run that is long enough
+    #[default]
+    FirstFit,
+    /// Shortest run that is long enough, the lowest one of those,
+    /// keeps long runs free for large files
+    BestFit,
+}
+
 pub struct AllocationBitmap {
     cluster_count: u32,
     data: Vec<u8>,
+    /// Clusters below are all allocated
+    first_free: u32,
 }
 
 impl AllocationBitmap {
@@ -16,18 +29,38 @@ impl AllocationBitmap {
         Self {
             cluster_count,
             data: Vec::new(),
+            first_free: 0,
         }
     }
 
     /// Size of the allocation bitmap in bytes
     pub fn size(&self) -> u32 {
-        self.cluster_count / 8
+        self.cluster_count.div_ceil(8)
+    }
+
+    pub fn cluster_count(&self) -> u32 {
+        self.cluster_count
     }
 
-    fn set_cluster(&mut self, cluster_index: u32, allocated: bool) {
+    /// Number of set bits, guest may set them in any order
+    pub fn allocated_count(&self) -> u32 {
+        let allocated: u32 = self.data.iter().map(|byte| byte.count_ones()).sum();
+        allocated.min(self.cluster_count)
+    }
+
+    pub fn is_allocated(&self, cluster_index: u32) -> bool {
         let bitmap_index = (cluster_index / 8) as usize;
 
-        let extend_by = (bitmap_index + 1) - self.data.len();
+        match self.data.get(bitmap_index) {
+            Some(byte) => byte & (1 << (cluster_index % 8)) > 0,
+            None => false,
+        }
+    }
+
+    pub fn set_cluster(&mut self, cluster_index: u32, allocated: bool) {
+        let bitmap_index = (cluster_index / 8) as usize;
+
+        let extend_by = (bitmap_index + 1).saturating_sub(self.data.len());
         if extend_by > 0 {
             self.data.extend(vec![0; extend_by]);
         }
@@ -37,9 +70,22 @@ impl AllocationBitmap {
             *byte |= 1 << (cluster_index % 8);
         } else {
             *byte &= !(1 << (cluster_index % 8));
+            self.first_free = self.first_free.min(cluster_index);
         }
     }
 
+    /// Allocates or frees `count` clusters starting at `cluster_index`
+    pub fn set_clusters(&mut self, cluster_index: u32, count: u32, allocated: bool) {
+        for cluster_index in cluster_index..cluster_index + count {
+            self.set_cluster(cluster_index, allocated);
+        }
+    }
+
+    /// Bitmap is grown lazily, bytes past the end are all free clusters
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
     pub fn read_sector(&self, sector: u32, buffer: &mut [u8]) {
         let bytes_per_sector = buffer.len();
         let bytes_to_skip = sector as usize * bytes_per_sector;
@@ -58,6 +104,17 @@ impl AllocationBitmap {
     pub fn write_sector(&mut self, sector: u32, buffer: &[u8]) {
         let bytes_per_sector = buffer.len();
         let bytes_to_skip = sector as usize * bytes_per_sector;
+        let bytes_in_bitmap = (self.size() as usize).saturating_sub(bytes_to_skip);
+        let buffer = &buffer[..usize::min(bytes_per_sector, bytes_in_bitmap)];
+
+        // bitmap is grown lazily, only extend it if there are allocated clusters past its end
+        if let Some(last_used) = buffer.iter().rposition(|&byte| byte != 0) {
+            let extend_by = (bytes_to_skip + last_used + 1).saturating_sub(self.data.len());
+            if extend_by > 0 {
+                self.data.extend(vec![0; extend_by]);
+            }
+        }
+
         let sector_data = self
             .data
             .iter_mut()
@@ -67,35 +124,74 @@ impl AllocationBitmap {
         for (new, byte) in buffer.iter().cloned().zip(sector_data) {
             *byte = new;
         }
+        // guest may have freed any of the clusters
+        let first_cluster = u32::try_from(bytes_to_skip * 8).unwrap_or(u32::MAX);
+        self.first_free = self.first_free.min(first_cluster);
+
+        // keep the trailing bytes allocated
+        while self.data.last() == Some(&0) {
+            self.data.pop();
+        }
     }
 
-    fn allocated_clusters_count(&self) -> u32 {
-        let all_but_last_eight = self.data.len().saturating_sub(1) * 8;
-        let last_eight = match self.data.last().cloned().unwrap_or_default() {
-            0b11111111 => 8, // 8 clusters allocated
-            0b01111111 => 7,
-            0b00111111 => 6,
-            0b00011111 => 5,
-            0b00001111 => 4,
-            0b00000111 => 3,
-            0b00000011 => 2,
-            0b00000001 => 1,
-            0b00000000 => 0,
-            _ => unreachable!(),
-        };
+    /// First cluster at or after `cluster_index` that is allocated, or free if not `allocated`
+    fn find_next(&self, mut cluster_index: u32, allocated: bool) -> Option<u32> {
+        while cluster_index < self.cluster_count {
+            let bitmap_index = (cluster_index / 8) as usize;
+            let Some(&byte) = self.data.get(bitmap_index) else {
+                // bitmap is grown lazily, the rest is free
+                return (!allocated).then_some(cluster_index);
+            };
+
+            let byte = if allocated { byte } else { !byte };
+            let matching = byte >> (cluster_index % 8);
+            if matching != 0 {
+                // guest may set bits past the last cluster
+                return Some(cluster_index + matching.trailing_zeros()).filter(|&found| found < self.cluster_count);
+            }
+            cluster_index = (bitmap_index as u32 + 1) * 8;
+        }
 
-        (all_but_last_eight + last_eight).try_into().unwrap()
+        None
     }
 
-    pub fn allocate_next_cluster(&mut self) -> Option<u32> {
-        let next_cluster = self.allocated_clusters_count();
+    /// Runs of free clusters as first cluster and length, in ascending order
+    fn free_runs(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        let mut cluster_index = self.first_free;
+        std::iter::from_fn(move || {
+            let start = self.find_next(cluster_index, false)?;
+            let end = self.find_next(start, true).unwrap_or(self.cluster_count);
+            cluster_index = end;
+            Some((start, end - start))
+        })
+    }
 
-        if next_cluster == self.cluster_count {
-            None
-        } else {
-            self.set_cluster(next_cluster, true);
-            Some(next_cluster)
+    /// Allocates `count` contiguous clusters, returns the first one
+    pub fn allocate(&mut self, count: u32, strategy: AllocationStrategy) -> Option<u32> {
+        if count == 0 {
+            return None;
         }
+
+        let first_free = self.find_next(self.first_free, false).unwrap_or(self.cluster_count);
+        let long_enough = || self.free_runs().filter(|&(_, length)| length >= count);
+        let run = match strategy {
+            AllocationStrategy::FirstFit => long_enough().next(),
+            AllocationStrategy::BestFit => long_enough().min_by_key(|&(_, length)| length),
+        };
+
+        self.first_free = first_free;
+        let (start, _) = run?;
+        self.set_clusters(start, count, true);
+        if start == first_free {
+            self.first_free = start + count;
+        }
+
+        Some(start)
+    }
+
+    #[cfg(test)]
+    pub fn allocate_next_cluster(&mut self) -> Option<u32> {
+        self.allocate(1, AllocationStrategy::FirstFit)
     }
 }
 
@@ -121,6 +217,15 @@ fn allocation_bitmap() {
     assert_eq!(&bitmap.data, &[0b11111111, 0b00000001]);
 }
 
+#[test]
+fn allocated_count() {
+    let mut bitmap = AllocationBitmap::new(16);
+    assert_eq!(bitmap.allocated_count(), 0);
+
+    bitmap.write_sector(0, &[0b10100101, 0b00000001]);
+    assert_eq!(bitmap.allocated_count(), 5);
+}
+
 #[test]
 fn out_of_memory() {
     let mut bitmap = AllocationBitmap::new(8);
@@ -136,6 +241,52 @@ fn out_of_memory() {
     assert_eq!(bitmap.allocate_next_cluster(), None);
 }
 
+#[test]
+fn write_sector() {
+    let mut bitmap = AllocationBitmap::new(8192);
+    bitmap.allocate_next_cluster();
+
+    let mut buffer = [0; 512];
+    buffer[0] = 0b11111111;
+    buffer[1] = 0b00000011;
+    bitmap.write_sector(0, &buffer);
+    assert_eq!(&bitmap.data, &[0b11111111, 0b00000011]);
+    assert_eq!(bitmap.allocate_next_cluster(), Some(10));
+
+    // bytes past the end of the bitmap are ignored
+    bitmap.write_sector(2, &[0xFF; 512]);
+    assert_eq!(bitmap.data.len(), 2);
+
+    bitmap.write_sector(0, &[0; 512]);
+    assert!(bitmap.data.is_empty());
+}
+
+#[test]
+fn allocate() {
+    let mut bitmap = AllocationBitmap::new(60);
+
+    // guest leaves holes of 3 and 2 clusters, and sets bits past the last cluster
+    bitmap.write_sector(0, &[0b11110001, 0b11111111, 0b11110011, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(bitmap.allocate(2, AllocationStrategy::BestFit), Some(18));
+    assert_eq!(bitmap.allocate(2, AllocationStrategy::FirstFit), Some(1));
+    assert_eq!(bitmap.allocate_next_cluster(), Some(3));
+    assert_eq!(bitmap.allocate_next_cluster(), None);
+
+    bitmap.set_clusters(8, 4, false);
+    bitmap.set_cluster(30, false);
+    assert_eq!(bitmap.allocate(5, AllocationStrategy::FirstFit), None);
+    assert_eq!(bitmap.allocate(1, AllocationStrategy::BestFit), Some(30));
+    assert_eq!(bitmap.allocate(4, AllocationStrategy::BestFit), Some(8));
+    assert_eq!(bitmap.allocated_count(), 60);
+
+    // free space past the end of the lazily grown bitmap
+    let mut bitmap = AllocationBitmap::new(20);
+    bitmap.write_sector(0, &[0b00001111]);
+    assert_eq!(bitmap.allocate(16, AllocationStrategy::BestFit), Some(4));
+    assert_eq!(bitmap.allocate(1, AllocationStrategy::FirstFit), None);
+    assert_eq!(bitmap.allocate(0, AllocationStrategy::FirstFit), None);
+}
+
 #[bitfield(u8)]
 #[derive(Zeroable, Pod, PartialEq)]
 pub struct BitmapFlags {
@@ -173,7 +324,7 @@ impl AllocationBitmapDirectoryEntry {
             bitmap_flags: BitmapFlags::new_with_raw_value(0).with_is_second_fat(is_second_fat),
             reserved: [0; 18],
             first_cluster: cluster_index + 2, // convert to FAT index
-            data_length: cluster_count / 8,
+            data_length: cluster_count.div_ceil(8),
         }
     }
 
@@ -181,6 +332,10 @@ impl AllocationBitmapDirectoryEntry {
         Self::new(cluster_index, cluster_count, false)
     }
 
+    pub fn new_second_fat(cluster_index: u32, cluster_count: u64) -> Self {
+        Self::new(cluster_index, cluster_count, true)
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         bytemuck::bytes_of(self)
     }
@@ -1,4 +1,9 @@
-use std::{fmt::Debug, io};
+use std::{
+    fmt::Debug,
+    fs::Metadata,
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use arbitrary_int::{u10, u4, u5, u6, u7};
 use bitbybit::bitfield;
@@ -97,6 +102,55 @@ struct Timestamp {
     year: u7,
 }
 
+impl Timestamp {
+    /// 1980-01-01 00:00:00 UTC
+    const MIN_UNIX_SECONDS: u64 = 315_532_800;
+    /// 2107-12-31 23:59:59 UTC
+    const MAX_UNIX_SECONDS: u64 = 4_354_819_199;
+
+    /// Timestamp in UTC and the remaining milliseconds as 10ms increment.
+    /// Times outside of the range representable in exFAT are clamped.
+    fn from_system_time(time: SystemTime) -> (Self, TenMsIncrement) {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let (seconds, millis) = match since_epoch.as_secs() {
+            seconds if seconds < Self::MIN_UNIX_SECONDS => (Self::MIN_UNIX_SECONDS, 0),
+            seconds if seconds > Self::MAX_UNIX_SECONDS => (Self::MAX_UNIX_SECONDS, 999),
+            seconds => (seconds, since_epoch.subsec_millis()),
+        };
+
+        let days = seconds / 86400;
+        let seconds_in_day = seconds % 86400;
+        let (year, month, day) = civil_from_days(days);
+
+        let timestamp = Self::new_with_raw_value(0)
+            .with_double_seconds(u5::new((seconds_in_day % 60 / 2) as u8))
+            .with_minute(u6::new((seconds_in_day / 60 % 60) as u8))
+            .with_hour(u5::new((seconds_in_day / 3600) as u8))
+            .with_day(u5::new(day as u8))
+            .with_month(u4::new(month as u8))
+            .with_year(u7::new((year - 1980) as u8));
+        let increment = TenMsIncrement(((seconds % 2) * 100) as u8 + (millis / 10) as u8);
+
+        (timestamp, increment)
+    }
+}
+
+/// Year, month and day from days since 1970-01-01
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // shift the epoch to 0000-03-01, so leap days end up at the end of the year
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    (year, month, day)
+}
+
 /// 10msIncrement fields shall provide additional time resolution to their corresponding Timestamp fields in ten-millisecond multiples.
 ///
 /// The valid range of values for these fields shall be:
@@ -116,6 +170,41 @@ struct UtcOffset {
     offset_valid: bool,
 }
 
+impl UtcOffset {
+    fn utc() -> Self {
+        Self::new_with_raw_value(0).with_offset_valid(true)
+    }
+}
+
+/// Create, last modified and last accessed times of a file or directory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EntryTimes {
+    pub created: SystemTime,
+    pub modified: SystemTime,
+    pub accessed: SystemTime,
+}
+
+impl EntryTimes {
+    pub fn now() -> Self {
+        let now = SystemTime::now();
+        Self {
+            created: now,
+            modified: now,
+            accessed: now,
+        }
+    }
+
+    /// Times the platform does not record fall back to the last modified time
+    pub fn from_metadata(metadata: &Metadata) -> io::Result<Self> {
+        let modified = metadata.modified()?;
+        Ok(Self {
+            created: metadata.created().unwrap_or(modified),
+            modified,
+            accessed: metadata.accessed().unwrap_or(modified),
+        })
+    }
+}
+
 #[derive(Clone, Copy, Zeroable, Pod, PartialEq)]
 #[repr(C)]
 pub struct FileDirectoryEntry {
@@ -163,6 +252,23 @@ impl FileDirectoryEntry {
         ret
     }
 
+    /// Timestamps are stored in UTC, last accessed time has a 2 second resolution
+    pub fn set_times(&mut self, times: &EntryTimes) {
+        let (timestamp, increment) = Timestamp::from_system_time(times.created);
+        self.create_timestamp = timestamp.raw_value();
+        self.create_10ms_increment = increment.0;
+        self.create_utc_offset = UtcOffset::utc().raw_value();
+
+        let (timestamp, increment) = Timestamp::from_system_time(times.modified);
+        self.last_modified_timestamp = timestamp.raw_value();
+        self.last_modified_10ms_increment = increment.0;
+        self.last_modified_utc_offset = UtcOffset::utc().raw_value();
+
+        let (timestamp, _) = Timestamp::from_system_time(times.accessed);
+        self.last_accessed_timestamp = timestamp.raw_value();
+        self.last_accessed_utc_offset = UtcOffset::utc().raw_value();
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         bytemuck::bytes_of(self)
     }
@@ -340,6 +446,19 @@ pub enum FileDirectoryEntryError {
     IllegalCharactersInName,
     IoError(io::Error),
     OutOfFreeSpace,
+    /// Symbolic link found while mapping a directory with [`SymlinkPolicy::Error`](crate::SymlinkPolicy::Error)
+    Symlink,
+    /// No entry with the name in the directory
+    NotFound,
+    /// Directory has to be removed with `remove_dir_all`
+    DirectoryNotEmpty,
+    NotADirectory,
+    /// Directory can't be moved into itself or one of its subdirectories
+    MoveIntoItself,
+    /// Entry is not a file mapped from the host
+    NotMappedFile,
+    /// Allocated cluster still holds something the guest wrote
+    ClusterInUse,
 }
 
 impl PartialEq for FileDirectoryEntryError {
@@ -382,3 +501,33 @@ fn hash() {
     let utf16: Vec<u16> = name.encode_utf16().collect();
     assert_eq!(name_hash(utf16.as_slice()), 0xA585);
 }
+
+#[test]
+fn timestamp() {
+    use std::time::Duration;
+
+    // 2023-06-15 12:34:57.789 UTC
+    let time = UNIX_EPOCH + Duration::from_millis(1_686_832_497_789);
+    let (timestamp, increment) = Timestamp::from_system_time(time);
+    assert_eq!(timestamp.year().value(), 43); // 2023
+    assert_eq!(timestamp.month().value(), 6);
+    assert_eq!(timestamp.day().value(), 15);
+    assert_eq!(timestamp.hour().value(), 12);
+    assert_eq!(timestamp.minute().value(), 34);
+    assert_eq!(timestamp.double_seconds().value(), 28);
+    assert_eq!(increment.0, 178);
+
+    // leap day
+    let time = UNIX_EPOCH + Duration::from_secs(951_782_400); // 2000-02-29
+    let (timestamp, _) = Timestamp::from_system_time(time);
+    assert_eq!((timestamp.year().value(), timestamp.month().value(), timestamp.day().value()), (20, 2, 29));
+
+    // clamped to the representable range
+    let (timestamp, increment) = Timestamp::from_system_time(UNIX_EPOCH);
+    assert_eq!(timestamp.raw_value(), 0x0021_0000); // 1980-01-01
+    assert_eq!(increment.0, 0);
+    let time = UNIX_EPOCH + Duration::from_secs(u64::from(u32::MAX) * 2);
+    let (timestamp, increment) = Timestamp::from_system_time(time);
+    assert_eq!(timestamp.raw_value(), 0xFF9F_BF7D); // 2107-12-31 23:59:58 + 1990ms
+    assert_eq!(increment.0, 199);
+}
@@ -3,7 +3,16 @@ use std::fmt::{Debug, Write};
 use arbitrary_int::u5;
 use bytemuck::{Pod, Zeroable};
 
-use super::EntryType;
+use super::{file::is_illegal_file_name_character, EntryType};
+
+#[derive(Debug, PartialEq)]
+pub enum VolumeLabelError {
+    /// Must be at most 11 UTF-16 code units
+    TooLong,
+    IllegalCharacters,
+    /// Root directory has no room for a new label entry
+    OutOfFreeSpace,
+}
 
 #[derive(Clone, Copy, Zeroable, Pod, PartialEq)]
 #[repr(C)]
@@ -26,6 +35,29 @@ impl VolumeLabelDirectoryEntry {
         }
     }
 
+    pub fn new(label: &str) -> Result<Self, VolumeLabelError> {
+        let label: Vec<u16> = label.encode_utf16().collect();
+        if label.len() > 11 {
+            return Err(VolumeLabelError::TooLong);
+        }
+
+        let contains_illegal_chars = label
+            .iter()
+            .cloned()
+            .any(|c| c == 0x0 || is_illegal_file_name_character(c));
+        if contains_illegal_chars {
+            return Err(VolumeLabelError::IllegalCharacters);
+        }
+
+        let mut entry = Self::empty();
+        entry.character_count = label.len() as u8;
+        for (out, char) in entry.volume_label.iter_mut().zip(label) {
+            *out = char;
+        }
+
+        Ok(entry)
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         bytemuck::bytes_of(self)
     }
@@ -46,3 +78,18 @@ impl Debug for VolumeLabelDirectoryEntry {
         Ok(())
     }
 }
+
+#[test]
+fn new() {
+    let entry = VolumeLabelDirectoryEntry::new("USB DRIVE").unwrap();
+    assert_eq!(entry.character_count, 9);
+    assert_eq!(format!("{entry:?}"), "VolumeLabelDirectoryEntry { USB DRIVE }");
+
+    let entry = VolumeLabelDirectoryEntry::new("").unwrap();
+    assert_eq!(entry, VolumeLabelDirectoryEntry::empty());
+
+    assert_eq!(VolumeLabelDirectoryEntry::new("ЁЁЁЁЁЁЁЁЁЁЁ").unwrap().character_count, 11);
+    assert_eq!(VolumeLabelDirectoryEntry::new("ЁЁЁЁЁЁЁЁЁЁЁЁ"), Err(VolumeLabelError::TooLong));
+    assert_eq!(VolumeLabelDirectoryEntry::new("A:B"), Err(VolumeLabelError::IllegalCharacters));
+    assert_eq!(VolumeLabelDirectoryEntry::new("A\0B"), Err(VolumeLabelError::IllegalCharacters));
+}
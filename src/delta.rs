@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use bytemuck::{Pod, Zeroable};
+
+const MAGIC: [u8; 8] = *b"VEXFATDL";
+const VERSION: u32 = 2;
+
+#[derive(Debug)]
+pub enum DeltaStoreError {
+    IoError(io::Error),
+
+    /// File is not a delta store
+    InvalidHeader,
+
+    /// Delta store was created for a volume with different geometry
+    GeometryMismatch,
+
+    /// Delta store was created for other mapped files, or the files changed since
+    MappingMismatch,
+}
+
+#[derive(Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+struct Header {
+    magic: [u8; 8],
+    version: u32,
+    bytes_per_cluster: u32,
+    cluster_count: u32,
+    reserved: [u8; 4],
+    mapping_fingerprint: u64,
+}
+
+#[derive(Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+struct RecordHeader {
+    cluster_index: u32,
+    flags: u32,
+}
+
+/// Record flag of a cluster that was released, earlier records of the cluster are dropped
+const RECORD_REMOVED: u32 = 1;
+
+/// Sparse on-disk store of whole clusters, written to instead of the mapped files.
+///
+/// Layout is a header followed by records, each being a record header and the cluster data.
+/// Records are appended when a cluster is written to for the first time, and overwritten in place afterwards.
+/// Records are keyed by cluster index, the header keeps a fingerprint of the mapping they apply to.
+pub struct DeltaStore {
+    file: File,
+    bytes_per_cluster: u32,
+
+    /// Cluster index to the offset of its data within the file
+    clusters: HashMap<u32, u64>,
+}
+
+impl DeltaStore {
+    /// Open existing delta store to reapply it, or create a new one.
+    /// `mapping` describes the mapped files, an existing store is only reapplied to the same description.
+    pub fn open<P>(path: P, bytes_per_cluster: u32, cluster_count: u32, mapping: &[u8]) -> Result<Self, DeltaStoreError>
+    where
+        P: AsRef<Path>,
+    {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(DeltaStoreError::IoError)?;
+
+        let expected_header = Header {
+            magic: MAGIC,
+            version: VERSION,
+            bytes_per_cluster,
+            cluster_count,
+            reserved: [0; 4],
+            mapping_fingerprint: fingerprint(mapping),
+        };
+
+        let file_size = file.seek(SeekFrom::End(0)).map_err(DeltaStoreError::IoError)?;
+        if file_size == 0 {
+            file.write_all(bytemuck::bytes_of(&expected_header))
+                .map_err(DeltaStoreError::IoError)?;
+
+            return Ok(Self {
+                file,
+                bytes_per_cluster,
+                clusters: HashMap::new(),
+            });
+        }
+
+        let mut header = Header::zeroed();
+        file.seek(SeekFrom::Start(0)).map_err(DeltaStoreError::IoError)?;
+        file.read_exact(bytemuck::bytes_of_mut(&mut header))
+            .map_err(|_| DeltaStoreError::InvalidHeader)?;
+        if header.magic != MAGIC || header.version != VERSION {
+            return Err(DeltaStoreError::InvalidHeader);
+        }
+        if header.bytes_per_cluster != bytes_per_cluster || header.cluster_count != cluster_count {
+            return Err(DeltaStoreError::GeometryMismatch);
+        }
+        if header.mapping_fingerprint != expected_header.mapping_fingerprint {
+            return Err(DeltaStoreError::MappingMismatch);
+        }
+
+        // rebuild the index, a partially written record at the end is dropped
+        let record_size = (std::mem::size_of::<RecordHeader>() + bytes_per_cluster as usize) as u64;
+        let mut clusters = HashMap::new();
+        let mut offset = std::mem::size_of::<Header>() as u64;
+        while offset + record_size <= file_size {
+            let mut record = RecordHeader::zeroed();
+            file.seek(SeekFrom::Start(offset)).map_err(DeltaStoreError::IoError)?;
+            file.read_exact(bytemuck::bytes_of_mut(&mut record))
+                .map_err(DeltaStoreError::IoError)?;
+            if record.cluster_index >= cluster_count {
+                return Err(DeltaStoreError::InvalidHeader);
+            }
+
+            if record.flags & RECORD_REMOVED != 0 {
+                clusters.remove(&record.cluster_index);
+            } else {
+                clusters.insert(
+                    record.cluster_index,
+                    offset + std::mem::size_of::<RecordHeader>() as u64,
+                );
+            }
+            offset += record_size;
+        }
+        file.set_len(offset).map_err(DeltaStoreError::IoError)?;
+
+        Ok(Self {
+            file,
+            bytes_per_cluster,
+            clusters,
+        })
+    }
+
+    /// Drop every stored cluster
+    pub fn discard(&mut self) -> io::Result<()> {
+        self.file.set_len(std::mem::size_of::<Header>() as u64)?;
+        self.clusters.clear();
+        Ok(())
+    }
+
+    pub fn contains(&self, cluster_index: u32) -> bool {
+        self.clusters.contains_key(&cluster_index)
+    }
+
+    /// Returns `false` if the cluster is not in the store
+    pub fn read_sector(&mut self, cluster_index: u32, offset_in_cluster: u64, buffer: &mut [u8]) -> io::Result<bool> {
+        let data_offset = match self.clusters.get(&cluster_index) {
+            Some(data_offset) => *data_offset,
+            None => return Ok(false),
+        };
+
+        self.file.seek(SeekFrom::Start(data_offset + offset_in_cluster))?;
+        self.file.read_exact(buffer)?;
+        Ok(true)
+    }
+
+    /// Cluster has to be stored with [`Self::insert_cluster`] first
+    pub fn write_sector(&mut self, cluster_index: u32, offset_in_cluster: u64, buffer: &[u8]) -> io::Result<()> {
+        let data_offset = self.clusters[&cluster_index];
+        self.file.seek(SeekFrom::Start(data_offset + offset_in_cluster))?;
+        self.file.write_all(buffer)
+    }
+
+    pub fn insert_cluster(&mut self, cluster_index: u32, data: &[u8]) -> io::Result<()> {
+        assert_eq!(data.len(), self.bytes_per_cluster as usize);
+
+        let record = RecordHeader {
+            cluster_index,
+            flags: 0,
+        };
+        let record_offset = self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(bytemuck::bytes_of(&record))?;
+        self.file.write_all(data)?;
+
+        self.clusters.insert(
+            cluster_index,
+            record_offset + std::mem::size_of::<RecordHeader>() as u64,
+        );
+        Ok(())
+    }
+
+    /// Drop the cluster, it stays dropped when the store is reapplied
+    pub fn remove_cluster(&mut self, cluster_index: u32) -> io::Result<()> {
+        let Some(data_offset) = self.clusters.remove(&cluster_index) else {
+            return Ok(());
+        };
+
+        let record = RecordHeader {
+            cluster_index,
+            flags: RECORD_REMOVED,
+        };
+        self.file.seek(SeekFrom::Start(data_offset - std::mem::size_of::<RecordHeader>() as u64))?;
+        self.file.write_all(bytemuck::bytes_of(&record))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// 64-bit FNV-1a, stays the same across builds unlike the hashers of the standard library
+fn fingerprint(data: &[u8]) -> u64 {
+    data.iter().fold(0xCBF29CE484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001B3)
+    })
+}
+
+#[test]
+fn delta_store() {
+    let path = crate::utils::TempPath::new("delta-store");
+
+    let mut store = DeltaStore::open(&path, 1024, 16, b"mapping").unwrap();
+    assert!(!store.contains(3));
+    store.insert_cluster(3, &[1; 1024]).unwrap();
+    store.write_sector(3, 512, &[2; 512]).unwrap();
+    drop(store);
+
+    // reapply
+    let mut store = DeltaStore::open(&path, 1024, 16, b"mapping").unwrap();
+    let mut buffer = [0; 512];
+    assert!(store.read_sector(3, 0, &mut buffer).unwrap());
+    assert_eq!(buffer, [1; 512]);
+    assert!(store.read_sector(3, 512, &mut buffer).unwrap());
+    assert_eq!(buffer, [2; 512]);
+    assert!(!store.read_sector(4, 0, &mut buffer).unwrap());
+
+    assert!(matches!(
+        DeltaStore::open(&path, 512, 16, b"mapping"),
+        Err(DeltaStoreError::GeometryMismatch)
+    ));
+    assert!(matches!(
+        DeltaStore::open(&path, 1024, 16, b"other mapping"),
+        Err(DeltaStoreError::MappingMismatch)
+    ));
+
+    // removed cluster stays removed until it is stored again
+    store.remove_cluster(3).unwrap();
+    store.insert_cluster(5, &[5; 1024]).unwrap();
+    drop(store);
+    let mut store = DeltaStore::open(&path, 1024, 16, b"mapping").unwrap();
+    assert!(!store.contains(3));
+    assert!(store.contains(5));
+    store.insert_cluster(3, &[3; 1024]).unwrap();
+    drop(store);
+    let mut store = DeltaStore::open(&path, 1024, 16, b"mapping").unwrap();
+    assert!(store.read_sector(3, 0, &mut buffer).unwrap());
+    assert_eq!(buffer, [3; 512]);
+
+    // discard
+    store.discard().unwrap();
+    assert!(!store.contains(3));
+    drop(store);
+    let store = DeltaStore::open(&path, 1024, 16, b"mapping").unwrap();
+    assert!(!store.contains(3));
+}
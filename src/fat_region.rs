@@ -5,12 +5,41 @@ pub const END_OF_CHAIN: u32 = 0xFFFFFFFF - 2;
 #[derive(Debug)]
 pub struct FileAllocationTable {
     first: Vec<u32>,
+    /// `None` if the volume has one FAT
+    second: Option<Vec<u32>>,
+    /// `ActiveFat` volume flag, chains are followed in the second FAT if set
+    second_active: bool,
 }
 
 impl FileAllocationTable {
-    pub fn empty() -> Self {
+    /// `number_of_fats` is either 1 or 2
+    pub fn new(number_of_fats: u8) -> Self {
+        let media_type = vec![0xFFFFFFF8, 0xFFFFFFFF];
         Self {
-            first: vec![0xFFFFFFF8, 0xFFFFFFFF],
+            second: (number_of_fats == 2).then(|| media_type.clone()),
+            first: media_type,
+            second_active: false,
+        }
+    }
+
+    pub fn has_second(&self) -> bool {
+        self.second.is_some()
+    }
+
+    pub fn is_second_active(&self) -> bool {
+        self.second_active
+    }
+
+    /// Ignored if the volume has one FAT
+    pub fn set_second_active(&mut self, second_active: bool) {
+        self.second_active = second_active && self.has_second();
+    }
+
+    /// Entries of the FAT the chains are followed in
+    pub fn active(&self) -> &[u32] {
+        match &self.second {
+            Some(second) if self.second_active => second,
+            _ => &self.first,
         }
     }
 
@@ -32,62 +61,181 @@ impl FileAllocationTable {
         self.read_sector(fat_sector, buffer, &self.first);
     }
 
-    pub fn write_sector_first(&mut self, fat_sector: u64, buffer: &[u8]) {
+    /// Reads zeroes if the volume has one FAT
+    pub fn read_sector_second(&self, fat_sector: u64, buffer: &mut [u8]) {
+        if let Some(second) = &self.second {
+            self.read_sector(fat_sector, buffer, second);
+        }
+    }
+
+    fn write_sector(fat_sector: u64, buffer: &[u8], list: &mut Vec<u32>) {
         let entries_per_sector = buffer.len() / size_of::<u32>();
         let buffer: &[u32] = bytemuck::cast_slice(buffer);
+        let skip = fat_sector as usize * entries_per_sector;
+
+        // FAT is grown lazily, only extend it if there are non-free entries past its end
+        if let Some(last_used) = buffer.iter().rposition(|&entry| entry != 0) {
+            let extend_by = (skip + last_used + 1).saturating_sub(list.len());
+            if extend_by > 0 {
+                list.extend(vec![0; extend_by]);
+            }
+        }
 
-        for (new, fat_entry) in buffer.iter().cloned().zip(
-            self.first.iter_mut()
-                .skip(fat_sector as usize * entries_per_sector)
-                .take(entries_per_sector)
-        ) {
+        for (new, fat_entry) in buffer
+            .iter()
+            .cloned()
+            .zip(list.iter_mut().skip(skip).take(entries_per_sector))
+        {
             *fat_entry = new;
         }
     }
 
+    pub fn write_sector_first(&mut self, fat_sector: u64, buffer: &[u8]) {
+        Self::write_sector(fat_sector, buffer, &mut self.first);
+    }
+
+    /// Ignored if the volume has one FAT
+    pub fn write_sector_second(&mut self, fat_sector: u64, buffer: &[u8]) {
+        if let Some(second) = &mut self.second {
+            Self::write_sector(fat_sector, buffer, second);
+        }
+    }
+
+    /// Sets the entry in both FATs
     pub fn set_cluster(&mut self, cluster_index: u32, next_cluster: u32) {
         let fat_cluster_index = (cluster_index + 2) as usize;
 
-        let extend_by = (fat_cluster_index + 1).saturating_sub(self.first.len());
-        if extend_by > 0 {
-            self.first.extend(vec![0; extend_by]);
+        for list in std::iter::once(&mut self.first).chain(self.second.as_mut()) {
+            let extend_by = (fat_cluster_index + 1).saturating_sub(list.len());
+            if extend_by > 0 {
+                list.extend(vec![0; extend_by]);
+            }
+
+            list[fat_cluster_index] = next_cluster + 2;
         }
+    }
 
-        self.first[fat_cluster_index] = next_cluster + 2;
+    /// Marks the entry as free in both tables
+    pub fn free_cluster(&mut self, cluster_index: u32) {
+        let fat_cluster_index = cluster_index as usize + 2;
+
+        for list in std::iter::once(&mut self.first).chain(self.second.as_mut()) {
+            if let Some(entry) = list.get_mut(fat_cluster_index) {
+                *entry = 0;
+            }
+        }
     }
 
-    pub fn chain(&self, cluster: u32) -> AllocationChain {
+    pub fn chain(&self, cluster: u32) -> AllocationChain<'_> {
+        let fat = self.active();
         AllocationChain {
-            fat: &self.first,
+            fat,
             index: cluster + 2,
+            remaining: fat.len(),
         }
     }
 }
 
+/// Clusters following the first one, ends at anything but a valid cluster index.
+/// Chains the guest wrote in a loop end after as many steps as the FAT has entries.
 pub struct AllocationChain<'a> {
     fat: &'a [u32],
     index: u32,
+    remaining: usize,
 }
 
 impl<'a> Iterator for AllocationChain<'a> {
     type Item = u32;
 
     fn next(&mut self) -> Option<Self::Item> {
+        self.remaining = self.remaining.checked_sub(1)?;
         self.index = self.fat.get(self.index as usize).cloned().unwrap_or(0xFFFFFFFF);
 
-        if self.index == 0xFFFFFFFF || self.index == 0 {
-            None
-        } else {
-            Some(self.index - 2)
+        // free, bad, media descriptor and end of chain values
+        match self.index {
+            2..=0xFFFFFFF6 => Some(self.index - 2),
+            _ => {
+                self.remaining = 0;
+                None
+            }
         }
     }
 }
 
 #[test]
 fn set_cluster() {
-    let mut fat = FileAllocationTable::empty();
+    let mut fat = FileAllocationTable::new(1);
     assert_eq!(fat.first, &[0xFFFFFFF8, 0xFFFFFFFF]);
 
     fat.set_cluster(0, END_OF_CHAIN);
     assert_eq!(fat.first, &[0xFFFFFFF8, 0xFFFFFFFF, 0xFFFFFFFF])
 }
+
+#[test]
+fn write_sector() {
+    let mut fat = FileAllocationTable::new(1);
+
+    let mut buffer = [0u32; 128];
+    buffer[0] = 0xFFFFFFF8;
+    buffer[1] = 0xFFFFFFFF;
+    buffer[4] = 5;
+    buffer[5] = 0xFFFFFFFF;
+    fat.write_sector_first(0, bytemuck::cast_slice(&buffer));
+    assert_eq!(fat.first, &[0xFFFFFFF8, 0xFFFFFFFF, 0, 0, 5, 0xFFFFFFFF]);
+    assert_eq!(fat.chain(2).collect::<Vec<_>>(), [3]);
+
+    // free entries past the end do not grow the table
+    fat.write_sector_first(1, &[0; 512]);
+    assert_eq!(fat.first.len(), 6);
+}
+
+#[test]
+fn second_fat() {
+    let mut fat = FileAllocationTable::new(2);
+    fat.set_cluster(0, 1);
+    fat.set_cluster(1, END_OF_CHAIN);
+    assert_eq!(fat.chain(0).collect::<Vec<_>>(), [1]);
+
+    // guest rewrites the chain in the second FAT only
+    let mut buffer = [0u32; 128];
+    buffer[0] = 0xFFFFFFF8;
+    buffer[1] = 0xFFFFFFFF;
+    buffer[2] = 0xFFFFFFFF;
+    fat.write_sector_second(0, bytemuck::cast_slice(&buffer));
+    assert_eq!(fat.chain(0).collect::<Vec<_>>(), [1]);
+
+    fat.set_second_active(true);
+    assert_eq!(fat.chain(0).next(), None);
+
+    let mut buffer = [0; 512];
+    fat.read_sector_first(0, &mut buffer);
+    assert_eq!(&buffer[8..16], &[3, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]);
+
+    // switching has no effect with one FAT
+    let mut fat = FileAllocationTable::new(1);
+    fat.set_second_active(true);
+    assert!(!fat.is_second_active());
+}
+
+#[test]
+fn invalid_chain() {
+    let mut fat = FileAllocationTable::new(1);
+    fat.set_cluster(0, 1);
+    fat.set_cluster(1, END_OF_CHAIN);
+
+    // reserved value
+    let mut buffer = [0u32; 128];
+    buffer[0] = 0xFFFFFFF8;
+    buffer[1] = 0xFFFFFFFF;
+    buffer[2] = 1;
+    buffer[3] = 0xFFFFFFF7;
+    fat.write_sector_first(0, bytemuck::cast_slice(&buffer));
+    assert_eq!(fat.chain(0).next(), None);
+    assert_eq!(fat.chain(1).next(), None);
+
+    // loop, ends after as many steps as there are entries
+    buffer[2] = 3;
+    buffer[3] = 2;
+    fat.write_sector_first(0, bytemuck::cast_slice(&buffer));
+    assert_eq!(fat.chain(0).collect::<Vec<_>>(), [1, 0, 1, 0]);
+}
@@ -1,22 +1,26 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Debug;
-use std::fs::File;
-use std::io::{Read, Seek};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, Write};
 use std::mem::size_of;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+use glob::{Pattern, PatternError};
 use itertools::Itertools;
 use static_assertions::const_assert;
 
-use crate::data_region::allocation_bitmap::{AllocationBitmap, AllocationBitmapDirectoryEntry};
+use crate::data_region::allocation_bitmap::{AllocationBitmap, AllocationBitmapDirectoryEntry, AllocationStrategy};
 use crate::data_region::file::{
-    entry_checksum, is_illegal_file_name_character, name_hash, FileAttributes, FileDirectoryEntry,
+    entry_checksum, is_illegal_file_name_character, name_hash, EntryTimes, FileAttributes, FileDirectoryEntry,
     FileDirectoryEntryError, FileNameDirectoryEntry, StreamExtensionDirectoryEntry,
 };
 use crate::data_region::upcase_table::{upcased_name, UpcaseTableDirectoryEntry, UPCASE_TABLE};
-use crate::data_region::volume_label::VolumeLabelDirectoryEntry;
+use crate::data_region::volume_label::{VolumeLabelDirectoryEntry, VolumeLabelError};
+use crate::delta::{DeltaStore, DeltaStoreError};
 use crate::fat_region::{FileAllocationTable, END_OF_CHAIN};
 use crate::utils::unsigned_rounded_up_div;
+#[cfg(test)]
+use crate::utils::TempPath;
 
 #[derive(Debug, PartialEq)]
 pub enum DirectoryEntry {
@@ -26,6 +30,9 @@ pub enum DirectoryEntry {
     File(FileDirectoryEntry),
     StreamExtension(StreamExtensionDirectoryEntry),
     FileName(FileNameDirectoryEntry),
+
+    /// Unused, not in use or unrecognised entry as written by the guest
+    Unknown([u8; 32]),
 }
 
 impl DirectoryEntry {
@@ -129,12 +136,179 @@ impl DirectoryEntry {
             DirectoryEntry::File(entry) => entry.as_bytes(),
             DirectoryEntry::StreamExtension(entry) => entry.as_bytes(),
             DirectoryEntry::FileName(entry) => entry.as_bytes(),
+            DirectoryEntry::Unknown(bytes) => bytes,
         }
     }
 }
 
+/// Change the guest made to the directory tree
+#[derive(Debug, Clone, PartialEq)]
+pub enum DirectoryEvent {
+    Created {
+        dir_cluster: u32,
+        name: String,
+        first_cluster: Option<u32>,
+    },
+    Deleted {
+        dir_cluster: u32,
+        name: String,
+        first_cluster: Option<u32>,
+    },
+    Renamed {
+        dir_cluster: u32,
+        old_name: String,
+        new_name: String,
+        first_cluster: u32,
+    },
+}
+
+/// Entries on the volume compared to what the host mapped, paths are relative to the root directory
+#[derive(Debug, Default, PartialEq)]
+pub struct ExportReport {
+    pub new: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    pub deleted: Vec<PathBuf>,
+}
+
+/// File or directory found when walking the directory tree
+#[derive(Debug, Clone)]
+pub(crate) struct TreeEntry {
+    /// Relative to the root directory
+    pub path: PathBuf,
+    pub directory: bool,
+    pub first_cluster: Option<u32>,
+    pub clusters: Vec<u32>,
+    pub data_length: u64,
+    pub valid_data_length: u64,
+}
+
+/// File or directory as added by the host
+#[derive(Debug, Clone)]
+struct MappedEntry {
+    dir_cluster: u32,
+    name: String,
+    directory: bool,
+    data_length: u64,
+}
+
+/// File directory entry, stream extension and file name entries with a valid checksum
+#[derive(Debug, Clone)]
+struct EntrySet {
+    file: FileDirectoryEntry,
+    stream_extension: StreamExtensionDirectoryEntry,
+    name: Vec<u16>,
+}
+
+impl EntrySet {
+    fn name_lossy(&self) -> String {
+        String::from_utf16_lossy(&self.name)
+    }
+
+    /// Heap cluster index, if anything is allocated
+    fn first_cluster(&self) -> Option<u32> {
+        self.stream_extension.first_cluster.checked_sub(2)
+    }
+}
+
 const_assert!(size_of::<DirectoryEntry>() - 8 == DirectoryEntry::SIZE); // 8 - enum discriminant
 
+/// How files from host file system are mapped
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MappingMode {
+    /// Guest writes to mapped files are kept in the overlay
+    #[default]
+    ReadOnly,
+
+    /// Mapped files are opened for writing, guest writes land in the host files
+    WriteBack,
+
+    /// Guest writes to mapped files are kept in the delta store, host files are never modified
+    CopyOnWrite,
+}
+
+/// What to do with symbolic links found while mapping a directory
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SymlinkPolicy {
+    /// Map what the link points to
+    #[default]
+    Follow,
+
+    /// Leave the link out, it is listed in the report
+    Skip,
+
+    /// Stop mapping with [`FileDirectoryEntryError::Symlink`]
+    Error,
+}
+
+/// Filters applied while mapping a directory.
+/// Patterns are matched against the path relative to the mapped directory and against the file name.
+#[derive(Debug, Clone, Default)]
+pub struct MapDirectoryOptions {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    symlinks: SymlinkPolicy,
+}
+
+impl MapDirectoryOptions {
+    /// Only map files matching one of the included patterns, directories are always walked
+    pub fn include(mut self, pattern: &str) -> Result<Self, PatternError> {
+        self.include.push(Pattern::new(pattern)?);
+        Ok(self)
+    }
+
+    /// Leave out files and directories matching the pattern
+    pub fn exclude(mut self, pattern: &str) -> Result<Self, PatternError> {
+        self.exclude.push(Pattern::new(pattern)?);
+        Ok(self)
+    }
+
+    pub fn symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlinks = policy;
+        self
+    }
+
+    pub(crate) fn is_included(&self, relative_path: &Path, directory: bool) -> bool {
+        let matches = |pattern: &Pattern| {
+            pattern.matches_path(relative_path)
+                || relative_path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| pattern.matches(name))
+        };
+
+        if self.exclude.iter().any(matches) {
+            return false;
+        }
+
+        directory || self.include.is_empty() || self.include.iter().any(matches)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SkipReason {
+    /// Name is empty, too long, not valid unicode or contains characters illegal in exFAT
+    IllegalName,
+
+    /// Another entry in the directory has the same name after upcasing
+    NameCollision,
+
+    /// Skipped according to [`SymlinkPolicy::Skip`], or the link is dangling
+    Symlink,
+
+    /// Neither a regular file nor a directory
+    Unsupported,
+}
+
+/// Host files and directories mapped by [`ClusterHeap::map_directory`]
+#[derive(Debug, Default, PartialEq)]
+pub struct MapDirectoryReport {
+    /// Host directory and the cluster it is mapped to, including the mapped directory itself
+    pub directories: Vec<(PathBuf, u32)>,
+    /// Host file and its first cluster
+    pub files: Vec<(PathBuf, u32)>,
+    pub skipped: Vec<(PathBuf, SkipReason)>,
+}
+
 pub struct ClusterHeap {
     bytes_per_sector: u32,
     sectors_per_cluster: u32,
@@ -145,16 +319,49 @@ pub struct ClusterHeap {
     allocation_bitmap_start_cluster: u32,
     allocation_bitmap_end_cluster: u32,
 
+    /// Allocation bitmap of the second FAT, `None` if the volume has one FAT
+    second_allocation_bitmap: Option<AllocationBitmap>,
+    second_allocation_bitmap_start_cluster: u32,
+    second_allocation_bitmap_end_cluster: u32,
+    allocation_strategy: AllocationStrategy,
+
     upcase_table_start_cluster: u32,
     upcase_table_end_cluster: u32,
 
     heap: HashMap<u32, Cluster>,
     cluster_lookup: HashMap<u32, u32>,
     parent_lookup: HashMap<u32, u32>,
+    /// Clusters of directories to the first cluster of the directory they belong to
+    directory_lookup: HashMap<u32, u32>,
+
+    /// Entry sets of directories the guest is writing to, as of the last write
+    known_entry_sets: HashMap<u32, Vec<EntrySet>>,
+    directory_events: Vec<DirectoryEvent>,
+
+    mapping_mode: MappingMode,
+    delta_store: Option<DeltaStore>,
+
+    /// Files and directories added by the host, by first cluster
+    mapped_entries: HashMap<u32, MappedEntry>,
+    /// Entries the host added whose first cluster the guest freed and the host reused, by path
+    released_entries: HashMap<PathBuf, (u32, MappedEntry)>,
+    /// Clusters the guest wrote to
+    dirty_clusters: HashSet<u32>,
 }
 
 impl ClusterHeap {
+    #[cfg(test)]
     pub fn new(bytes_per_sector: u32, sectors_per_cluster: u32, cluster_count: u32) -> Self {
+        Self::new_with_number_of_fats(bytes_per_sector, sectors_per_cluster, cluster_count, 1)
+    }
+
+    /// With two FATs, the second allocation bitmap follows the first one
+    pub fn new_with_number_of_fats(
+        bytes_per_sector: u32,
+        sectors_per_cluster: u32,
+        cluster_count: u32,
+        number_of_fats: u8,
+    ) -> Self {
         let bytes_per_cluster = sectors_per_cluster * bytes_per_sector;
 
         let mut allocation_bitmap = AllocationBitmap::new(cluster_count);
@@ -164,44 +371,67 @@ impl ClusterHeap {
         let allocation_bitmap_end_cluster =
             allocation_bitmap_start_cluster + allocation_bitmap_size_clusters;
 
-        let upcase_table_start_cluster = allocation_bitmap_end_cluster;
+        let mut second_allocation_bitmap = (number_of_fats == 2).then(|| AllocationBitmap::new(cluster_count));
+        let second_allocation_bitmap_start_cluster = allocation_bitmap_end_cluster;
+        let second_allocation_bitmap_end_cluster = match second_allocation_bitmap {
+            Some(_) => second_allocation_bitmap_start_cluster + allocation_bitmap_size_clusters,
+            None => second_allocation_bitmap_start_cluster,
+        };
+
+        let upcase_table_start_cluster = second_allocation_bitmap_end_cluster;
         let upcase_table_size_clusters =
             unsigned_rounded_up_div(2 * UPCASE_TABLE.len() as u32, bytes_per_cluster);
         let upcase_table_end_cluster = upcase_table_start_cluster + upcase_table_size_clusters;
 
         let root_directory_start_cluster = upcase_table_end_cluster;
 
+        let mut root_directory_entries = vec![
+            DirectoryEntry::VolumeLabel(VolumeLabelDirectoryEntry::empty()),
+            DirectoryEntry::AllocationBitmap(AllocationBitmapDirectoryEntry::new_first_fat(
+                allocation_bitmap_start_cluster,
+                u64::from(cluster_count),
+            )),
+        ];
+        if second_allocation_bitmap.is_some() {
+            root_directory_entries.push(DirectoryEntry::AllocationBitmap(
+                AllocationBitmapDirectoryEntry::new_second_fat(
+                    second_allocation_bitmap_start_cluster,
+                    u64::from(cluster_count),
+                ),
+            ));
+        }
+        root_directory_entries.push(DirectoryEntry::UpcaseTable(UpcaseTableDirectoryEntry::default()));
+
         let mut heap = HashMap::new();
         let mut cluster_lookup = HashMap::new();
         heap.insert(
             root_directory_start_cluster,
             Cluster {
-                data: ClusterData::DirectoryEntries(DirectoryEntries(vec![
-                    DirectoryEntry::VolumeLabel(VolumeLabelDirectoryEntry::empty()),
-                    DirectoryEntry::AllocationBitmap(
-                        AllocationBitmapDirectoryEntry::new_first_fat(
-                            allocation_bitmap_start_cluster,
-                            u64::from(cluster_count),
-                        ),
-                    ),
-                    DirectoryEntry::UpcaseTable(UpcaseTableDirectoryEntry::default()),
-                ])),
+                data: ClusterData::DirectoryEntries(DirectoryEntries(root_directory_entries)),
             },
         );
         cluster_lookup.insert(root_directory_start_cluster, root_directory_start_cluster);
 
-        for _ in 0..=upcase_table_end_cluster {
-            allocation_bitmap.allocate_next_cluster();
+        allocation_bitmap.set_clusters(0, root_directory_start_cluster + 1, true);
+        if let Some(second_allocation_bitmap) = &mut second_allocation_bitmap {
+            second_allocation_bitmap.set_clusters(0, root_directory_start_cluster + 1, true);
         }
 
-        let mut fat = FileAllocationTable::empty();
+        let mut fat = FileAllocationTable::new(number_of_fats);
 
-        for (cluster, next_cluster) in
-            (allocation_bitmap_start_cluster..allocation_bitmap_end_cluster).tuple_windows()
-        {
-            fat.set_cluster(cluster, next_cluster);
+        for (start_cluster, end_cluster) in [
+            (allocation_bitmap_start_cluster, allocation_bitmap_end_cluster),
+            (second_allocation_bitmap_start_cluster, second_allocation_bitmap_end_cluster),
+        ] {
+            if start_cluster == end_cluster {
+                continue;
+            }
+
+            for (cluster, next_cluster) in (start_cluster..end_cluster).tuple_windows() {
+                fat.set_cluster(cluster, next_cluster);
+            }
+            fat.set_cluster(end_cluster - 1, END_OF_CHAIN);
         }
-        fat.set_cluster(allocation_bitmap_end_cluster - 1, END_OF_CHAIN);
 
         for (cluster, next_cluster) in
             (upcase_table_start_cluster..upcase_table_end_cluster).tuple_windows()
@@ -222,207 +452,1282 @@ impl ClusterHeap {
             allocation_bitmap_start_cluster,
             allocation_bitmap_end_cluster,
 
+            second_allocation_bitmap,
+            second_allocation_bitmap_start_cluster,
+            second_allocation_bitmap_end_cluster,
+            allocation_strategy: AllocationStrategy::default(),
+
             upcase_table_start_cluster,
             upcase_table_end_cluster,
 
             heap,
             cluster_lookup,
             parent_lookup: HashMap::new(),
+            directory_lookup: HashMap::from([(root_directory_start_cluster, root_directory_start_cluster)]),
+
+            known_entry_sets: HashMap::new(),
+            directory_events: Vec::new(),
+
+            mapping_mode: MappingMode::default(),
+            delta_store: None,
+
+            mapped_entries: HashMap::new(),
+            released_entries: HashMap::new(),
+            dirty_clusters: HashSet::new(),
         }
     }
 
-    pub fn read_sector(&mut self, sector: u32, buffer: &mut [u8]) {
-        let cluster_index = sector / self.sectors_per_cluster;
-        let sector_in_cluster = sector % self.sectors_per_cluster;
+    /// Clusters taken up by the allocation bitmaps, upcase table and root directory
+    pub(crate) fn system_clusters_count(bytes_per_cluster: u32, cluster_count: u32, number_of_fats: u8) -> u32 {
+        let allocation_bitmap_size = cluster_count.div_ceil(8).max(1);
+        unsigned_rounded_up_div(allocation_bitmap_size, bytes_per_cluster) * u32::from(number_of_fats)
+            + unsigned_rounded_up_div(2 * UPCASE_TABLE.len() as u32, bytes_per_cluster)
+            + 1
+    }
+
+    pub fn read_sector(&mut self, sector: u64, buffer: &mut [u8]) {
+        let cluster_index = (sector / u64::from(self.sectors_per_cluster)) as u32;
+        let sector_in_cluster = (sector % u64::from(self.sectors_per_cluster)) as u32;
         self.read_sector_in_cluster(cluster_index, sector_in_cluster, buffer);
     }
 
+    /// Returns `false` if the write could not be applied
+    pub fn write_sector(&mut self, sector: u64, buffer: &[u8]) -> bool {
+        let cluster_index = (sector / u64::from(self.sectors_per_cluster)) as u32;
+        let sector_in_cluster = (sector % u64::from(self.sectors_per_cluster)) as u32;
+        self.dirty_clusters.insert(cluster_index);
+        self.write_sector_in_cluster(cluster_index, sector_in_cluster, buffer)
+    }
+
     /// `sector` is cluster relative index
-    fn read_sector_in_cluster(&mut self, cluster_index: u32, sector: u32, buffer: &mut [u8]) {
-        if (cluster_index >= self.allocation_bitmap_start_cluster)
-            && (cluster_index < self.allocation_bitmap_end_cluster)
-        {
-            let relative_cluster = cluster_index - self.allocation_bitmap_start_cluster;
-            let bitmap_sector = (relative_cluster * self.sectors_per_cluster) + sector;
-            self.allocation_bitmap.read_sector(bitmap_sector, buffer);
+    fn write_sector_in_cluster(&mut self, cluster_index: u32, sector: u32, buffer: &[u8]) -> bool {
+        if let Some((second, bitmap_sector)) = self.allocation_bitmap_sector(cluster_index, sector) {
+            let allocation_bitmap = match second {
+                false => &mut self.allocation_bitmap,
+                true => self.second_allocation_bitmap.as_mut().unwrap(),
+            };
+
+            let mut previous = vec![0; buffer.len()];
+            allocation_bitmap.read_sector(bitmap_sector, &mut previous);
+            allocation_bitmap.write_sector(bitmap_sector, buffer);
+
+            // inactive bitmap is only kept until the guest switches to it
+            if second == self.fat.is_second_active() {
+                let first_cluster = bitmap_sector * self.bytes_per_sector * 8;
+                self.apply_allocation_changes(first_cluster, &previous);
+            }
+            true
         } else if cluster_index >= self.upcase_table_start_cluster
             && cluster_index < self.upcase_table_end_cluster
         {
-            let relative_cluster = cluster_index - self.upcase_table_start_cluster;
-            let sector = (relative_cluster * self.sectors_per_cluster) + sector;
-
-            let bytes_to_skip = sector as usize * self.bytes_per_sector as usize;
-            let table: &[u8] = bytemuck::cast_slice(&UPCASE_TABLE);
-            let sector_data = table
-                .iter()
-                .skip(bytes_to_skip)
-                .take(self.bytes_per_sector as usize)
-                .cloned();
-
-            for (out, byte) in buffer.iter_mut().zip(sector_data) {
-                *out = byte;
-            }
+            false
         } else if let Some(first_cluster) = self.cluster_lookup.get(&cluster_index).cloned() {
             let cluster = self.heap.get_mut(&first_cluster).unwrap();
-            let sector = (cluster_index - first_cluster) * self.sectors_per_cluster + sector;
             match &mut cluster.data {
-                ClusterData::DirectoryEntries(entries) => entries.read_sector(sector, buffer),
-                ClusterData::FileMappedData(file) => {
-                    file.read_sector(u64::from(sector) * u64::from(self.bytes_per_sector), buffer)
+                ClusterData::GuestData(data) => {
+                    data.write_sector(u64::from(sector), buffer);
+                    true
                 }
-            }
-        }
-    }
-
-    pub(crate) fn root_directory_cluster(&self) -> u32 {
-        self.upcase_table_end_cluster
-    }
-
-    fn is_name_in_cluster_chain(&self, root_index: u32, upcased_name_hash: u16, file_name: &[u16]) -> bool {
-        let cluster_chain: Vec<u32> = [root_index]
-            .into_iter()
-            .chain(self.fat.chain(root_index))
-            .collect();
+                ClusterData::DirectoryEntries(_) => {
+                    let dir_cluster = self.directory_of(cluster_index);
+                    if !self.known_entry_sets.contains_key(&dir_cluster) {
+                        let entry_sets = self.entry_sets(dir_cluster);
+                        self.known_entry_sets.insert(dir_cluster, entry_sets);
+                    }
 
-        let mut hash_matched = false;
-        let mut name_len = 0;
-        let mut name_offset = 0;
-        let mut name_matched = false;
+                    let cluster = self.heap.get_mut(&first_cluster).unwrap();
+                    if let ClusterData::DirectoryEntries(entries) = &mut cluster.data {
+                        entries.write_sector(u64::from(sector), buffer);
+                    }
 
-        // check hash in stream extension, if it matches, then check the file name
-        for cluster_index in cluster_chain {
-            if let Some(cluster) = self.heap.get(&cluster_index) {
-                if let ClusterData::DirectoryEntries(entries) = &cluster.data {
-                    for entry in entries.0.iter() {
-                        match entry {
-                            DirectoryEntry::StreamExtension(stream_extension) => {
-                                hash_matched = stream_extension.name_hash == upcased_name_hash;
-                                name_len = usize::from(stream_extension.name_length);
-                                name_offset = 0;
-                                name_matched = false;
-                            },
-                            DirectoryEntry::FileName(e) => {
-                                if !hash_matched {
-                                    continue;
+                    self.record_directory_events(dir_cluster);
+                    true
+                }
+                ClusterData::FileMappedData(file) => {
+                    let bytes_per_sector = u64::from(self.bytes_per_sector);
+                    let cluster_offset = u64::from(cluster_index - first_cluster)
+                        * u64::from(self.sectors_per_cluster)
+                        * bytes_per_sector;
+                    let offset_in_cluster = u64::from(sector) * bytes_per_sector;
+
+                    match &mut self.delta_store {
+                        Some(delta_store) if file.mode == MappingMode::CopyOnWrite => {
+                            if !delta_store.contains(cluster_index) {
+                                let mut data =
+                                    vec![0; (self.sectors_per_cluster * self.bytes_per_sector) as usize];
+                                for (sector, sector_data) in
+                                    data.chunks_mut(self.bytes_per_sector as usize).enumerate()
+                                {
+                                    let offset = cluster_offset + sector as u64 * bytes_per_sector;
+                                    file.read_sector(offset, sector_data);
                                 }
 
-                                let name_chunk1 = file_name.iter().skip(name_offset).take(e.file_name.len()).cloned();
-                                let name_chunk2 = e.file_name.iter().cloned().take_while(|&c| c != 0);
-                                for (c1, c2) in name_chunk1.zip(name_chunk2) {
-                                    name_matched = c1 == c2;
-                                    name_offset += 1;
-                                    if !name_matched {
-                                        hash_matched = false;
-                                        continue;
-                                    }
+                                if delta_store.insert_cluster(cluster_index, &data).is_err() {
+                                    return false;
                                 }
+                            }
 
-                                if name_offset == name_len && name_matched {
-                                    return true;
-                                }
-                            },
-                            _ => {}
+                            delta_store
+                                .write_sector(cluster_index, offset_in_cluster, buffer)
+                                .is_ok()
                         }
+                        _ => file.write_sector(cluster_offset + offset_in_cluster, buffer),
                     }
                 }
             }
+        } else {
+            // guest can write the data before marking the cluster as allocated
+            self.insert_guest_cluster(cluster_index);
+            self.write_sector_in_cluster(cluster_index, sector, buffer)
         }
+    }
 
-        false
+    /// Keep track of clusters the guest allocated or freed in the allocation bitmap,
+    /// `previous` is the bitmap sector starting at `first_cluster` before it was overwritten
+    fn apply_allocation_changes(&mut self, first_cluster: u32, previous: &[u8]) {
+        for (byte_index, previous_byte) in previous.iter().cloned().enumerate() {
+            let byte_first_cluster = first_cluster + (byte_index as u32 * 8);
+
+            for bit in 0..8 {
+                let cluster_index = byte_first_cluster + bit;
+                if cluster_index >= self.allocation_bitmap.cluster_count() {
+                    return;
+                }
+
+                let was_allocated = previous_byte & (1 << bit) > 0;
+                let is_allocated = self.active_allocation_bitmap().is_allocated(cluster_index);
+                match (was_allocated, is_allocated) {
+                    (false, true) if !self.cluster_lookup.contains_key(&cluster_index) => {
+                        self.insert_guest_cluster(cluster_index);
+                    }
+                    (true, false) => self.forget_cluster(cluster_index),
+                    _ => {}
+                }
+            }
+        }
     }
 
-    fn increase_parent_directory_size(&mut self, dir_cluster: u32) {
-        if dir_cluster == self.root_directory_cluster() {
+    /// Guest freed the cluster, whatever reuses it must not be parsed as or written to what the cluster held before
+    fn forget_cluster(&mut self, cluster_index: u32) {
+        let Some(&run_cluster) = self.cluster_lookup.get(&cluster_index) else {
             return;
+        };
+
+        match self.heap.get(&run_cluster).map(|cluster| &cluster.data) {
+            Some(ClusterData::GuestData(_)) => {}
+            Some(ClusterData::DirectoryEntries(_)) if cluster_index != self.root_directory_cluster() => {
+                self.directory_lookup.remove(&cluster_index);
+                self.known_entry_sets.remove(&cluster_index);
+            }
+            Some(ClusterData::FileMappedData(_)) => return self.unmap_cluster(run_cluster, cluster_index),
+            _ => return,
         }
 
-        let parent_cluster = self.parent_lookup.get(&dir_cluster).cloned().unwrap();
-        let cluster_chain: Vec<u32> = [parent_cluster]
-            .into_iter()
-            .chain(self.fat.chain(parent_cluster))
+        self.heap.remove(&cluster_index);
+        self.cluster_lookup.remove(&cluster_index);
+    }
+
+    /// Split the run of a mapped file around the cluster, sectors pending for the cluster are dropped
+    fn unmap_cluster(&mut self, run_cluster: u32, cluster_index: u32) {
+        let run = self.heap.remove(&run_cluster).map(|cluster| cluster.data);
+        let Some(ClusterData::FileMappedData(mut run)) = run else {
+            return;
+        };
+        self.cluster_lookup.remove(&cluster_index);
+
+        let cluster_size = u64::from(self.sectors_per_cluster * self.bytes_per_sector);
+        let cluster_offset = run.offset + u64::from(cluster_index - run_cluster) * cluster_size;
+        let mut following_pending_sectors = run.pending_sectors.split_off(&cluster_offset);
+        following_pending_sectors = following_pending_sectors.split_off(&(cluster_offset + cluster_size));
+
+        let following_clusters: Vec<u32> = (cluster_index + 1..)
+            .take_while(|next_cluster| self.cluster_lookup.get(next_cluster) == Some(&run_cluster))
             .collect();
+        let (leading_run, following_run) = match (cluster_index == run_cluster, following_clusters.is_empty()) {
+            (true, true) => (None, None),
+            // nothing is left before the cluster, the run moves on
+            (true, false) => (None, Some(run)),
+            (false, true) => (Some(run), None),
+            (false, false) => {
+                let following_run = run
+                    .file
+                    .try_clone()
+                    .ok()
+                    .map(|file| FileMappedData {
+                        file,
+                        offset: 0,
+                        mode: run.mode,
+                        writable_length: run.writable_length,
+                        pending_sectors: BTreeMap::new(),
+                    });
+                (Some(run), following_run)
+            }
+        };
 
-        // look for stream extension entry, keep track of file entry while doing so
-        let mut file_entry_pos = None;
-        let mut stream_ext_pos = None;
+        if let Some(leading_run) = leading_run {
+            self.heap.insert(
+                run_cluster,
+                Cluster {
+                    data: ClusterData::FileMappedData(leading_run),
+                },
+            );
+        }
+        match following_run {
+            Some(mut following_run) => {
+                following_run.offset = cluster_offset + cluster_size;
+                following_run.pending_sectors = following_pending_sectors;
+                for next_cluster in following_clusters {
+                    self.cluster_lookup.insert(next_cluster, cluster_index + 1);
+                }
+                self.heap.insert(
+                    cluster_index + 1,
+                    Cluster {
+                        data: ClusterData::FileMappedData(following_run),
+                    },
+                );
+            }
+            None => {
+                for next_cluster in following_clusters {
+                    self.cluster_lookup.remove(&next_cluster);
+                }
+            }
+        }
+    }
 
-        'outer: for (cluster_idx, cluster_id) in cluster_chain.iter().cloned().enumerate() {
-            let cluster = self.heap.get_mut(&cluster_id).unwrap();
-            for (entry_idx, entry) in cluster.as_entries_mut().unwrap().iter_mut().enumerate() {
-                match entry {
-                    DirectoryEntry::File(_) => {
-                        file_entry_pos = Some((cluster_idx, entry_idx));
-                    }
-                    DirectoryEntry::StreamExtension(stream_ext) => {
-                        // FAT index
-                        if stream_ext.first_cluster != dir_cluster + 2 {
-                            continue;
-                        }
+    fn insert_guest_cluster(&mut self, cluster_index: u32) {
+        self.cluster_lookup.insert(cluster_index, cluster_index);
+        self.heap.entry(cluster_index).or_insert(Cluster {
+            data: ClusterData::GuestData(GuestData(Vec::new())),
+        });
+    }
 
-                        stream_ext_pos = Some((cluster_idx, entry_idx));
+    fn allocate_next_cluster(&mut self) -> Option<u32> {
+        self.allocate_clusters(1)
+    }
 
-                        // update length and flags in stream in extension
-                        let cluster_size = self.sectors_per_cluster * self.bytes_per_sector;
+    /// Allocates `count` contiguous clusters in both allocation bitmaps, returns the first one
+    fn allocate_clusters(&mut self, count: u32) -> Option<u32> {
+        let allocation_bitmap = match (&mut self.second_allocation_bitmap, self.fat.is_second_active()) {
+            (Some(second_allocation_bitmap), true) => second_allocation_bitmap,
+            _ => &mut self.allocation_bitmap,
+        };
 
-                        stream_ext.general_secondary_flags =
-                            stream_ext.general_secondary_flags.with_no_fat_chain(false);
-                        stream_ext.data_length += u64::from(cluster_size);
-                        stream_ext.valid_data_length = stream_ext.data_length;
+        // guest can write to clusters before marking them as allocated, runs containing them are skipped
+        let mut skipped = Vec::new();
+        let cluster_index = loop {
+            let Some(cluster_index) = allocation_bitmap.allocate(count, self.allocation_strategy) else {
+                break None;
+            };
+            let in_use: Vec<u32> = (cluster_index..cluster_index + count)
+                .filter(|cluster_index| self.cluster_lookup.contains_key(cluster_index))
+                .collect();
+            if in_use.is_empty() {
+                break Some(cluster_index);
+            }
 
-                        break 'outer;
-                    }
-                    _ => continue,
-                }
+            allocation_bitmap.set_clusters(cluster_index, count, false);
+            for cluster_index in in_use {
+                allocation_bitmap.set_cluster(cluster_index, true);
+                skipped.push(cluster_index);
             }
+        };
+        for cluster_index in skipped {
+            allocation_bitmap.set_cluster(cluster_index, false);
         }
+        let cluster_index = cluster_index?;
 
-        let (stream_ext_chain_idx, stream_ext_entry_idx) = stream_ext_pos.unwrap();
-        let (file_chain_idx, file_entry_idx) = file_entry_pos.unwrap();
+        self.allocation_bitmap.set_clusters(cluster_index, count, true);
+        if let Some(second_allocation_bitmap) = &mut self.second_allocation_bitmap {
+            second_allocation_bitmap.set_clusters(cluster_index, count, true);
+        }
+        self.release_entries(cluster_index, count);
 
-        let file_name_entries_count;
+        Some(cluster_index)
+    }
 
-        // calculate the checksum
-        let mut checksum = 0;
+    /// Host reuses clusters the guest freed, entries which started at them are gone.
+    /// Mapped entries are kept by path for the export report, along with the entries within them.
+    fn release_entries(&mut self, first_cluster: u32, count: u32) {
+        for cluster_index in first_cluster..first_cluster + count {
+            self.parent_lookup.remove(&cluster_index);
+            if !self.mapped_entries.contains_key(&cluster_index) {
+                continue;
+            }
 
-        {
-            let cluster_id = cluster_chain[file_chain_idx];
-            let cluster = self.heap.get(&cluster_id).unwrap();
-            let entry = cluster.as_entries().unwrap().get(file_entry_idx).unwrap();
-            match entry {
-                DirectoryEntry::File(file) => {
-                    file_name_entries_count = file.secondary_count - 1;
-                    checksum = entry_checksum(checksum, bytemuck::bytes_of(file), true);
+            let released: Vec<(u32, Option<PathBuf>)> = self
+                .mapped_entries
+                .keys()
+                .cloned()
+                .filter(|&first_cluster| self.is_mapped_within(first_cluster, cluster_index))
+                .map(|first_cluster| (first_cluster, self.mapped_path(first_cluster)))
+                .collect();
+            for (first_cluster, path) in released {
+                let entry = self.mapped_entries.remove(&first_cluster).unwrap();
+                if let Some(path) = path {
+                    self.released_entries.insert(path, (first_cluster, entry));
                 }
-                _ => panic!("expected file entry, got {entry:?}"),
             }
         }
+    }
 
-        {
-            let cluster_id = cluster_chain[stream_ext_chain_idx];
-            let cluster = self.heap.get(&cluster_id).unwrap();
-            let entry = cluster
-                .as_entries()
-                .unwrap()
-                .get(stream_ext_entry_idx)
-                .unwrap();
-            match entry {
-                DirectoryEntry::StreamExtension(stream_ext) => {
-                    checksum = entry_checksum(checksum, bytemuck::bytes_of(stream_ext), false);
-                }
-                _ => panic!("expected stream extension entry, got {entry:?}"),
+    /// Whether the mapped entry is the mapped directory or somewhere within it
+    fn is_mapped_within(&self, first_cluster: u32, dir_cluster: u32) -> bool {
+        let mut cluster = first_cluster;
+        for _ in 0..=self.mapped_entries.len() {
+            if cluster == dir_cluster {
+                return true;
             }
+            match self.mapped_entries.get(&cluster) {
+                Some(entry) => cluster = entry.dir_cluster,
+                None => return false,
+            }
+        }
+
+        false
+    }
+
+    /// Percentage of allocated clusters in the active allocation bitmap, rounded down
+    pub fn percent_in_use(&self) -> u8 {
+        let allocation_bitmap = self.active_allocation_bitmap();
+        let allocated = u64::from(allocation_bitmap.allocated_count());
+        (allocated * 100 / u64::from(allocation_bitmap.cluster_count())) as u8
+    }
+
+    /// Allocation bitmap of the active FAT
+    fn active_allocation_bitmap(&self) -> &AllocationBitmap {
+        match (&self.second_allocation_bitmap, self.fat.is_second_active()) {
+            (Some(second_allocation_bitmap), true) => second_allocation_bitmap,
+            _ => &self.allocation_bitmap,
         }
+    }
 
+    /// Whether the cluster belongs to the second allocation bitmap and the sector within the bitmap,
+    /// `None` if the cluster is not part of either allocation bitmap
+    fn allocation_bitmap_sector(&self, cluster_index: u32, sector: u32) -> Option<(bool, u32)> {
+        let (second, start_cluster) = if (self.allocation_bitmap_start_cluster
+            ..self.allocation_bitmap_end_cluster)
+            .contains(&cluster_index)
         {
-            let cluster_id = cluster_chain[stream_ext_chain_idx];
-            let cluster = self.heap.get(&cluster_id).unwrap();
+            (false, self.allocation_bitmap_start_cluster)
+        } else if (self.second_allocation_bitmap_start_cluster..self.second_allocation_bitmap_end_cluster)
+            .contains(&cluster_index)
+        {
+            (true, self.second_allocation_bitmap_start_cluster)
+        } else {
+            return None;
+        };
 
-            let mut entries_found = 0;
+        Some((second, (cluster_index - start_cluster) * self.sectors_per_cluster + sector))
+    }
 
-            for entry in cluster
-                .as_entries()
-                .unwrap()
-                .iter()
+    /// Follows the guest switching between the FATs and allocation bitmaps with the `ActiveFat` volume flag
+    pub fn set_active_fat(&mut self, second: bool) {
+        if self.second_allocation_bitmap.is_none() || second == self.fat.is_second_active() {
+            return;
+        }
+
+        let mut previous_bitmap = self.active_allocation_bitmap().as_bytes().to_vec();
+        let mut previous_fat = self.fat.active().to_vec();
+        self.fat.set_second_active(second);
+
+        // both are grown lazily, entries past the end are free
+        let bitmap_len = previous_bitmap.len().max(self.active_allocation_bitmap().as_bytes().len());
+        previous_bitmap.resize(bitmap_len, 0);
+        self.apply_allocation_changes(0, &previous_bitmap);
+
+        let mut current_fat = self.fat.active().to_vec();
+        let fat_len = previous_fat.len().max(current_fat.len());
+        previous_fat.resize(fat_len, 0);
+        current_fat.resize(fat_len, 0);
+        self.apply_fat_changes(0, &previous_fat, &current_fat);
+    }
+
+    /// `second` selects the FAT the guest wrote to
+    pub fn write_fat_sector(&mut self, second: bool, fat_sector: u64, buffer: &[u8]) {
+        let mut previous = vec![0; buffer.len()];
+        if second {
+            self.fat.read_sector_second(fat_sector, &mut previous);
+            self.fat.write_sector_second(fat_sector, buffer);
+        } else {
+            self.fat.read_sector_first(fat_sector, &mut previous);
+            self.fat.write_sector_first(fat_sector, buffer);
+        }
+
+        // inactive FAT is only kept until the guest switches to it
+        if second == self.fat.is_second_active() {
+            let entries_per_sector = buffer.len() / size_of::<u32>();
+            self.apply_fat_changes(
+                fat_sector as usize * entries_per_sector,
+                bytemuck::cast_slice(&previous),
+                bytemuck::cast_slice(buffer),
+            );
+        }
+    }
+
+    /// `previous` and `current` are FAT entries starting at `first_fat_index`
+    fn apply_fat_changes(&mut self, first_fat_index: usize, previous: &[u32], current: &[u32]) {
+        let cluster_count = self.allocation_bitmap.cluster_count();
+        // free, bad cluster or end of chain are not a next cluster
+        let next_cluster = |entry: u32| entry.checked_sub(2).filter(|&next| next < cluster_count);
+
+        let mut unlinked = Vec::new();
+        for (entry_index, (previous, current)) in previous.iter().zip(current).enumerate() {
+            let fat_index = first_fat_index + entry_index;
+            if previous == current || fat_index < 2 {
+                continue;
+            }
+
+            let cluster_index = fat_index as u32 - 2;
+            let Some(&dir_cluster) = self.directory_lookup.get(&cluster_index) else {
+                continue;
+            };
+
+            // clusters the guest appended to directories hold entries as well
+            if let Some(next_cluster) = next_cluster(*current) {
+                self.convert_to_directory(next_cluster, dir_cluster);
+            }
+            if let Some(previous_next_cluster) = next_cluster(*previous) {
+                unlinked.push((dir_cluster, previous_next_cluster));
+            }
+        }
+
+        // clusters the guest cut off directories hold plain data again, unless they were relinked
+        for (dir_cluster, cluster_index) in unlinked {
+            let chain: HashSet<u32> = self.directory_chain(dir_cluster).into_iter().collect();
+            let cut_off: Vec<u32> = [cluster_index]
+                .into_iter()
+                .chain(self.fat.chain(cluster_index))
+                .take_while(|cluster_index| {
+                    self.directory_lookup.get(cluster_index) == Some(&dir_cluster) && !chain.contains(cluster_index)
+                })
+                .collect();
+            for cluster_index in cut_off {
+                self.convert_to_guest_data(cluster_index);
+            }
+        }
+    }
+
+    fn is_directory_cluster(&self, cluster_index: u32) -> bool {
+        self.cluster_lookup
+            .get(&cluster_index)
+            .and_then(|first_cluster| self.heap.get(first_cluster))
+            .map(|cluster| matches!(cluster.data, ClusterData::DirectoryEntries(_)))
+            .unwrap_or(false)
+    }
+
+    /// Re-parse cluster written by the guest as directory entries of the directory starting at `dir_cluster`
+    fn convert_to_directory(&mut self, cluster_index: u32, dir_cluster: u32) {
+        let data = match self.cluster_lookup.get(&cluster_index) {
+            None => Vec::new(),
+            Some(&first_cluster) if first_cluster == cluster_index => {
+                match self.heap.get_mut(&cluster_index).map(|cluster| &mut cluster.data) {
+                    Some(ClusterData::GuestData(data)) => std::mem::take(&mut data.0),
+                    _ => return, // already directory, or belongs to a mapped file
+                }
+            }
+            Some(_) => return,
+        };
+
+        let mut entries = DirectoryEntries(Vec::new());
+        for (sector, sector_data) in data.chunks(self.bytes_per_sector as usize).enumerate() {
+            entries.write_sector(sector as u64, sector_data);
+        }
+
+        self.cluster_lookup.insert(cluster_index, cluster_index);
+        self.directory_lookup.insert(cluster_index, dir_cluster);
+        self.heap.insert(
+            cluster_index,
+            Cluster {
+                data: ClusterData::DirectoryEntries(entries),
+            },
+        );
+    }
+
+    /// Keep the entries of a cluster which is no longer part of a directory as the bytes the guest wrote
+    fn convert_to_guest_data(&mut self, cluster_index: u32) {
+        let Some(cluster) = self.heap.get_mut(&cluster_index) else {
+            return;
+        };
+        let Some(entries) = cluster.as_entries() else {
+            return;
+        };
+
+        let data = entries.iter().flat_map(|entry| entry.as_bytes()).cloned().collect();
+        cluster.data = ClusterData::GuestData(GuestData(data));
+        self.directory_lookup.remove(&cluster_index);
+    }
+
+    /// First cluster of the directory the cluster belongs to
+    fn directory_of(&self, cluster_index: u32) -> u32 {
+        // not reachable from any known directory yet
+        self.directory_lookup.get(&cluster_index).cloned().unwrap_or(cluster_index)
+    }
+
+    /// Clusters of the directory, in order. Directories other than the root directory are looked up in their parent,
+    /// contiguous ones don't have a FAT chain.
+    fn directory_chain(&self, dir_cluster: u32) -> Vec<u32> {
+        self.directory_chain_within(dir_cluster, self.parent_lookup.len())
+    }
+
+    /// `depth` is the number of parents left to look up, guards against cycles the guest could make
+    fn directory_chain_within(&self, dir_cluster: u32, depth: usize) -> Vec<u32> {
+        let stream_extension = match self.parent_lookup.get(&dir_cluster) {
+            Some(&parent_cluster) if dir_cluster != self.root_directory_cluster() && depth > 0 => self
+                .all_entry_sets_in(&self.directory_chain_within(parent_cluster, depth - 1))
+                .into_iter()
+                .find(|(entry_set, checksum_valid)| {
+                    *checksum_valid
+                        && entry_set.file.file_attributes.directory()
+                        && entry_set.first_cluster() == Some(dir_cluster)
+                })
+                .map(|(entry_set, _)| entry_set.stream_extension),
+            _ => None,
+        };
+
+        match stream_extension {
+            Some(stream_extension) if stream_extension.general_secondary_flags.no_fat_chain() => {
+                self.allocation_chain(dir_cluster, &stream_extension)
+            }
+            // guest can link clusters before it updates the length of the directory
+            _ => self.fat_chain(dir_cluster),
+        }
+    }
+
+    /// First cluster followed by the clusters linked to it in the FAT
+    fn fat_chain(&self, first_cluster: u32) -> Vec<u32> {
+        [first_cluster]
+            .into_iter()
+            .chain(self.fat.chain(first_cluster))
+            .take(self.allocation_bitmap.cluster_count() as usize) // guard against cycles
+            .collect()
+    }
+
+    /// Entry sets with a valid checksum in the specified directory
+    fn entry_sets(&self, dir_cluster: u32) -> Vec<EntrySet> {
+        self.all_entry_sets(dir_cluster)
+            .into_iter()
+            .filter_map(|(entry_set, checksum_valid)| checksum_valid.then_some(entry_set))
+            .collect()
+    }
+
+    /// Entry sets in use in the specified directory, and whether their checksum is valid
+    fn all_entry_sets(&self, dir_cluster: u32) -> Vec<(EntrySet, bool)> {
+        self.all_entry_sets_in(&self.directory_chain(dir_cluster))
+    }
+
+    /// Entry sets in use in the specified directory clusters, and whether their checksum is valid
+    fn all_entry_sets_in(&self, clusters: &[u32]) -> Vec<(EntrySet, bool)> {
+        self.indexed_entry_sets_in(clusters)
+            .into_iter()
+            .map(|(_, entry_set, checksum_valid)| (entry_set, checksum_valid))
+            .collect()
+    }
+
+    /// Same as [`Self::all_entry_sets_in`], along with the index of the file entry among the entries of the clusters
+    fn indexed_entry_sets_in(&self, clusters: &[u32]) -> Vec<(usize, EntrySet, bool)> {
+        let entries: Vec<&DirectoryEntry> = clusters
+            .iter()
+            .filter_map(|cluster_index| self.heap.get(cluster_index))
+            .filter_map(|cluster| cluster.as_entries())
+            .flatten()
+            .collect();
+
+        let mut entry_sets = Vec::new();
+
+        for (entry_index, entry) in entries.iter().enumerate() {
+            let file = match entry {
+                DirectoryEntry::File(file) => file,
+                _ => continue,
+            };
+
+            let set_end = entry_index + 1 + usize::from(file.secondary_count);
+            let set = match entries.get(entry_index..set_end) {
+                Some(set) => set,
+                None => continue,
+            };
+
+            let stream_extension = match set[1] {
+                DirectoryEntry::StreamExtension(stream_extension) => stream_extension,
+                _ => continue,
+            };
+
+            let name_length = usize::from(stream_extension.name_length);
+            let file_name_entries_count = unsigned_rounded_up_div(name_length.max(1), 15);
+            let mut name = Vec::with_capacity(name_length);
+            for entry in set.iter().skip(2).take(file_name_entries_count) {
+                if let DirectoryEntry::FileName(file_name) = entry {
+                    name.extend(file_name.file_name.iter().cloned());
+                }
+            }
+            if name.len() < name_length {
+                continue;
+            }
+            name.truncate(name_length);
+
+            let checksum = set
+                .iter()
+                .enumerate()
+                .fold(0, |checksum, (index, entry)| {
+                    entry_checksum(checksum, entry.as_bytes(), index == 0)
+                });
+
+            let entry_set = EntrySet {
+                file: *file,
+                stream_extension: *stream_extension,
+                name,
+            };
+            entry_sets.push((entry_index, entry_set, checksum == file.set_checksum));
+        }
+
+        entry_sets
+    }
+
+    /// Entry set with a valid checksum and the name in the specified directory,
+    /// along with the cluster and index within the cluster of each of its entries
+    fn find_entry_set(&self, dir_cluster: u32, name: &str) -> Option<(EntrySet, Vec<(u32, usize)>)> {
+        let name_utf16: Vec<u16> = name.encode_utf16().collect();
+        let upcased = upcased_name(&name_utf16);
+        let clusters = self.directory_chain(dir_cluster);
+
+        let (entry_index, entry_set, _) = self
+            .indexed_entry_sets_in(&clusters)
+            .into_iter()
+            .find(|(_, entry_set, checksum_valid)| *checksum_valid && upcased_name(&entry_set.name) == upcased)?;
+
+        let positions = clusters
+            .iter()
+            .filter_map(|&cluster_index| {
+                let entries = self.heap.get(&cluster_index)?.as_entries()?;
+                Some((0..entries.len()).map(move |index| (cluster_index, index)))
+            })
+            .flatten()
+            .skip(entry_index)
+            .take(1 + usize::from(entry_set.file.secondary_count))
+            .collect();
+
+        Some((entry_set, positions))
+    }
+
+    /// Compare the entry sets the directory has now with what it had before the write
+    fn record_directory_events(&mut self, dir_cluster: u32) {
+        let previous = self.known_entry_sets.remove(&dir_cluster).unwrap_or_default();
+        let (entry_sets, pending): (Vec<_>, Vec<_>) = self
+            .all_entry_sets(dir_cluster)
+            .into_iter()
+            .partition(|(_, checksum_valid)| *checksum_valid);
+        let entry_sets: Vec<EntrySet> = entry_sets.into_iter().map(|(set, _)| set).collect();
+        let pending: Vec<EntrySet> = pending.into_iter().map(|(set, _)| set).collect();
+
+        // sets without an allocation can only be told apart by their name
+        let key = |set: &EntrySet| match set.first_cluster() {
+            Some(first_cluster) => (Some(first_cluster), Vec::new()),
+            None => (None, set.name.clone()),
+        };
+
+        for set in entry_sets.iter() {
+            let previous_sets: Vec<&EntrySet> =
+                previous.iter().filter(|p| key(p) == key(set)).collect();
+
+            if previous_sets.is_empty() {
+                self.directory_events.push(DirectoryEvent::Created {
+                    dir_cluster,
+                    name: set.name_lossy(),
+                    first_cluster: set.first_cluster(),
+                });
+
+                if set.file.file_attributes.directory() {
+                    if let Some(first_cluster) = set.first_cluster() {
+                        self.adopt_guest_directory(dir_cluster, first_cluster, &set.stream_extension);
+                    }
+                }
+            } else {
+                if previous_sets.iter().all(|p| p.name != set.name) {
+                    // renamed set can be written before the old one is marked as not in use
+                    let previous_set = previous_sets.last().unwrap();
+                    self.directory_events.push(DirectoryEvent::Renamed {
+                        dir_cluster,
+                        old_name: previous_set.name_lossy(),
+                        new_name: set.name_lossy(),
+                        first_cluster: set.first_cluster().unwrap(),
+                    });
+                }
+
+                let resized = previous_sets
+                    .iter()
+                    .all(|p| p.stream_extension != set.stream_extension);
+                if resized && set.file.file_attributes.directory() {
+                    // clusters the guest added to a contiguous directory hold entries as well
+                    if let Some(first_cluster) = set.first_cluster() {
+                        self.adopt_guest_directory(dir_cluster, first_cluster, &set.stream_extension);
+                    }
+                } else if resized {
+                    self.resize_mapped_file(set);
+                }
+            }
+        }
+
+        // sets which are still being written keep their previous state
+        let mut known = entry_sets;
+        for set in previous.into_iter() {
+            if known.iter().any(|s| key(s) == key(&set)) {
+                continue;
+            }
+
+            if pending.iter().any(|s| key(s) == key(&set)) {
+                known.push(set);
+            } else {
+                self.directory_events.push(DirectoryEvent::Deleted {
+                    dir_cluster,
+                    name: set.name_lossy(),
+                    first_cluster: set.first_cluster(),
+                });
+            }
+        }
+
+        self.known_entry_sets.insert(dir_cluster, known);
+    }
+
+    /// Directory created by the guest, its clusters have to be parsed as directory entries
+    fn adopt_guest_directory(
+        &mut self,
+        parent_cluster: u32,
+        first_cluster: u32,
+        stream_extension: &StreamExtensionDirectoryEntry,
+    ) {
+        let clusters = self.allocation_chain(first_cluster, stream_extension);
+        for cluster_index in clusters {
+            self.convert_to_directory(cluster_index, first_cluster);
+        }
+
+        self.parent_lookup.insert(first_cluster, parent_cluster);
+    }
+
+    /// Clusters allocated to the stream, in order
+    fn allocation_chain(
+        &self,
+        first_cluster: u32,
+        stream_extension: &StreamExtensionDirectoryEntry,
+    ) -> Vec<u32> {
+        let cluster_size = u64::from(self.sectors_per_cluster * self.bytes_per_sector);
+        let size_clusters = unsigned_rounded_up_div(stream_extension.data_length.max(1), cluster_size);
+
+        if stream_extension.general_secondary_flags.no_fat_chain() {
+            let end_cluster = u64::min(
+                u64::from(first_cluster) + size_clusters,
+                u64::from(self.allocation_bitmap.cluster_count()),
+            );
+            (first_cluster..end_cluster as u32).collect()
+        } else {
+            let mut chain = self.fat_chain(first_cluster);
+            chain.truncate(size_clusters as usize);
+            chain
+        }
+    }
+
+    /// Guest changed the length of a file, extend or truncate the host file mapped with write-back
+    fn resize_mapped_file(&mut self, entry_set: &EntrySet) {
+        let first_cluster = match entry_set.first_cluster() {
+            Some(first_cluster) => first_cluster,
+            None => return,
+        };
+        let file = match self.heap.get(&first_cluster).map(|cluster| &cluster.data) {
+            Some(ClusterData::FileMappedData(data)) if data.writable_length.is_some() => {
+                match data.file.try_clone() {
+                    Ok(file) => file,
+                    Err(_) => return,
+                }
+            }
+            _ => return,
+        };
+
+        let valid_data_length = entry_set.stream_extension.valid_data_length;
+        let cluster_size = u64::from(self.sectors_per_cluster * self.bytes_per_sector);
+        let clusters = self.allocation_chain(first_cluster, &entry_set.stream_extension);
+
+        // clusters the guest appended to the file become part of the mapping
+        for (index, cluster_index) in clusters.iter().cloned().enumerate() {
+            let guest_data = match self.cluster_lookup.get(&cluster_index) {
+                None => Vec::new(),
+                Some(&run_cluster) => match &mut self.heap.get_mut(&run_cluster).unwrap().data {
+                    ClusterData::GuestData(data) => std::mem::take(&mut data.0),
+                    _ => continue,
+                },
+            };
+
+            let mut run = match file.try_clone() {
+                Ok(file) => FileMappedData::new(
+                    file,
+                    index as u64 * cluster_size,
+                    MappingMode::WriteBack,
+                    valid_data_length,
+                ),
+                Err(_) => return,
+            };
+            for (sector, sector_data) in guest_data.chunks(self.bytes_per_sector as usize).enumerate() {
+                run.write_sector(sector as u64 * u64::from(self.bytes_per_sector), sector_data);
+            }
+
+            self.cluster_lookup.insert(cluster_index, cluster_index);
+            self.heap.insert(
+                cluster_index,
+                Cluster {
+                    data: ClusterData::FileMappedData(run),
+                },
+            );
+        }
+
+        if file.set_len(valid_data_length).is_err() {
+            return;
+        }
+
+        let runs: Vec<u32> = clusters
+            .iter()
+            .filter_map(|cluster_index| self.cluster_lookup.get(cluster_index).cloned())
+            .dedup()
+            .collect();
+        for run_cluster in runs {
+            if let Some(ClusterData::FileMappedData(run)) =
+                self.heap.get_mut(&run_cluster).map(|cluster| &mut cluster.data)
+            {
+                run.set_writable_length(valid_data_length);
+            }
+        }
+    }
+
+    pub fn set_mapping_mode(&mut self, mapping_mode: MappingMode) {
+        self.mapping_mode = mapping_mode;
+    }
+
+    pub fn set_allocation_strategy(&mut self, allocation_strategy: AllocationStrategy) {
+        self.allocation_strategy = allocation_strategy;
+    }
+
+    /// Open the delta store, reapplying what was written to it previously if the files mapped with copy-on-write
+    /// are the same
+    pub fn open_delta_store<P>(&mut self, path: P) -> Result<(), DeltaStoreError>
+    where
+        P: AsRef<Path>,
+    {
+        let delta_store = DeltaStore::open(
+            path,
+            self.sectors_per_cluster * self.bytes_per_sector,
+            self.allocation_bitmap.cluster_count(),
+            &self.copy_on_write_mapping(),
+        )?;
+        self.delta_store = Some(delta_store);
+        Ok(())
+    }
+
+    /// Runs of files mapped with copy-on-write along with the path, length and modification time of the files,
+    /// delta store records only apply to the clusters they were written for
+    fn copy_on_write_mapping(&self) -> Vec<u8> {
+        let mut run_lengths: BTreeMap<u32, u32> = BTreeMap::new();
+        for run_cluster in self.cluster_lookup.values() {
+            *run_lengths.entry(*run_cluster).or_default() += 1;
+        }
+
+        let mut mapping = Vec::new();
+        for (run_cluster, run_length) in run_lengths {
+            let run = match self.heap.get(&run_cluster).map(|cluster| &cluster.data) {
+                Some(ClusterData::FileMappedData(run)) if run.mode == MappingMode::CopyOnWrite => run,
+                _ => continue,
+            };
+            let metadata = run.file.metadata().ok();
+            let length = metadata.as_ref().map_or(0, |metadata| metadata.len());
+            let modified = metadata
+                .and_then(|metadata| metadata.modified().ok())
+                .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                .unwrap_or_default();
+
+            mapping.extend(run_cluster.to_le_bytes());
+            mapping.extend(run_length.to_le_bytes());
+            mapping.extend(run.offset.to_le_bytes());
+            mapping.extend(length.to_le_bytes());
+            mapping.extend(modified.as_nanos().to_le_bytes());
+            if let Some(path) = self.mapped_path(run_cluster) {
+                mapping.extend(path.to_string_lossy().as_bytes());
+            }
+            mapping.push(0);
+        }
+
+        mapping
+    }
+
+    pub fn discard_delta_store(&mut self) -> std::io::Result<()> {
+        match &mut self.delta_store {
+            Some(delta_store) => delta_store.discard(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.delta_store {
+            Some(delta_store) => delta_store.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Replace the label entry of the root directory, add one if the guest dropped it
+    pub fn set_volume_label(&mut self, label: VolumeLabelDirectoryEntry) -> Result<(), VolumeLabelError> {
+        let root_directory_cluster = self.root_directory_cluster();
+        let chain = self.directory_chain(root_directory_cluster);
+
+        // label entry the guest marked as not in use is reused as well, otherwise any entry not in use
+        let candidates: [fn(&DirectoryEntry) -> bool; 2] = [
+            |entry| match entry {
+                DirectoryEntry::VolumeLabel(_) => true,
+                DirectoryEntry::Unknown(bytes) => bytes[0] == 0x03,
+                _ => false,
+            },
+            |entry| matches!(entry, DirectoryEntry::Unknown(bytes) if bytes[0] < 0x80),
+        ];
+        for is_candidate in candidates {
+            for cluster_index in &chain {
+                let entries = match self.heap.get_mut(cluster_index).and_then(|cluster| cluster.as_entries_mut()) {
+                    Some(entries) => entries,
+                    None => continue,
+                };
+
+                if let Some(entry) = entries.iter_mut().find(|entry| is_candidate(entry)) {
+                    *entry = DirectoryEntry::VolumeLabel(label);
+                    return Ok(());
+                }
+            }
+        }
+
+        self.append_entries(root_directory_cluster, vec![DirectoryEntry::VolumeLabel(label)])
+            .map_err(|_| VolumeLabelError::OutOfFreeSpace)
+    }
+
+    pub fn take_directory_events(&mut self) -> Vec<DirectoryEvent> {
+        std::mem::take(&mut self.directory_events)
+    }
+
+    /// Every file and directory reachable from the root directory, parents come before their children
+    pub(crate) fn tree(&self) -> Vec<TreeEntry> {
+        let mut tree = Vec::new();
+        let mut visited = HashSet::new();
+        let root_directory_cluster = self.root_directory_cluster();
+        let mut pending = vec![(PathBuf::new(), self.directory_chain(root_directory_cluster))];
+        visited.insert(root_directory_cluster);
+
+        while let Some((dir_path, dir_clusters)) = pending.pop() {
+            let entry_sets = self
+                .all_entry_sets_in(&dir_clusters)
+                .into_iter()
+                .filter_map(|(entry_set, checksum_valid)| checksum_valid.then_some(entry_set));
+
+            for entry_set in entry_sets {
+                // guest could write names which would escape the target directory
+                let name = entry_set.name_lossy();
+                if name == "." || name == ".." {
+                    continue;
+                }
+
+                let path = dir_path.join(name);
+                let directory = entry_set.file.file_attributes.directory();
+                let first_cluster = entry_set.first_cluster();
+                let clusters = first_cluster
+                    .map(|first_cluster| self.allocation_chain(first_cluster, &entry_set.stream_extension))
+                    .unwrap_or_default();
+
+                if let Some(first_cluster) = first_cluster.filter(|_| directory) {
+                    if visited.insert(first_cluster) {
+                        pending.push((path.clone(), clusters.clone()));
+                    }
+                }
+
+                tree.push(TreeEntry {
+                    path,
+                    directory,
+                    first_cluster,
+                    clusters,
+                    data_length: entry_set.stream_extension.data_length,
+                    valid_data_length: entry_set.stream_extension.valid_data_length,
+                });
+            }
+        }
+
+        tree
+    }
+
+    /// Path of an entry the host added, relative to the root directory
+    fn mapped_path(&self, first_cluster: u32) -> Option<PathBuf> {
+        let mut names = Vec::new();
+        let mut cluster = first_cluster;
+        while cluster != self.root_directory_cluster() {
+            let entry = self.mapped_entries.get(&cluster)?;
+            names.push(entry.name.as_str());
+            cluster = entry.dir_cluster;
+
+            if names.len() > self.mapped_entries.len() {
+                return None;
+            }
+        }
+
+        Some(names.into_iter().rev().collect())
+    }
+
+    /// Compare the tree with what the host mapped
+    pub(crate) fn export_report(&self, tree: &[TreeEntry]) -> ExportReport {
+        let released = self
+            .released_entries
+            .iter()
+            .map(|(path, (first_cluster, entry))| (path.clone(), (*first_cluster, entry)));
+        let mapped: HashMap<PathBuf, (u32, &MappedEntry)> = released
+            .chain(self.mapped_entries.iter().filter_map(|(&first_cluster, entry)| {
+                let path = self.mapped_path(first_cluster)?;
+                Some((path, (first_cluster, entry)))
+            }))
+            .collect();
+
+        let mut report = ExportReport::default();
+
+        for entry in tree {
+            match mapped.get(&entry.path) {
+                None => report.new.push(entry.path.clone()),
+                Some((_, mapped_entry)) if mapped_entry.directory != entry.directory => {
+                    report.deleted.push(entry.path.clone());
+                    report.new.push(entry.path.clone());
+                }
+                Some((first_cluster, mapped_entry)) => {
+                    let modified = !entry.directory
+                        && (entry.first_cluster != Some(*first_cluster)
+                            || entry.data_length != mapped_entry.data_length
+                            || entry.clusters.iter().any(|cluster| self.dirty_clusters.contains(cluster)));
+                    if modified {
+                        report.modified.push(entry.path.clone());
+                    }
+                }
+            }
+        }
+
+        for path in mapped.into_keys() {
+            if !tree.iter().any(|entry| entry.path == path) {
+                report.deleted.push(path);
+            }
+        }
+
+        report.new.sort();
+        report.modified.sort();
+        report.deleted.sort();
+        report
+    }
+
+    /// `sector` is cluster relative index
+    fn read_sector_in_cluster(&mut self, cluster_index: u32, sector: u32, buffer: &mut [u8]) {
+        if let Some((second, bitmap_sector)) = self.allocation_bitmap_sector(cluster_index, sector) {
+            match (second, &self.second_allocation_bitmap) {
+                (true, Some(second_allocation_bitmap)) => {
+                    second_allocation_bitmap.read_sector(bitmap_sector, buffer)
+                }
+                _ => self.allocation_bitmap.read_sector(bitmap_sector, buffer),
+            }
+        } else if cluster_index >= self.upcase_table_start_cluster
+            && cluster_index < self.upcase_table_end_cluster
+        {
+            let relative_cluster = cluster_index - self.upcase_table_start_cluster;
+            let sector = (relative_cluster * self.sectors_per_cluster) + sector;
+
+            let bytes_to_skip = sector as usize * self.bytes_per_sector as usize;
+            let table: &[u8] = bytemuck::cast_slice(&UPCASE_TABLE);
+            let sector_data = table
+                .iter()
+                .skip(bytes_to_skip)
+                .take(self.bytes_per_sector as usize)
+                .cloned();
+
+            for (out, byte) in buffer.iter_mut().zip(sector_data) {
+                *out = byte;
+            }
+        } else if let Some(first_cluster) = self.cluster_lookup.get(&cluster_index).cloned() {
+            let cluster = self.heap.get_mut(&first_cluster).unwrap();
+            let sector_in_cluster = sector;
+            let sector = u64::from(cluster_index - first_cluster) * u64::from(self.sectors_per_cluster)
+                + u64::from(sector);
+            match &mut cluster.data {
+                ClusterData::DirectoryEntries(entries) => entries.read_sector(sector, buffer),
+                ClusterData::FileMappedData(file) => {
+                    let in_delta_store = match &mut self.delta_store {
+                        Some(delta_store) if file.mode == MappingMode::CopyOnWrite => {
+                            let offset_in_cluster = u64::from(sector_in_cluster) * u64::from(self.bytes_per_sector);
+                            // store was truncated or can't be read, the host file is still intact
+                            delta_store
+                                .read_sector(cluster_index, offset_in_cluster, buffer)
+                                .unwrap_or_else(|_| {
+                                    buffer.fill(0);
+                                    false
+                                })
+                        }
+                        _ => false,
+                    };
+
+                    if !in_delta_store {
+                        file.read_sector(sector * u64::from(self.bytes_per_sector), buffer)
+                    }
+                }
+                ClusterData::GuestData(data) => data.read_sector(sector, buffer),
+            }
+        }
+    }
+
+    pub(crate) fn root_directory_cluster(&self) -> u32 {
+        self.upcase_table_end_cluster
+    }
+
+    fn is_name_in_cluster_chain(&self, root_index: u32, upcased_name_hash: u16, file_name: &[u16]) -> bool {
+        let cluster_chain = self.directory_chain(root_index);
+
+        let mut hash_matched = false;
+        let mut name_len = 0;
+        let mut name_offset = 0;
+        let mut name_matched = false;
+
+        // check hash in stream extension, if it matches, then check the file name
+        for cluster_index in cluster_chain {
+            if let Some(cluster) = self.heap.get(&cluster_index) {
+                if let ClusterData::DirectoryEntries(entries) = &cluster.data {
+                    for entry in entries.0.iter() {
+                        match entry {
+                            DirectoryEntry::StreamExtension(stream_extension) => {
+                                hash_matched = stream_extension.name_hash == upcased_name_hash;
+                                name_len = usize::from(stream_extension.name_length);
+                                name_offset = 0;
+                                name_matched = false;
+                            },
+                            DirectoryEntry::FileName(e) => {
+                                if !hash_matched {
+                                    continue;
+                                }
+
+                                let name_chunk1 = file_name.iter().skip(name_offset).take(e.file_name.len()).cloned();
+                                let name_chunk2 = e.file_name.iter().cloned().take_while(|&c| c != 0);
+                                for (c1, c2) in name_chunk1.zip(name_chunk2) {
+                                    name_matched = c1 == c2;
+                                    name_offset += 1;
+                                    if !name_matched {
+                                        hash_matched = false;
+                                        continue;
+                                    }
+                                }
+
+                                if name_offset == name_len && name_matched {
+                                    return true;
+                                }
+                            },
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Link a new cluster to the end of the directory. Contiguous directories have no FAT chain yet, the links of their
+    /// existing clusters are written before the stream extension loses its `NoFatChain` flag.
+    fn append_directory_cluster(&mut self, dir_cluster: u32, new_cluster: u32) {
+        let chain = self.directory_chain(dir_cluster);
+        for pair in chain.windows(2) {
+            self.fat.set_cluster(pair[0], pair[1]);
+        }
+        self.fat.set_cluster(*chain.last().unwrap(), new_cluster);
+        self.fat.set_cluster(new_cluster, END_OF_CHAIN);
+        self.cluster_lookup.insert(new_cluster, new_cluster);
+        self.directory_lookup.insert(new_cluster, dir_cluster);
+        self.increase_parent_directory_size(dir_cluster);
+    }
+
+    fn increase_parent_directory_size(&mut self, dir_cluster: u32) {
+        if dir_cluster == self.root_directory_cluster() {
+            return;
+        }
+
+        let parent_cluster = self.parent_lookup.get(&dir_cluster).cloned().unwrap();
+        let cluster_chain = self.directory_chain(parent_cluster);
+
+        // look for stream extension entry, keep track of file entry while doing so
+        let mut file_entry_pos = None;
+        let mut stream_ext_pos = None;
+
+        'outer: for (cluster_idx, cluster_id) in cluster_chain.iter().cloned().enumerate() {
+            let cluster = self.heap.get_mut(&cluster_id).unwrap();
+            for (entry_idx, entry) in cluster.as_entries_mut().unwrap().iter_mut().enumerate() {
+                match entry {
+                    DirectoryEntry::File(_) => {
+                        file_entry_pos = Some((cluster_idx, entry_idx));
+                    }
+                    DirectoryEntry::StreamExtension(stream_ext) => {
+                        // FAT index
+                        if stream_ext.first_cluster != dir_cluster + 2 {
+                            continue;
+                        }
+
+                        stream_ext_pos = Some((cluster_idx, entry_idx));
+
+                        // update length and flags in stream in extension
+                        let cluster_size = self.sectors_per_cluster * self.bytes_per_sector;
+
+                        stream_ext.general_secondary_flags =
+                            stream_ext.general_secondary_flags.with_no_fat_chain(false);
+                        stream_ext.data_length += u64::from(cluster_size);
+                        stream_ext.valid_data_length = stream_ext.data_length;
+
+                        break 'outer;
+                    }
+                    _ => continue,
+                }
+            }
+        }
+
+        let (stream_ext_chain_idx, stream_ext_entry_idx) = stream_ext_pos.unwrap();
+        let (file_chain_idx, file_entry_idx) = file_entry_pos.unwrap();
+
+        let file_name_entries_count;
+
+        // calculate the checksum
+        let mut checksum = 0;
+
+        {
+            let cluster_id = cluster_chain[file_chain_idx];
+            let cluster = self.heap.get(&cluster_id).unwrap();
+            let entry = cluster.as_entries().unwrap().get(file_entry_idx).unwrap();
+            match entry {
+                DirectoryEntry::File(file) => {
+                    file_name_entries_count = file.secondary_count - 1;
+                    checksum = entry_checksum(checksum, bytemuck::bytes_of(file), true);
+                }
+                _ => panic!("expected file entry, got {entry:?}"),
+            }
+        }
+
+        {
+            let cluster_id = cluster_chain[stream_ext_chain_idx];
+            let cluster = self.heap.get(&cluster_id).unwrap();
+            let entry = cluster
+                .as_entries()
+                .unwrap()
+                .get(stream_ext_entry_idx)
+                .unwrap();
+            match entry {
+                DirectoryEntry::StreamExtension(stream_ext) => {
+                    checksum = entry_checksum(checksum, bytemuck::bytes_of(stream_ext), false);
+                }
+                _ => panic!("expected stream extension entry, got {entry:?}"),
+            }
+        }
+
+        {
+            let cluster_id = cluster_chain[stream_ext_chain_idx];
+            let cluster = self.heap.get(&cluster_id).unwrap();
+
+            let mut entries_found = 0;
+
+            for entry in cluster
+                .as_entries()
+                .unwrap()
+                .iter()
                 .skip(stream_ext_entry_idx + 1)
                 .take(usize::from(file_name_entries_count))
             {
@@ -482,11 +1787,31 @@ impl ClusterHeap {
         }
     }
 
-    /// Add directory into specified root directory, returns first cluster of inserted directory
+    /// Add directory into specified root directory, returns first cluster of inserted directory.
+    /// Timestamps of the directory are left zeroed.
     pub fn add_directory(
         &mut self,
         root_cluster: u32,
         name: &str,
+    ) -> Result<u32, FileDirectoryEntryError> {
+        self.insert_directory(root_cluster, name, None)
+    }
+
+    /// Add directory into specified root directory with explicit times, returns first cluster of inserted directory
+    pub fn add_directory_with_times(
+        &mut self,
+        root_cluster: u32,
+        name: &str,
+        times: &EntryTimes,
+    ) -> Result<u32, FileDirectoryEntryError> {
+        self.insert_directory(root_cluster, name, Some(times))
+    }
+
+    fn insert_directory(
+        &mut self,
+        root_cluster: u32,
+        name: &str,
+        times: Option<&EntryTimes>,
     ) -> Result<u32, FileDirectoryEntryError> {
         // file name entries
         let name_length: u8 = name
@@ -503,6 +1828,7 @@ impl ClusterHeap {
             return Err(FileDirectoryEntryError::DuplicateName);
         }
         let file_name_entries = FileNameDirectoryEntry::new(&name_utf16)?;
+        self.known_entry_sets.remove(&root_cluster);
 
         let secondary_count = 1 + file_name_entries.len() as u8; // stream extension entry and 1..=17 file name entries
 
@@ -510,7 +1836,7 @@ impl ClusterHeap {
         let cluster_size = self.sectors_per_cluster * self.bytes_per_sector;
         let max_entries_in_cluster = cluster_size / DirectoryEntry::SIZE as u32;
 
-        let mut end_cluster = self.fat.chain(root_cluster).last().unwrap_or(root_cluster);
+        let mut end_cluster = *self.directory_chain(root_cluster).last().unwrap();
         let mut previous_cluster = end_cluster;
         let entries_in_cluster = self
             .heap
@@ -531,9 +1857,7 @@ impl ClusterHeap {
         if entries_to_insert_in_new_cluster > 0 {
             // new entires will not fit into current last cluster, allocate a new one
             previous_cluster = end_cluster;
-            end_cluster = self
-                .allocation_bitmap
-                .allocate_next_cluster()
+            end_cluster = self.allocate_next_cluster()
                 .ok_or(FileDirectoryEntryError::OutOfFreeSpace)?;
             self.heap.insert(
                 end_cluster,
@@ -541,39 +1865,39 @@ impl ClusterHeap {
                     data: ClusterData::DirectoryEntries(DirectoryEntries(Vec::new())),
                 },
             );
-            self.fat.set_cluster(previous_cluster, end_cluster);
-            self.fat.set_cluster(end_cluster, END_OF_CHAIN);
-            self.cluster_lookup.insert(end_cluster, end_cluster);
-            self.increase_parent_directory_size(root_cluster);
+            self.append_directory_cluster(root_cluster, end_cluster);
         }
 
         // stream extension entry
-        let directory_cluster = self
-            .allocation_bitmap
-            .allocate_next_cluster()
+        let directory_cluster = self.allocate_next_cluster()
             .ok_or(FileDirectoryEntryError::OutOfFreeSpace)?;
+        if self.heap.contains_key(&directory_cluster) {
+            self.set_allocated(directory_cluster, 1, false);
+            return Err(FileDirectoryEntryError::ClusterInUse);
+        }
         let mut stream_extension_entry = StreamExtensionDirectoryEntry::default();
         stream_extension_entry.name_length = name_length;
         stream_extension_entry.name_hash = name_hash;
         stream_extension_entry.first_cluster = directory_cluster + 2; // FAT index
         stream_extension_entry.data_length = u64::from(cluster_size); // empty directory is 1 cluster big
         stream_extension_entry.valid_data_length = stream_extension_entry.data_length;
-        self.parent_lookup
-            .insert(directory_cluster, root_cluster);
-        self.cluster_lookup.insert(directory_cluster, directory_cluster);
-        assert!(self
-            .heap
-            .insert(
-                directory_cluster,
-                Cluster {
-                    data: ClusterData::DirectoryEntries(DirectoryEntries(Vec::new())),
-                }
-            )
-            .is_none());
+        self.parent_lookup
+            .insert(directory_cluster, root_cluster);
+        self.cluster_lookup.insert(directory_cluster, directory_cluster);
+        self.directory_lookup.insert(directory_cluster, directory_cluster);
+        self.heap.insert(
+            directory_cluster,
+            Cluster {
+                data: ClusterData::DirectoryEntries(DirectoryEntries(Vec::new())),
+            },
+        );
 
         // file entry
         let mut file_entry = FileDirectoryEntry::new_directory();
         file_entry.secondary_count = secondary_count;
+        if let Some(times) = times {
+            file_entry.set_times(times);
+        }
         file_entry.set_checksum = {
             let mut checksum = entry_checksum(0, bytemuck::bytes_of(&file_entry), true);
             checksum = entry_checksum(checksum, bytemuck::bytes_of(&stream_extension_entry), false);
@@ -618,6 +1942,16 @@ impl ClusterHeap {
             assert_eq!(entries.len(), 0);
         }
 
+        self.mapped_entries.insert(
+            directory_cluster,
+            MappedEntry {
+                dir_cluster: root_cluster,
+                name: name.to_string(),
+                directory: true,
+                data_length: u64::from(cluster_size),
+            },
+        );
+
         Ok(directory_cluster)
     }
 
@@ -646,10 +1980,20 @@ impl ClusterHeap {
         }
         let file_name_entries = FileNameDirectoryEntry::new(&name_utf16)?;
 
-        let mut file = File::open(&path).map_err(FileDirectoryEntryError::IoError)?;
+        let writable = self.mapping_mode == MappingMode::WriteBack;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(&path)
+            .map_err(FileDirectoryEntryError::IoError)?;
+        self.known_entry_sets.remove(&dir_cluster);
         let file_size_bytes = file
             .seek(std::io::SeekFrom::End(0))
             .map_err(FileDirectoryEntryError::IoError)?;
+        let times = file
+            .metadata()
+            .and_then(|metadata| EntryTimes::from_metadata(&metadata))
+            .map_err(FileDirectoryEntryError::IoError)?;
 
         let secondary_count = 1 + file_name_entries.len() as u8; // stream extension entry and 1..=17 file name entries
 
@@ -657,7 +2001,7 @@ impl ClusterHeap {
         let cluster_size = self.sectors_per_cluster * self.bytes_per_sector;
         let max_entries_in_cluster = cluster_size / DirectoryEntry::SIZE as u32;
 
-        let mut end_dir_cluster = self.fat.chain(dir_cluster).last().unwrap_or(dir_cluster);
+        let mut end_dir_cluster = *self.directory_chain(dir_cluster).last().unwrap();
         let mut previous_dir_cluster = end_dir_cluster;
         let entries_in_cluster = self
             .heap
@@ -678,9 +2022,7 @@ impl ClusterHeap {
         if entries_to_insert_in_new_cluster > 0 {
             // new entires will not fit into current last cluster, allocate a new one
             previous_dir_cluster = end_dir_cluster;
-            end_dir_cluster = self
-                .allocation_bitmap
-                .allocate_next_cluster()
+            end_dir_cluster = self.allocate_next_cluster()
                 .ok_or(FileDirectoryEntryError::OutOfFreeSpace)?;
             self.heap.insert(
                 end_dir_cluster,
@@ -688,16 +2030,18 @@ impl ClusterHeap {
                     data: ClusterData::DirectoryEntries(DirectoryEntries(Vec::new())),
                 },
             );
-            self.fat.set_cluster(previous_dir_cluster, end_dir_cluster);
-            self.fat.set_cluster(end_dir_cluster, END_OF_CHAIN);
-            self.cluster_lookup.insert(end_dir_cluster, end_dir_cluster);
-            self.increase_parent_directory_size(dir_cluster);
+            self.append_directory_cluster(dir_cluster, end_dir_cluster);
         }
 
-        // stream extension entry
-        let file_cluster = self
-            .allocation_bitmap
-            .allocate_next_cluster()
+        // stream extension entry, the file is mapped into a single run of clusters
+        let file_size_clusters = if file_size_bytes > 1 {
+            unsigned_rounded_up_div(file_size_bytes, u64::from(cluster_size))
+        } else {
+            1
+        };
+        let file_cluster = u32::try_from(file_size_clusters)
+            .ok()
+            .and_then(|file_size_clusters| self.allocate_clusters(file_size_clusters))
             .ok_or(FileDirectoryEntryError::OutOfFreeSpace)?;
         let mut stream_extension_entry = StreamExtensionDirectoryEntry::default();
         stream_extension_entry.name_length = name_length;
@@ -711,6 +2055,9 @@ impl ClusterHeap {
         // file entry
         let mut file_entry = FileDirectoryEntry::new_file();
         file_entry.secondary_count = secondary_count;
+        file_entry.file_attributes = FileAttributes::new_with_raw_value(0)
+            .with_read_only(self.mapping_mode == MappingMode::ReadOnly);
+        file_entry.set_times(&times);
         file_entry.set_checksum = {
             let mut checksum = entry_checksum(0, bytemuck::bytes_of(&file_entry), true);
             checksum = entry_checksum(checksum, bytemuck::bytes_of(&stream_extension_entry), false);
@@ -720,7 +2067,6 @@ impl ClusterHeap {
 
             checksum
         };
-        file_entry.file_attributes = FileAttributes::new_with_raw_value(0).with_read_only(true);
 
         // insert entries into cluster(s)
         let mut entries = vec![
@@ -756,27 +2102,30 @@ impl ClusterHeap {
             assert_eq!(entries.len(), 0);
         }
 
-        // allocate space for the file
-        let file_size_clusters = if file_size_bytes > 1 {
-            unsigned_rounded_up_div(file_size_bytes, u64::from(cluster_size))
-        } else {
-            1
-        };
         for i in 1..file_size_clusters as u32 {
             self.cluster_lookup.insert(file_cluster + i, file_cluster);
-            assert_eq!(
-                file_cluster + i,
-                self.allocation_bitmap
-                    .allocate_next_cluster()
-                    .ok_or(FileDirectoryEntryError::OutOfFreeSpace)?
-            );
         }
 
         // insert file into heap
         self.heap.insert(
             file_cluster,
             Cluster {
-                data: ClusterData::FileMappedData(FileMappedData { file }),
+                data: ClusterData::FileMappedData(FileMappedData::new(
+                    file,
+                    0,
+                    self.mapping_mode,
+                    file_size_bytes,
+                )),
+            },
+        );
+
+        self.mapped_entries.insert(
+            file_cluster,
+            MappedEntry {
+                dir_cluster,
+                name: name.to_string(),
+                directory: false,
+                data_length: file_size_bytes,
             },
         );
 
@@ -790,25 +2139,716 @@ impl ClusterHeap {
     {
         let path = path.as_ref();
 
-        let name = path
-            .file_name()
-            .ok_or(FileDirectoryEntryError::EmptyName)?
-            .to_string_lossy();
+        let name = path
+            .file_name()
+            .ok_or(FileDirectoryEntryError::EmptyName)?
+            .to_string_lossy();
+
+        self.map_file_with_name(dir_cluster, path, &name)
+    }
+
+    /// Map contents of a host directory recursively into specified directory
+    pub fn map_directory<P>(
+        &mut self,
+        dir_cluster: u32,
+        host_path: P,
+        options: &MapDirectoryOptions,
+    ) -> Result<MapDirectoryReport, FileDirectoryEntryError>
+    where
+        P: AsRef<Path>,
+    {
+        let mut report = MapDirectoryReport::default();
+        let mut visited = HashSet::new();
+        self.map_directory_contents(
+            dir_cluster,
+            host_path.as_ref(),
+            Path::new(""),
+            options,
+            &mut report,
+            &mut visited,
+        )?;
+        Ok(report)
+    }
+
+    fn map_directory_contents(
+        &mut self,
+        dir_cluster: u32,
+        host_dir: &Path,
+        relative_dir: &Path,
+        options: &MapDirectoryOptions,
+        report: &mut MapDirectoryReport,
+        visited: &mut HashSet<PathBuf>,
+    ) -> Result<(), FileDirectoryEntryError> {
+        // followed links could point back up the tree
+        let canonical_path = fs::canonicalize(host_dir).map_err(FileDirectoryEntryError::IoError)?;
+        if !visited.insert(canonical_path) {
+            return Ok(());
+        }
+        report.directories.push((host_dir.to_path_buf(), dir_cluster));
+
+        let mut dir_entries = fs::read_dir(host_dir)
+            .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+            .map_err(FileDirectoryEntryError::IoError)?;
+        dir_entries.sort_by_key(|entry| entry.file_name());
+
+        let mut upcased_names = HashSet::new();
+
+        for dir_entry in dir_entries {
+            let host_path = dir_entry.path();
+            let relative_path = relative_dir.join(dir_entry.file_name());
+            let file_type = dir_entry.file_type().map_err(FileDirectoryEntryError::IoError)?;
+
+            let file_type = if file_type.is_symlink() {
+                match options.symlinks {
+                    SymlinkPolicy::Follow => match fs::metadata(&host_path) {
+                        Ok(metadata) => metadata.file_type(),
+                        Err(_) => {
+                            report.skipped.push((host_path, SkipReason::Symlink));
+                            continue;
+                        }
+                    },
+                    SymlinkPolicy::Skip => {
+                        report.skipped.push((host_path, SkipReason::Symlink));
+                        continue;
+                    }
+                    SymlinkPolicy::Error => return Err(FileDirectoryEntryError::Symlink),
+                }
+            } else {
+                file_type
+            };
+
+            let directory = file_type.is_dir();
+            if !options.is_included(&relative_path, directory) {
+                continue;
+            }
+            if !directory && !file_type.is_file() {
+                report.skipped.push((host_path, SkipReason::Unsupported));
+                continue;
+            }
+
+            let name = match dir_entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => {
+                    report.skipped.push((host_path, SkipReason::IllegalName));
+                    continue;
+                }
+            };
+
+            let name_utf16: Vec<u16> = name.encode_utf16().collect();
+            if !upcased_names.insert(upcased_name(&name_utf16)) {
+                report.skipped.push((host_path, SkipReason::NameCollision));
+                continue;
+            }
+
+            let result = if directory {
+                fs::metadata(&host_path)
+                    .and_then(|metadata| EntryTimes::from_metadata(&metadata))
+                    .map_err(FileDirectoryEntryError::IoError)
+                    .and_then(|times| self.add_directory_with_times(dir_cluster, &name, &times))
+            } else {
+                self.map_file_with_name(dir_cluster, &host_path, &name)
+            };
+
+            match result {
+                Ok(first_cluster) if directory => self.map_directory_contents(
+                    first_cluster,
+                    &host_path,
+                    &relative_path,
+                    options,
+                    report,
+                    visited,
+                )?,
+                Ok(first_cluster) => report.files.push((host_path, first_cluster)),
+                Err(
+                    FileDirectoryEntryError::EmptyName
+                    | FileDirectoryEntryError::NameTooLong
+                    | FileDirectoryEntryError::IllegalCharactersInName,
+                ) => report.skipped.push((host_path, SkipReason::IllegalName)),
+                Err(FileDirectoryEntryError::DuplicateName) => {
+                    report.skipped.push((host_path, SkipReason::NameCollision))
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove file or empty directory from specified directory, returns the released clusters
+    pub fn remove(&mut self, dir_cluster: u32, name: &str) -> Result<Vec<u32>, FileDirectoryEntryError> {
+        let (entry_set, positions) = self
+            .find_entry_set(dir_cluster, name)
+            .ok_or(FileDirectoryEntryError::NotFound)?;
+        let non_empty_directory = entry_set.file.file_attributes.directory()
+            && entry_set
+                .first_cluster()
+                .is_some_and(|first_cluster| !self.entry_sets(first_cluster).is_empty());
+        if non_empty_directory {
+            return Err(FileDirectoryEntryError::DirectoryNotEmpty);
+        }
+
+        Ok(self.remove_entry_set(dir_cluster, &entry_set, &positions))
+    }
+
+    /// Remove directory from specified directory along with everything in it, returns the released clusters
+    pub fn remove_dir_all(&mut self, dir_cluster: u32, name: &str) -> Result<Vec<u32>, FileDirectoryEntryError> {
+        let (entry_set, positions) = self
+            .find_entry_set(dir_cluster, name)
+            .ok_or(FileDirectoryEntryError::NotFound)?;
+        if !entry_set.file.file_attributes.directory() {
+            return Err(FileDirectoryEntryError::NotADirectory);
+        }
+
+        Ok(self.remove_entry_set(dir_cluster, &entry_set, &positions))
+    }
+
+    /// Mark the entries as not in use and release the clusters of the entry set
+    fn remove_entry_set(&mut self, dir_cluster: u32, entry_set: &EntrySet, positions: &[(u32, usize)]) -> Vec<u32> {
+        // not a change made by the guest
+        self.known_entry_sets.remove(&dir_cluster);
+        self.mark_not_in_use(positions);
+
+        let mut released = Vec::new();
+        if let Some(first_cluster) = entry_set.first_cluster() {
+            let mut removed_runs = HashSet::new();
+            self.release_clusters(first_cluster, entry_set, &mut released, &mut removed_runs);
+
+            // clusters of a mapped file the guest truncated are still looked up
+            self.cluster_lookup.retain(|_, run_cluster| !removed_runs.contains(run_cluster));
+        }
+
+        released
+    }
+
+    /// Clear `InUse` bit of the entries at the positions
+    fn mark_not_in_use(&mut self, positions: &[(u32, usize)]) {
+        for &(cluster_index, entry_index) in positions {
+            let entries = self
+                .heap
+                .get_mut(&cluster_index)
+                .and_then(|cluster| cluster.as_entries_mut())
+                .unwrap();
+            let mut bytes: [u8; DirectoryEntry::SIZE] = entries[entry_index].as_bytes().try_into().unwrap();
+            bytes[0] &= !0x80; // InUse
+            entries[entry_index] = DirectoryEntry::Unknown(bytes);
+        }
+    }
+
+    /// Rename file or directory, moving it into `new_dir_cluster`, its clusters are kept as they are
+    pub fn rename(
+        &mut self,
+        dir_cluster: u32,
+        old_name: &str,
+        new_dir_cluster: u32,
+        new_name: &str,
+    ) -> Result<(), FileDirectoryEntryError> {
+        let (entry_set, positions) = self
+            .find_entry_set(dir_cluster, old_name)
+            .ok_or(FileDirectoryEntryError::NotFound)?;
+
+        let name_utf16: Vec<u16> = new_name.encode_utf16().collect();
+        let name_length: u8 = name_utf16
+            .len()
+            .try_into()
+            .map_err(|_| FileDirectoryEntryError::NameTooLong)?;
+        if name_length == 0 {
+            return Err(FileDirectoryEntryError::EmptyName);
+        }
+        let file_name_entries = FileNameDirectoryEntry::new(&name_utf16)?;
+
+        if !self.is_directory_cluster(new_dir_cluster) {
+            return Err(FileDirectoryEntryError::NotADirectory);
+        }
+        // changing the case of the name is not a collision with itself
+        if let Some((_, existing_positions)) = self.find_entry_set(new_dir_cluster, new_name) {
+            if new_dir_cluster != dir_cluster || existing_positions != positions {
+                return Err(FileDirectoryEntryError::DuplicateName);
+            }
+        }
+        if let Some(first_cluster) = entry_set.first_cluster().filter(|_| entry_set.file.file_attributes.directory()) {
+            if self.is_within(new_dir_cluster, first_cluster) {
+                return Err(FileDirectoryEntryError::MoveIntoItself);
+            }
+        }
+
+        // file entry and stream extension are kept, except for the name
+        let mut file_entry = entry_set.file;
+        file_entry.secondary_count = 1 + file_name_entries.len() as u8;
+        let mut stream_extension_entry = entry_set.stream_extension;
+        stream_extension_entry.name_length = name_length;
+        stream_extension_entry.name_hash = name_hash(&upcased_name(&name_utf16));
+        file_entry.set_checksum = {
+            let mut checksum = entry_checksum(0, bytemuck::bytes_of(&file_entry), true);
+            checksum = entry_checksum(checksum, bytemuck::bytes_of(&stream_extension_entry), false);
+            for file_name_entry in &file_name_entries {
+                checksum = entry_checksum(checksum, bytemuck::bytes_of(file_name_entry), false);
+            }
+
+            checksum
+        };
+
+        let mut entries = vec![
+            DirectoryEntry::File(file_entry),
+            DirectoryEntry::StreamExtension(stream_extension_entry),
+        ];
+        entries.extend(file_name_entries.into_iter().map(DirectoryEntry::FileName));
+
+        // not a change made by the guest
+        self.known_entry_sets.remove(&dir_cluster);
+        self.known_entry_sets.remove(&new_dir_cluster);
+
+        if new_dir_cluster == dir_cluster && entries.len() == positions.len() {
+            // same number of entries, rewrite the set in place
+            for ((cluster_index, entry_index), entry) in positions.iter().cloned().zip(entries) {
+                let cluster_entries = self
+                    .heap
+                    .get_mut(&cluster_index)
+                    .and_then(|cluster| cluster.as_entries_mut())
+                    .unwrap();
+                cluster_entries[entry_index] = entry;
+            }
+        } else {
+            // new set is appended first, nothing changes if the directory can't grow
+            self.append_entries(new_dir_cluster, entries)?;
+            self.mark_not_in_use(&positions);
+        }
+
+        if let Some(first_cluster) = entry_set.first_cluster() {
+            if new_dir_cluster != dir_cluster {
+                self.parent_lookup.insert(first_cluster, new_dir_cluster);
+            }
+            if let Some(mapped_entry) = self.mapped_entries.get_mut(&first_cluster) {
+                mapped_entry.dir_cluster = new_dir_cluster;
+                mapped_entry.name = new_name.to_string();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether `dir_cluster` is `ancestor_cluster` or one of its subdirectories
+    fn is_within(&self, dir_cluster: u32, ancestor_cluster: u32) -> bool {
+        let mut cluster = dir_cluster;
+        // parent lookup could contain a cycle written by the guest
+        for _ in 0..=self.parent_lookup.len() {
+            if cluster == ancestor_cluster {
+                return true;
+            }
+            match self.parent_lookup.get(&cluster) {
+                Some(&parent_cluster) => cluster = parent_cluster,
+                None => return false,
+            }
+        }
+
+        false
+    }
+
+    /// Append entries at the end of the directory, growing it by a cluster if they don't fit
+    fn append_entries(&mut self, dir_cluster: u32, mut entries: Vec<DirectoryEntry>) -> Result<(), FileDirectoryEntryError> {
+        let cluster_size = self.sectors_per_cluster * self.bytes_per_sector;
+        let max_entries_in_cluster = (cluster_size / DirectoryEntry::SIZE as u32) as usize;
+
+        let last_cluster = *self.directory_chain(dir_cluster).last().unwrap();
+        let entries_in_cluster = self
+            .heap
+            .get(&last_cluster)
+            .and_then(|cluster| cluster.as_entries())
+            .map_or(0, |entries| entries.len());
+        let entries_in_this_cluster = max_entries_in_cluster
+            .saturating_sub(entries_in_cluster)
+            .min(entries.len());
+
+        if entries_in_this_cluster < entries.len() {
+            let new_cluster = self
+                .allocate_next_cluster()
+                .ok_or(FileDirectoryEntryError::OutOfFreeSpace)?;
+            let entries_in_new_cluster = entries.split_off(entries_in_this_cluster);
+            self.heap.insert(
+                new_cluster,
+                Cluster {
+                    data: ClusterData::DirectoryEntries(DirectoryEntries(entries_in_new_cluster)),
+                },
+            );
+            self.append_directory_cluster(dir_cluster, new_cluster);
+        }
+
+        self.heap
+            .entry(last_cluster)
+            .or_insert(Cluster {
+                data: ClusterData::DirectoryEntries(DirectoryEntries(Vec::new())),
+            })
+            .as_entries_mut()
+            .unwrap()
+            .extend(entries);
+
+        Ok(())
+    }
+
+    /// Free clusters of the entry set in the allocation bitmaps and the FAT,
+    /// everything in a directory is released before the directory itself
+    fn release_clusters(
+        &mut self,
+        first_cluster: u32,
+        entry_set: &EntrySet,
+        released: &mut Vec<u32>,
+        removed_runs: &mut HashSet<u32>,
+    ) {
+        // guest could link directories into a cycle
+        if !removed_runs.insert(first_cluster) {
+            return;
+        }
+
+        if entry_set.file.file_attributes.directory() {
+            for child in self.entry_sets(first_cluster) {
+                if let Some(child_cluster) = child.first_cluster() {
+                    self.release_clusters(child_cluster, &child, released, removed_runs);
+                }
+            }
+
+            self.known_entry_sets.remove(&first_cluster);
+        }
+        self.parent_lookup.remove(&first_cluster);
+        self.mapped_entries.remove(&first_cluster);
+
+        let cluster_count = self.allocation_bitmap.cluster_count();
+        let clusters = self.allocation_chain(first_cluster, &entry_set.stream_extension);
+        for cluster_index in clusters.into_iter().filter(|&cluster_index| cluster_index < cluster_count) {
+            if let Some(run_cluster) = self.cluster_lookup.remove(&cluster_index) {
+                self.heap.remove(&run_cluster);
+                removed_runs.insert(run_cluster);
+            }
+            self.directory_lookup.remove(&cluster_index);
+            self.free_cluster(cluster_index);
+            released.push(cluster_index);
+        }
+    }
+
+    /// Free the cluster in both allocation bitmaps and the FAT, and forget what was written to it
+    fn free_cluster(&mut self, cluster_index: u32) {
+        self.allocation_bitmap.set_cluster(cluster_index, false);
+        if let Some(second_allocation_bitmap) = &mut self.second_allocation_bitmap {
+            second_allocation_bitmap.set_cluster(cluster_index, false);
+        }
+        self.fat.free_cluster(cluster_index);
+
+        self.dirty_clusters.remove(&cluster_index);
+        if let Some(delta_store) = &mut self.delta_store {
+            // stale data would show up in whatever reuses the cluster
+            let _ = delta_store.remove_cluster(cluster_index);
+        }
+    }
+
+    /// Re-read the length and times of the mapped host file, clusters are reallocated to fit its new length.
+    /// Returns the released clusters.
+    pub fn refresh_file(&mut self, dir_cluster: u32, name: &str) -> Result<Vec<u32>, FileDirectoryEntryError> {
+        let (entry_set, positions) = self
+            .find_entry_set(dir_cluster, name)
+            .ok_or(FileDirectoryEntryError::NotFound)?;
+        let (file, mode) = match entry_set
+            .first_cluster()
+            .and_then(|first_cluster| self.heap.get(&first_cluster))
+            .map(|cluster| &cluster.data)
+        {
+            Some(ClusterData::FileMappedData(data)) if !entry_set.file.file_attributes.directory() => (
+                data.file.try_clone().map_err(FileDirectoryEntryError::IoError)?,
+                data.mode,
+            ),
+            _ => return Err(FileDirectoryEntryError::NotMappedFile),
+        };
+
+        let (old_clusters, new_clusters) = self.remap_file(dir_cluster, &entry_set, &positions, file, mode, true)?;
+        let new_clusters: HashSet<u32> = new_clusters.into_iter().collect();
+        Ok(old_clusters
+            .into_iter()
+            .filter(|cluster_index| !new_clusters.contains(cluster_index))
+            .collect())
+    }
+
+    /// Map another host file in place of the mapped file with the same mode, what the guest wrote to the file is dropped.
+    /// Returns the clusters the file had.
+    pub fn replace_file<P>(&mut self, dir_cluster: u32, name: &str, path: P) -> Result<Vec<u32>, FileDirectoryEntryError>
+    where
+        P: AsRef<Path>,
+    {
+        let (entry_set, positions) = self
+            .find_entry_set(dir_cluster, name)
+            .ok_or(FileDirectoryEntryError::NotFound)?;
+        let mode = match entry_set
+            .first_cluster()
+            .and_then(|first_cluster| self.heap.get(&first_cluster))
+            .map(|cluster| &cluster.data)
+        {
+            Some(ClusterData::FileMappedData(data)) if !entry_set.file.file_attributes.directory() => data.mode,
+            _ => return Err(FileDirectoryEntryError::NotMappedFile),
+        };
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(mode == MappingMode::WriteBack)
+            .open(&path)
+            .map_err(FileDirectoryEntryError::IoError)?;
+
+        let (old_clusters, _) = self.remap_file(dir_cluster, &entry_set, &positions, file, mode, false)?;
+        for cluster_index in old_clusters.iter().cloned() {
+            self.dirty_clusters.remove(&cluster_index);
+            if let Some(delta_store) = &mut self.delta_store {
+                let _ = delta_store.remove_cluster(cluster_index);
+            }
+        }
+
+        Ok(old_clusters)
+    }
+
+    /// Reallocate clusters of the mapped file to fit the length of `file`, and map them to it.
+    /// File grows in place if the following clusters are free, otherwise it is relocated to a contiguous run,
+    /// clusters are chained in the FAT if there is no such run. Returns the clusters the file had and has now.
+    /// With `keep_guest_writes`, sectors the guest wrote past the valid data length stay pending in the new runs,
+    /// and clusters it wrote with copy-on-write keep their data at the same offset in the file.
+    fn remap_file(
+        &mut self,
+        dir_cluster: u32,
+        entry_set: &EntrySet,
+        positions: &[(u32, usize)],
+        mut file: File,
+        mode: MappingMode,
+        keep_guest_writes: bool,
+    ) -> Result<(Vec<u32>, Vec<u32>), FileDirectoryEntryError> {
+        let file_size_bytes = file
+            .seek(std::io::SeekFrom::End(0))
+            .map_err(FileDirectoryEntryError::IoError)?;
+        let times = file
+            .metadata()
+            .and_then(|metadata| EntryTimes::from_metadata(&metadata))
+            .map_err(FileDirectoryEntryError::IoError)?;
+
+        let cluster_size = u64::from(self.sectors_per_cluster * self.bytes_per_sector);
+        let file_size_clusters = if file_size_bytes > 1 {
+            unsigned_rounded_up_div(file_size_bytes, cluster_size)
+        } else {
+            1
+        };
+        let file_size_clusters =
+            usize::try_from(file_size_clusters).map_err(|_| FileDirectoryEntryError::OutOfFreeSpace)?;
+
+        let old_first_cluster = entry_set.first_cluster().unwrap();
+        let cluster_count = self.allocation_bitmap.cluster_count();
+        let old_clusters: Vec<u32> = self
+            .allocation_chain(old_first_cluster, &entry_set.stream_extension)
+            .into_iter()
+            .filter(|&cluster_index| cluster_index < cluster_count)
+            .collect();
+        let clusters = self.reallocate_clusters(&old_clusters, file_size_clusters)?;
+        let contiguous = clusters.iter().tuple_windows().all(|(a, b)| a + 1 == *b);
+        let kept: HashSet<u32> = clusters.iter().cloned().collect();
+
+        // copy-on-write data and dirty state follow the offset in the file, not the cluster index
+        let mut moved_clusters = Vec::new();
+        for (index, cluster_index) in old_clusters.iter().cloned().enumerate().filter(|_| keep_guest_writes) {
+            let dirty = self.dirty_clusters.remove(&cluster_index);
+            let mut data = None;
+            if let Some(delta_store) = self.delta_store.as_mut().filter(|store| store.contains(cluster_index)) {
+                let mut buffer = vec![0; cluster_size as usize];
+                if let Ok(true) = delta_store.read_sector(cluster_index, 0, &mut buffer) {
+                    data = Some(buffer);
+                }
+                let _ = delta_store.remove_cluster(cluster_index);
+            }
+            moved_clusters.push((index, dirty, data));
+        }
+
+        // mapping is rebuilt from scratch
+        let mut pending_sectors = BTreeMap::new();
+        for cluster_index in old_clusters.iter().cloned() {
+            if let Some(run_cluster) = self.cluster_lookup.remove(&cluster_index) {
+                let run = self.heap.remove(&run_cluster).map(|cluster| cluster.data);
+                if let Some(ClusterData::FileMappedData(run)) = run {
+                    pending_sectors.extend(run.pending_sectors);
+                }
+            }
+            if kept.contains(&cluster_index) {
+                self.fat.free_cluster(cluster_index);
+            } else {
+                self.free_cluster(cluster_index);
+            }
+        }
+
+        // each contiguous run maps the file from the offset of its first cluster
+        let mut run_cluster = clusters[0];
+        for (index, cluster_index) in clusters.iter().cloned().enumerate() {
+            if index == 0 || clusters[index - 1] + 1 != cluster_index {
+                run_cluster = cluster_index;
+                let run_file = file.try_clone().map_err(FileDirectoryEntryError::IoError)?;
+                self.heap.insert(
+                    run_cluster,
+                    Cluster {
+                        data: ClusterData::FileMappedData(FileMappedData::new(
+                            run_file,
+                            index as u64 * cluster_size,
+                            mode,
+                            file_size_bytes,
+                        )),
+                    },
+                );
+            }
+            self.cluster_lookup.insert(cluster_index, run_cluster);
+        }
+
+        // moved clusters past the new end of the file are dropped
+        for (index, dirty, data) in moved_clusters {
+            let Some(cluster_index) = clusters.get(index).cloned() else {
+                break;
+            };
+            if dirty {
+                self.dirty_clusters.insert(cluster_index);
+            }
+            if let (Some(delta_store), Some(data)) = (&mut self.delta_store, data) {
+                let _ = delta_store.insert_cluster(cluster_index, &data);
+            }
+        }
+
+        // sectors past the clusters the file has now are dropped
+        for (offset, sector) in pending_sectors.into_iter().filter(|_| keep_guest_writes) {
+            let Some(cluster_index) = clusters.get((offset / cluster_size) as usize) else {
+                break;
+            };
+            let run_cluster = self.cluster_lookup[cluster_index];
+            let run = self.heap.get_mut(&run_cluster).map(|cluster| &mut cluster.data);
+            if let Some(ClusterData::FileMappedData(run)) = run {
+                run.pending_sectors.insert(offset, sector);
+            }
+        }
+
+        if !contiguous {
+            for (cluster_index, next_cluster) in clusters.iter().cloned().tuple_windows() {
+                self.fat.set_cluster(cluster_index, next_cluster);
+            }
+            self.fat.set_cluster(*clusters.last().unwrap(), END_OF_CHAIN);
+        }
+
+        let first_cluster = clusters[0];
+        if first_cluster != old_first_cluster {
+            if let Some(parent_cluster) = self.parent_lookup.remove(&old_first_cluster) {
+                self.parent_lookup.insert(first_cluster, parent_cluster);
+            }
+            if let Some(mapped_entry) = self.mapped_entries.remove(&old_first_cluster) {
+                self.mapped_entries.insert(first_cluster, mapped_entry);
+            }
+        }
+        if let Some(mapped_entry) = self.mapped_entries.get_mut(&first_cluster) {
+            mapped_entry.data_length = file_size_bytes;
+        }
+
+        let mut file_entry = entry_set.file;
+        file_entry.set_times(&times);
+        let mut stream_extension_entry = entry_set.stream_extension;
+        stream_extension_entry.first_cluster = first_cluster + 2; // FAT index
+        stream_extension_entry.data_length = file_size_bytes;
+        stream_extension_entry.valid_data_length = file_size_bytes;
+        stream_extension_entry.general_secondary_flags =
+            stream_extension_entry.general_secondary_flags.with_no_fat_chain(contiguous);
+
+        // not a change made by the guest
+        self.known_entry_sets.remove(&dir_cluster);
+        self.update_entry_set(positions, file_entry, stream_extension_entry);
+
+        Ok((old_clusters, clusters))
+    }
+
+    /// Clusters to hold `count` clusters of data, keeping as many of `clusters` as possible in place
+    fn reallocate_clusters(&mut self, clusters: &[u32], count: usize) -> Result<Vec<u32>, FileDirectoryEntryError> {
+        if count <= clusters.len() {
+            return Ok(clusters[..count].to_vec());
+        }
+
+        let extra = (count - clusters.len()) as u32;
+        let contiguous = clusters.iter().tuple_windows().all(|(a, b)| a + 1 == *b);
+        let end_cluster = clusters.last().map_or(0, |last| last + 1);
+        let cluster_count = self.allocation_bitmap.cluster_count();
+        let active_allocation_bitmap = self.active_allocation_bitmap();
+        let can_grow_in_place = contiguous
+            && end_cluster.checked_add(extra).is_some_and(|end| end <= cluster_count)
+            && (end_cluster..end_cluster + extra).all(|cluster_index| {
+                !active_allocation_bitmap.is_allocated(cluster_index) && !self.cluster_lookup.contains_key(&cluster_index)
+            });
+        if can_grow_in_place {
+            self.set_allocated(end_cluster, extra, true);
+            self.release_entries(end_cluster, extra);
+            return Ok(clusters.iter().cloned().chain(end_cluster..end_cluster + extra).collect());
+        }
+
+        // contiguous run may overlap the clusters the file had
+        for cluster_index in clusters.iter().cloned() {
+            self.set_allocated(cluster_index, 1, false);
+        }
+        if let Some(first_cluster) = u32::try_from(count).ok().and_then(|count| self.allocate_clusters(count)) {
+            return Ok((first_cluster..first_cluster + count as u32).collect());
+        }
+        for cluster_index in clusters.iter().cloned() {
+            self.set_allocated(cluster_index, 1, true);
+        }
+
+        let mut chain = clusters.to_vec();
+        while chain.len() < count {
+            match self.allocate_next_cluster() {
+                Some(cluster_index) => chain.push(cluster_index),
+                None => {
+                    for cluster_index in chain.drain(clusters.len()..) {
+                        self.set_allocated(cluster_index, 1, false);
+                    }
+                    return Err(FileDirectoryEntryError::OutOfFreeSpace);
+                }
+            }
+        }
+
+        Ok(chain)
+    }
 
-        self.map_file_with_name(dir_cluster, path, &name)
+    /// Allocate or free clusters in both allocation bitmaps
+    fn set_allocated(&mut self, cluster_index: u32, count: u32, allocated: bool) {
+        self.allocation_bitmap.set_clusters(cluster_index, count, allocated);
+        if let Some(second_allocation_bitmap) = &mut self.second_allocation_bitmap {
+            second_allocation_bitmap.set_clusters(cluster_index, count, allocated);
+        }
+    }
+
+    /// Write file and stream extension entries of the entry set at the positions, along with a new checksum
+    fn update_entry_set(
+        &mut self,
+        positions: &[(u32, usize)],
+        mut file_entry: FileDirectoryEntry,
+        stream_extension_entry: StreamExtensionDirectoryEntry,
+    ) {
+        let mut checksum = entry_checksum(0, bytemuck::bytes_of(&file_entry), true);
+        checksum = entry_checksum(checksum, bytemuck::bytes_of(&stream_extension_entry), false);
+        for &(cluster_index, entry_index) in &positions[2..] {
+            let entry = &self.heap[&cluster_index].as_entries().unwrap()[entry_index];
+            checksum = entry_checksum(checksum, entry.as_bytes(), false);
+        }
+        file_entry.set_checksum = checksum;
+
+        for (&(cluster_index, entry_index), entry) in positions.iter().zip([
+            DirectoryEntry::File(file_entry),
+            DirectoryEntry::StreamExtension(stream_extension_entry),
+        ]) {
+            let entries = self
+                .heap
+                .get_mut(&cluster_index)
+                .and_then(|cluster| cluster.as_entries_mut())
+                .unwrap();
+            entries[entry_index] = entry;
+        }
     }
 }
 
 struct DirectoryEntries(Vec<DirectoryEntry>);
 
 impl DirectoryEntries {
-    fn read_sector(&self, sector: u32, buffer: &mut [u8]) {
+    fn read_sector(&self, sector: u64, buffer: &mut [u8]) {
         let bytes_per_sector = buffer.len();
         let bytes_to_skip = sector as usize * bytes_per_sector;
 
         let sector_data = self.0.iter()
-            .map(|item| item.as_bytes().iter())
-            .flatten()
+            .flat_map(|item| item.as_bytes().iter())
             .skip(bytes_to_skip)
             .take(bytes_per_sector)
             .cloned();
@@ -817,6 +2857,29 @@ impl DirectoryEntries {
             *buffer_byte = sector_byte;
         }
     }
+
+    fn write_sector(&mut self, sector: u64, buffer: &[u8]) {
+        let entries_per_sector = buffer.len() / DirectoryEntry::SIZE;
+        let entries_to_skip = sector as usize * entries_per_sector;
+
+        let extend_by = (entries_to_skip + entries_per_sector).saturating_sub(self.0.len());
+        if extend_by > 0 {
+            self.0.extend((0..extend_by).map(|_| DirectoryEntry::Unknown([0; 32])));
+        }
+
+        for (out, bytes) in self.0[entries_to_skip..]
+            .iter_mut()
+            .zip(buffer.chunks_exact(DirectoryEntry::SIZE))
+        {
+            *out = DirectoryEntry::new_from_bytes(bytes)
+                .unwrap_or_else(|| DirectoryEntry::Unknown(bytes.try_into().unwrap()));
+        }
+
+        // end of directory markers, new entries are inserted right after the last one in use
+        while self.0.last() == Some(&DirectoryEntry::Unknown([0; 32])) {
+            self.0.pop();
+        }
+    }
 }
 
 impl Debug for DirectoryEntries {
@@ -831,19 +2894,132 @@ impl Debug for DirectoryEntries {
 #[derive(Debug)]
 struct FileMappedData {
     file: File,
+
+    /// Offset of the first cluster of this run of clusters within the file
+    offset: u64,
+
+    /// Mode the file was mapped with
+    mode: MappingMode,
+
+    /// Valid data length of the file, `None` unless the file is mapped with write-back
+    writable_length: Option<u64>,
+
+    /// Sectors written past the valid data length, in case the guest extends the file afterwards
+    pending_sectors: BTreeMap<u64, Box<[u8]>>,
 }
 
 impl FileMappedData {
+    /// `length` of the file is only kept for write-back
+    fn new(file: File, offset: u64, mode: MappingMode, length: u64) -> Self {
+        Self {
+            file,
+            offset,
+            mode,
+            writable_length: (mode == MappingMode::WriteBack).then_some(length),
+            pending_sectors: BTreeMap::new(),
+        }
+    }
+
+    /// `offset` is relative to the first cluster of the run
     fn read_sector(&mut self, offset: u64, buffer: &mut [u8]) {
+        let offset = self.offset + offset;
+        if let Some(sector) = self.pending_sectors.get(&offset) {
+            buffer.copy_from_slice(sector);
+            return;
+        }
+
         self.file.seek(std::io::SeekFrom::Start(offset)).unwrap();
         let _ = self.file.read(buffer).unwrap();
     }
+
+    /// `offset` is relative to the first cluster of the run, returns `false` if the write could not be applied
+    fn write_sector(&mut self, offset: u64, buffer: &[u8]) -> bool {
+        let writable_length = match self.writable_length {
+            Some(writable_length) => writable_length,
+            None => return false,
+        };
+
+        let offset = self.offset + offset;
+        let bytes_in_file = usize::min(
+            writable_length.saturating_sub(offset).try_into().unwrap_or(usize::MAX),
+            buffer.len(),
+        );
+
+        if bytes_in_file > 0 {
+            let written = self
+                .file
+                .seek(std::io::SeekFrom::Start(offset))
+                .and_then(|_| self.file.write_all(&buffer[..bytes_in_file]));
+            if written.is_err() {
+                return false;
+            }
+        }
+
+        if bytes_in_file < buffer.len() {
+            self.pending_sectors.insert(offset, buffer.into());
+        } else {
+            self.pending_sectors.remove(&offset);
+        }
+
+        true
+    }
+
+    /// Write out the pending sectors which are now within the file
+    fn set_writable_length(&mut self, writable_length: u64) {
+        self.writable_length = Some(writable_length);
+
+        let pending_sectors = std::mem::take(&mut self.pending_sectors);
+        for (offset, sector) in pending_sectors {
+            self.write_sector(offset - self.offset, &sector);
+        }
+    }
+}
+
+/// Cluster allocated and written to by the guest
+struct GuestData(Vec<u8>);
+
+impl GuestData {
+    fn read_sector(&self, sector: u64, buffer: &mut [u8]) {
+        let bytes_per_sector = buffer.len();
+        let bytes_to_skip = sector as usize * bytes_per_sector;
+        let sector_data = self
+            .0
+            .iter()
+            .skip(bytes_to_skip)
+            .take(bytes_per_sector)
+            .cloned();
+
+        for (out, byte) in buffer.iter_mut().zip(sector_data) {
+            *out = byte;
+        }
+    }
+
+    fn write_sector(&mut self, sector: u64, buffer: &[u8]) {
+        let bytes_per_sector = buffer.len();
+        let bytes_to_skip = sector as usize * bytes_per_sector;
+
+        let extend_by = (bytes_to_skip + bytes_per_sector).saturating_sub(self.0.len());
+        if extend_by > 0 {
+            self.0.extend(vec![0; extend_by]);
+        }
+
+        self.0[bytes_to_skip..bytes_to_skip + bytes_per_sector].copy_from_slice(buffer);
+    }
+}
+
+impl Debug for GuestData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GuestData")
+            .field("len", &self.0.len())
+            .finish()
+    }
 }
 
 #[derive(Debug)]
 enum ClusterData {
     DirectoryEntries(DirectoryEntries),
     FileMappedData(FileMappedData),
+    GuestData(GuestData),
 }
 
 #[derive(Debug)]
@@ -855,14 +3031,14 @@ impl Cluster {
     fn as_entries(&self) -> Option<&[DirectoryEntry]> {
         match &self.data {
             ClusterData::DirectoryEntries(entries) => Some(&entries.0),
-            ClusterData::FileMappedData(_) => None,
+            ClusterData::FileMappedData(_) | ClusterData::GuestData(_) => None,
         }
     }
 
     fn as_entries_mut(&mut self) -> Option<&mut Vec<DirectoryEntry>> {
         match &mut self.data {
             ClusterData::DirectoryEntries(entries) => Some(&mut entries.0),
-            ClusterData::FileMappedData(_) => None,
+            ClusterData::FileMappedData(_) | ClusterData::GuestData(_) => None,
         }
     }
 }
@@ -1009,3 +3185,889 @@ fn fragmentation() {
     assert_eq!(first_clusters.next(), Some(12));
     assert_eq!(first_clusters.next(), None);
 }
+
+#[test]
+fn guest_allocation() {
+    const BYTES_PER_SECTOR: usize = 512;
+    let mut heap = ClusterHeap::new(BYTES_PER_SECTOR as _, 8, 512); // clusters 0..=3 are in use
+
+    // guest writes the data first, then marks clusters 4 and 5 as allocated
+    assert!(heap.write_sector_in_cluster(4, 1, &[0xAB; BYTES_PER_SECTOR]));
+    let mut bitmap_sector = [0; BYTES_PER_SECTOR];
+    bitmap_sector[0] = 0b00111111;
+    assert!(heap.write_sector(0, &bitmap_sector));
+
+    let mut lookup_keys: Vec<_> = heap.cluster_lookup.keys().cloned().collect();
+    lookup_keys.sort_unstable();
+    assert_eq!(lookup_keys, [3, 4, 5]);
+
+    let mut buffer = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(4, 1, &mut buffer);
+    assert_eq!(buffer, [0xAB; BYTES_PER_SECTOR]);
+    buffer = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(5, 0, &mut buffer);
+    assert_eq!(buffer, [0; BYTES_PER_SECTOR]);
+
+    // host allocations go after clusters allocated by guest
+    assert_eq!(heap.add_directory(heap.root_directory_cluster(), "dir"), Ok(6));
+
+    // guest frees cluster 4
+    bitmap_sector[0] = 0b01101111;
+    assert!(heap.write_sector(0, &bitmap_sector));
+    assert!(!heap.cluster_lookup.contains_key(&4));
+    assert!(!heap.heap.contains_key(&4));
+
+    buffer = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(4, 1, &mut buffer);
+    assert_eq!(buffer, [0; BYTES_PER_SECTOR]);
+
+    // freed directory is forgotten, data written to the cluster afterwards is not parsed as entries
+    bitmap_sector[0] = 0b00101111;
+    assert!(heap.write_sector(0, &bitmap_sector));
+    assert!(!heap.heap.contains_key(&6));
+    assert!(!heap.directory_lookup.contains_key(&6));
+
+    bitmap_sector[0] = 0b01101111;
+    assert!(heap.write_sector(0, &bitmap_sector));
+    let mut data_sector = [0; BYTES_PER_SECTOR];
+    let entry_set = guest_entry_set("not an entry", 7, true);
+    data_sector[..entry_set.len()].copy_from_slice(&entry_set);
+    assert!(heap.write_sector_in_cluster(6, 0, &data_sector));
+    assert!(heap.take_directory_events().is_empty());
+    assert!(!heap.parent_lookup.contains_key(&7));
+    heap.read_sector_in_cluster(6, 0, &mut buffer);
+    assert_eq!(buffer, data_sector);
+}
+
+#[test]
+fn unallocated_guest_data() {
+    const BYTES_PER_SECTOR: usize = 512;
+    let mut heap = ClusterHeap::new(BYTES_PER_SECTOR as _, 8, 512); // clusters 0..=3 are in use
+    let root_cluster = heap.root_directory_cluster();
+
+    // guest writes the data before marking cluster 4 as allocated, the host allocates around it
+    assert!(heap.write_sector_in_cluster(4, 0, &[0xAB; BYTES_PER_SECTOR]));
+    assert_eq!(heap.add_directory(root_cluster, "dir"), Ok(5));
+    let mut bitmap_sector = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(0, 0, &mut bitmap_sector);
+    assert_eq!(bitmap_sector[0], 0b00101111);
+    bitmap_sector[0] = 0b00111111;
+    assert!(heap.write_sector(0, &bitmap_sector));
+    let mut buffer = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(4, 0, &mut buffer);
+    assert_eq!(buffer, [0xAB; BYTES_PER_SECTOR]);
+
+    // guest extends the directory with cluster 6, then cuts it off again
+    let mut fat_sector = [0u32; BYTES_PER_SECTOR / 4];
+    heap.fat.read_sector_first(0, bytemuck::cast_slice_mut(&mut fat_sector));
+    fat_sector[5 + 2] = 6 + 2;
+    fat_sector[6 + 2] = 0xFFFFFFFF;
+    heap.write_fat_sector(false, 0, bytemuck::cast_slice(&fat_sector));
+    guest_rewrite_entry_set(&mut heap, root_cluster, "dir", &guest_entry_set_with_length("dir", 5, true, 8192, false));
+    assert_eq!(heap.directory_of(6), 5);
+
+    let mut dir_sector = [0; BYTES_PER_SECTOR];
+    let entry_set = guest_entry_set("file", 7, false);
+    dir_sector[..entry_set.len()].copy_from_slice(&entry_set);
+    assert!(heap.write_sector_in_cluster(6, 0, &dir_sector));
+    assert_eq!(heap.take_directory_events().len(), 1);
+
+    fat_sector[5 + 2] = 0xFFFFFFFF;
+    fat_sector[6 + 2] = 0;
+    heap.write_fat_sector(false, 0, bytemuck::cast_slice(&fat_sector));
+    assert!(!heap.is_directory_cluster(6));
+    assert!(heap.entry_sets(5).is_empty());
+    buffer = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(6, 0, &mut buffer);
+    assert_eq!(buffer, dir_sector);
+
+    // cut off cluster is not handed out by the host either
+    assert_eq!(heap.add_directory(root_cluster, "next"), Ok(7));
+}
+
+#[test]
+fn fragmented_allocation() {
+    const BYTES_PER_SECTOR: usize = 512;
+    let path = TempPath::new("fragmented-allocation");
+    std::fs::write(&path, [1; 4097]).unwrap(); // 2 clusters
+
+    // guest leaves holes at cluster 5, 8..=10 and 12..=13
+    let mut heap = ClusterHeap::new(BYTES_PER_SECTOR as _, 8, 512);
+    let mut bitmap_sector = [0; BYTES_PER_SECTOR];
+    bitmap_sector[0] = 0b11011111;
+    bitmap_sector[1] = 0b11001000;
+    assert!(heap.write_sector(0, &bitmap_sector));
+
+    let root_cluster = heap.root_directory_cluster();
+    heap.set_allocation_strategy(AllocationStrategy::BestFit);
+    assert_eq!(heap.map_file_with_name(root_cluster, &path, "best"), Ok(12));
+    assert_eq!(heap.cluster_lookup.get(&13), Some(&12));
+    heap.set_allocation_strategy(AllocationStrategy::FirstFit);
+    assert_eq!(heap.map_file_with_name(root_cluster, &path, "first"), Ok(8));
+    assert_eq!(heap.add_directory(root_cluster, "dir"), Ok(5));
+    assert_eq!(heap.add_directory(root_cluster, "next"), Ok(10));
+    assert_eq!(heap.add_directory(root_cluster, "last"), Ok(16));
+}
+
+#[test]
+fn reused_clusters() {
+    const BYTES_PER_SECTOR: usize = 512;
+    let path = TempPath::new("reused-clusters");
+    std::fs::write(&path, [1; 100]).unwrap();
+    let other_path = TempPath::new("reused-clusters-other");
+    std::fs::write(&other_path, [2; 100]).unwrap();
+
+    // guest deletes a directory the host added, the host adds another one in its place
+    let mut heap = ClusterHeap::new(BYTES_PER_SECTOR as _, 8, 512);
+    let root_cluster = heap.root_directory_cluster();
+    assert_eq!(heap.add_directory(root_cluster, "dir"), Ok(4));
+    assert_eq!(heap.map_file_with_name(4, &path, "inner"), Ok(5));
+    let mut bitmap_sector = [0; BYTES_PER_SECTOR];
+    bitmap_sector[0] = 0b00001111;
+    assert!(heap.write_sector(0, &bitmap_sector));
+    assert_eq!(heap.add_directory(root_cluster, "again"), Ok(4));
+    assert_eq!(heap.parent_lookup.get(&4), Some(&root_cluster));
+    assert_eq!(heap.add_directory(4, "sub"), Ok(5));
+    assert_eq!(heap.parent_lookup.get(&5), Some(&4));
+    assert!(heap.entry_sets(4).iter().any(|set| set.name_lossy() == "sub"));
+    assert_eq!(heap.mapped_entries.len(), 2);
+
+    // guest deletes a mapped file, the host maps another one in its place
+    let mut heap = ClusterHeap::new(BYTES_PER_SECTOR as _, 8, 512);
+    assert_eq!(heap.map_file_with_name(root_cluster, &path, "file"), Ok(4));
+    let mut buffer = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(root_cluster, 0, &mut buffer);
+    for entry in 3..6 {
+        buffer[entry * 32] &= 0x7F;
+    }
+    assert!(heap.write_sector_in_cluster(root_cluster, 0, &buffer));
+    assert!(heap.write_sector(0, &bitmap_sector));
+    assert_eq!(heap.map_file_with_name(root_cluster, &other_path, "other"), Ok(4));
+    heap.read_sector_in_cluster(4, 0, &mut buffer);
+    assert_eq!(buffer[..100], [2; 100]);
+    assert_eq!(heap.mapped_entries[&4].name, "other");
+
+    // deleted file is still reported
+    let report = heap.export_report(&heap.tree());
+    assert!(report.new.is_empty());
+    assert!(report.modified.is_empty());
+    assert_eq!(report.deleted, [PathBuf::from("file")]);
+}
+
+#[test]
+fn remove() {
+    const BYTES_PER_SECTOR: usize = 512;
+    let path = TempPath::new("remove");
+    std::fs::write(&path, [1; 4097]).unwrap(); // 2 clusters
+
+    let mut heap = ClusterHeap::new(BYTES_PER_SECTOR as _, 8, 512);
+    let root_cluster = heap.root_directory_cluster();
+    assert_eq!(heap.map_file_with_name(root_cluster, &path, "file"), Ok(4));
+    assert_eq!(heap.add_directory(root_cluster, "dir"), Ok(6));
+    assert_eq!(heap.add_directory(6, "sub"), Ok(7));
+    assert_eq!(heap.map_file_with_name(7, &path, "nested"), Ok(8));
+
+    // names are matched case-insensitively
+    assert_eq!(heap.remove(root_cluster, "FILE"), Ok(vec![4, 5]));
+    assert!(!heap.allocation_bitmap.is_allocated(4));
+    assert!(!heap.heap.contains_key(&4));
+    assert!(!heap.cluster_lookup.contains_key(&5));
+    assert!(heap.entry_sets(root_cluster).iter().all(|set| set.name_lossy() != "file"));
+    let mut buffer = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(root_cluster, 0, &mut buffer);
+    assert_eq!(buffer[3 * 32], 0x05); // not in use
+
+    assert_eq!(heap.remove(root_cluster, "file"), Err(FileDirectoryEntryError::NotFound));
+    assert_eq!(heap.remove(root_cluster, "dir"), Err(FileDirectoryEntryError::DirectoryNotEmpty));
+    assert_eq!(heap.remove_dir_all(7, "nested"), Err(FileDirectoryEntryError::NotADirectory));
+
+    let mut released = heap.remove_dir_all(root_cluster, "dir").unwrap();
+    released.sort_unstable();
+    assert_eq!(released, [6, 7, 8, 9]);
+    assert!(heap.parent_lookup.is_empty());
+    assert!(heap.mapped_entries.is_empty());
+    assert!(heap.fat.chain(6).next().is_none());
+    assert_eq!(heap.allocation_bitmap.allocated_count(), 4);
+
+    // freed clusters and names are reused
+    assert_eq!(heap.map_file_with_name(root_cluster, &path, "file"), Ok(4));
+    assert_eq!(heap.add_directory(root_cluster, "dir"), Ok(6));
+    assert_eq!(heap.remove(root_cluster, "dir"), Ok(vec![6]));
+}
+
+#[test]
+fn rename() {
+    let path = TempPath::new("rename");
+    std::fs::write(&path, [1; 4097]).unwrap(); // 2 clusters
+    let names = |heap: &ClusterHeap, dir_cluster| -> Vec<String> {
+        heap.entry_sets(dir_cluster).iter().map(EntrySet::name_lossy).collect()
+    };
+
+    let mut heap = ClusterHeap::new(512, 8, 512);
+    let root_cluster = heap.root_directory_cluster();
+    assert_eq!(heap.map_file_with_name(root_cluster, &path, "file"), Ok(4));
+    assert_eq!(heap.add_directory(root_cluster, "a"), Ok(6));
+    assert_eq!(heap.add_directory(root_cluster, "b"), Ok(7));
+    assert_eq!(heap.add_directory(6, "sub"), Ok(8));
+
+    // same number of entries is rewritten in place, longer name is appended
+    heap.rename(root_cluster, "file", root_cluster, "FILE").unwrap();
+    assert_eq!(names(&heap, root_cluster), ["FILE", "a", "b"]);
+    let long_name = "name spanning two entries";
+    heap.rename(root_cluster, "file", root_cluster, long_name).unwrap();
+    assert_eq!(names(&heap, root_cluster), ["a", "b", long_name]);
+
+    // move into another directory
+    heap.rename(root_cluster, long_name, 7, "moved").unwrap();
+    assert_eq!(names(&heap, root_cluster), ["a", "b"]);
+    assert_eq!(names(&heap, 7), ["moved"]);
+    assert_eq!(heap.parent_lookup.get(&4), Some(&7));
+    assert_eq!(heap.mapped_path(4), Some(Path::new("b").join("moved")));
+
+    assert_eq!(heap.rename(root_cluster, "a", root_cluster, "B"), Err(FileDirectoryEntryError::DuplicateName));
+    assert_eq!(heap.rename(root_cluster, "a", 6, "a"), Err(FileDirectoryEntryError::MoveIntoItself));
+    assert_eq!(heap.rename(root_cluster, "a", 8, "a"), Err(FileDirectoryEntryError::MoveIntoItself));
+    assert_eq!(heap.rename(root_cluster, "a", 4, "a"), Err(FileDirectoryEntryError::NotADirectory));
+    assert_eq!(heap.rename(root_cluster, "c", 7, "c"), Err(FileDirectoryEntryError::NotFound));
+
+    heap.rename(root_cluster, "a", 7, "a").unwrap();
+    assert_eq!(heap.parent_lookup.get(&6), Some(&7));
+    assert_eq!(heap.parent_lookup.get(&8), Some(&6));
+
+    // 6 entries and 6 sets of 19 entries, the renamed set of 16 entries does not fit into the cluster
+    for index in 0..6 {
+        let name: String = std::iter::once(char::from(b'0' + index)).chain(std::iter::repeat_n('O', 253)).collect();
+        assert_eq!(heap.add_directory(7, &name), Ok(9 + u32::from(index)));
+    }
+    let long_name = "L".repeat(200);
+    heap.rename(7, "moved", 7, &long_name).unwrap();
+    assert_eq!(heap.fat.chain(7).collect::<Vec<_>>(), [15]);
+    assert!(names(&heap, 7).contains(&long_name));
+    assert!(!names(&heap, 7).contains(&"moved".to_string()));
+    let b = heap.entry_sets(root_cluster).into_iter().find(|set| set.name_lossy() == "b").unwrap();
+    assert_eq!(b.stream_extension.data_length, 2 * 4096);
+}
+
+#[test]
+fn rename_into_contiguous_directory() {
+    const BYTES_PER_SECTOR: usize = 512;
+    let names = |heap: &ClusterHeap, dir_cluster| -> Vec<String> {
+        heap.entry_sets(dir_cluster).iter().map(EntrySet::name_lossy).collect()
+    };
+
+    let mut heap = ClusterHeap::new(BYTES_PER_SECTOR as _, 8, 512);
+    let root_cluster = heap.root_directory_cluster();
+    assert_eq!(heap.add_directory(root_cluster, "a"), Ok(4));
+
+    // guest directory spans clusters 6 and 7 without a FAT chain, the second cluster is full
+    let mut bitmap_sector = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(0, 0, &mut bitmap_sector);
+    bitmap_sector[0] |= 0b11000000;
+    bitmap_sector[1] |= 0b00000001;
+    assert!(heap.write_sector(0, &bitmap_sector));
+    let mut root_sector = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(root_cluster, 0, &mut root_sector);
+    let dir_set = guest_entry_set_with_length("dir", 6, true, 8192, true);
+    root_sector[192..192 + dir_set.len()].copy_from_slice(&dir_set);
+    assert!(heap.write_sector_in_cluster(root_cluster, 0, &root_sector));
+    let mut dir_sector = [0; BYTES_PER_SECTOR];
+    for entry in dir_sector.chunks_exact_mut(32) {
+        entry[0] = 0x05; // deleted file entry
+    }
+    for sector in 1..8 {
+        assert!(heap.write_sector_in_cluster(7, sector, &dir_sector));
+    }
+    let nested_set = guest_entry_set("nested", 8, true);
+    dir_sector[..nested_set.len()].copy_from_slice(&nested_set);
+    assert!(heap.write_sector_in_cluster(7, 0, &dir_sector));
+    heap.take_directory_events();
+
+    // existing clusters are linked in the FAT before the directory loses its contiguous flag
+    heap.rename(root_cluster, "a", 6, "a").unwrap();
+    assert_eq!(heap.fat.chain(6).collect::<Vec<_>>(), [7, 5]);
+    let (dir_set, _) = heap.find_entry_set(root_cluster, "dir").unwrap();
+    assert!(!dir_set.stream_extension.general_secondary_flags.no_fat_chain());
+    assert_eq!(dir_set.stream_extension.data_length, 3 * 4096);
+    assert_eq!(names(&heap, 6), ["nested", "a"]);
+    let (_, positions) = heap.find_entry_set(6, "a").unwrap();
+    assert_eq!(positions[0], (5, 0));
+    assert_eq!(heap.parent_lookup.get(&4), Some(&6));
+    assert_eq!(heap.parent_lookup.get(&8), Some(&6));
+}
+
+#[test]
+fn refresh_file() {
+    let path = TempPath::new("refresh-file");
+    let file_contents = |length: usize| -> Vec<u8> { (0..length).map(|index| (index / 4096) as u8 + 1).collect() };
+    let data_length = |heap: &ClusterHeap| {
+        let root_cluster = heap.root_directory_cluster();
+        let (entry_set, _) = heap.find_entry_set(root_cluster, "file").unwrap();
+        (entry_set.first_cluster(), entry_set.stream_extension.data_length)
+    };
+    let first_byte = |heap: &mut ClusterHeap, cluster_index| {
+        let mut buffer = [0; 512];
+        heap.read_sector_in_cluster(cluster_index, 0, &mut buffer);
+        buffer[0]
+    };
+
+    std::fs::write(&path, file_contents(4097)).unwrap();
+    let mut heap = ClusterHeap::new(512, 8, 512);
+    let root_cluster = heap.root_directory_cluster();
+    assert_eq!(heap.map_file_with_name(root_cluster, &path, "file"), Ok(4));
+    assert_eq!(heap.add_directory(root_cluster, "dir"), Ok(6));
+
+    // shrink and grow back in place
+    std::fs::write(&path, file_contents(100)).unwrap();
+    assert_eq!(heap.refresh_file(root_cluster, "file"), Ok(vec![5]));
+    assert_eq!(data_length(&heap), (Some(4), 100));
+    assert!(!heap.allocation_bitmap.is_allocated(5));
+    std::fs::write(&path, file_contents(4097)).unwrap();
+    assert_eq!(heap.refresh_file(root_cluster, "file"), Ok(vec![]));
+    assert_eq!(data_length(&heap), (Some(4), 4097));
+    assert_eq!(first_byte(&mut heap, 5), 2);
+
+    // directory is in the way, relocated after it
+    std::fs::write(&path, file_contents(8193)).unwrap();
+    assert_eq!(heap.refresh_file(root_cluster, "file"), Ok(vec![4, 5]));
+    assert_eq!(data_length(&heap), (Some(7), 8193));
+    assert_eq!(heap.mapped_path(7), Some(PathBuf::from("file")));
+    assert_eq!(heap.parent_lookup.get(&7), Some(&root_cluster));
+    assert_eq!(first_byte(&mut heap, 9), 3);
+
+    assert_eq!(heap.refresh_file(root_cluster, "dir"), Err(FileDirectoryEntryError::NotMappedFile));
+    assert_eq!(heap.refresh_file(root_cluster, "missing"), Err(FileDirectoryEntryError::NotFound));
+
+    // replaced contents
+    let other_path = TempPath::new("refresh-file-other");
+    std::fs::write(&other_path, [0xCD; 100]).unwrap();
+    assert_eq!(heap.replace_file(root_cluster, "file", &other_path), Ok(vec![7, 8, 9]));
+    assert_eq!(data_length(&heap), (Some(7), 100));
+    assert_eq!(first_byte(&mut heap, 7), 0xCD);
+    assert!(!heap.allocation_bitmap.is_allocated(8));
+
+    // sectors the guest wrote past the end of a write-back file stay pending
+    let mut heap = ClusterHeap::new(512, 8, 512);
+    heap.set_mapping_mode(MappingMode::WriteBack);
+    std::fs::write(&path, file_contents(100)).unwrap();
+    assert_eq!(heap.map_file_with_name(root_cluster, &path, "file"), Ok(4));
+    assert!(heap.write_sector_in_cluster(4, 1, &[0xAB; 512]));
+    std::fs::write(&path, file_contents(200)).unwrap();
+    assert_eq!(heap.refresh_file(root_cluster, "file"), Ok(vec![]));
+    let mut buffer = [0; 512];
+    heap.read_sector_in_cluster(4, 1, &mut buffer);
+    assert_eq!(buffer, [0xAB; 512]);
+    assert_eq!(std::fs::read(&path).unwrap(), file_contents(200));
+
+    // copy-on-write data moves along with the relocated file
+    let delta_path = TempPath::new("refresh-file-delta");
+    let mut heap = ClusterHeap::new(512, 8, 512);
+    heap.set_mapping_mode(MappingMode::CopyOnWrite);
+    std::fs::write(&path, file_contents(4097)).unwrap();
+    assert_eq!(heap.map_file_with_name(root_cluster, &path, "file"), Ok(4));
+    assert_eq!(heap.add_directory(root_cluster, "dir"), Ok(6));
+    heap.open_delta_store(&delta_path).unwrap();
+    assert!(heap.write_sector(5 * 8, &[0xEE; 512]));
+    std::fs::write(&path, file_contents(8193)).unwrap();
+    assert_eq!(heap.refresh_file(root_cluster, "file"), Ok(vec![4, 5]));
+    assert_eq!(data_length(&heap), (Some(7), 8193));
+    heap.read_sector_in_cluster(8, 0, &mut buffer);
+    assert_eq!(buffer, [0xEE; 512]);
+    assert_eq!(first_byte(&mut heap, 7), 1);
+    assert_eq!(first_byte(&mut heap, 9), 3);
+    assert!(heap.dirty_clusters.contains(&8));
+    assert!(!heap.dirty_clusters.contains(&5));
+    let delta_store = heap.delta_store.as_ref().unwrap();
+    assert!(delta_store.contains(8) && !delta_store.contains(5));
+    assert_eq!(std::fs::read(&path).unwrap(), file_contents(8193));
+}
+
+#[test]
+fn refresh_file_fat_chain() {
+    let path = TempPath::new("refresh-file-fat-chain");
+    std::fs::write(&path, [1; 4097]).unwrap();
+
+    let mut heap = ClusterHeap::new(512, 8, 16);
+    let root_cluster = heap.root_directory_cluster();
+    assert_eq!(heap.map_file_with_name(root_cluster, &path, "file"), Ok(4));
+
+    // guest leaves free clusters 7 and 9 only
+    let mut bitmap_sector = [0; 512];
+    bitmap_sector[0] = 0b01111111;
+    bitmap_sector[1] = 0b11111101;
+    assert!(heap.write_sector(0, &bitmap_sector));
+
+    std::fs::write(&path, (0..12289).map(|index| (index / 4096) as u8 + 1).collect::<Vec<_>>()).unwrap();
+    assert_eq!(heap.refresh_file(root_cluster, "file"), Ok(vec![]));
+    let (entry_set, _) = heap.find_entry_set(root_cluster, "file").unwrap();
+    assert!(!entry_set.stream_extension.general_secondary_flags.no_fat_chain());
+    assert_eq!(heap.allocation_chain(4, &entry_set.stream_extension), [4, 5, 7, 9]);
+    for (cluster_index, expected) in [(7, 3), (9, 4)] {
+        let mut buffer = [0; 512];
+        heap.read_sector_in_cluster(cluster_index, 0, &mut buffer);
+        assert_eq!(buffer[0], expected);
+    }
+
+    // nothing changes if the file does not fit
+    std::fs::write(&path, [1; 16385]).unwrap();
+    assert_eq!(heap.refresh_file(root_cluster, "file"), Err(FileDirectoryEntryError::OutOfFreeSpace));
+    assert_eq!(heap.allocation_bitmap.allocated_count(), 16);
+    let (entry_set, _) = heap.find_entry_set(root_cluster, "file").unwrap();
+    assert_eq!(entry_set.stream_extension.data_length, 12289);
+}
+
+#[cfg(test)]
+pub(crate) fn guest_entry_set(name: &str, first_cluster: u32, directory: bool) -> Vec<u8> {
+    guest_entry_set_with_length(name, first_cluster, directory, 4096, true)
+}
+
+#[cfg(test)]
+pub(crate) fn guest_entry_set_with_length(
+    name: &str,
+    first_cluster: u32,
+    directory: bool,
+    data_length: u64,
+    no_fat_chain: bool,
+) -> Vec<u8> {
+    let name_utf16: Vec<u16> = name.encode_utf16().collect();
+    let file_name_entries = FileNameDirectoryEntry::new(&name_utf16).unwrap();
+
+    let mut file_entry = if directory {
+        FileDirectoryEntry::new_directory()
+    } else {
+        FileDirectoryEntry::new_file()
+    };
+    file_entry.secondary_count = 1 + file_name_entries.len() as u8;
+
+    let mut stream_extension_entry = StreamExtensionDirectoryEntry::default();
+    stream_extension_entry.name_length = name_utf16.len() as u8;
+    stream_extension_entry.name_hash = name_hash(&upcased_name(&name_utf16));
+    stream_extension_entry.first_cluster = first_cluster + 2;
+    stream_extension_entry.general_secondary_flags =
+        stream_extension_entry.general_secondary_flags.with_no_fat_chain(no_fat_chain);
+    stream_extension_entry.data_length = data_length;
+    stream_extension_entry.valid_data_length = data_length;
+
+    let mut checksum = entry_checksum(0, file_entry.as_bytes(), true);
+    checksum = entry_checksum(checksum, stream_extension_entry.as_bytes(), false);
+    for file_name_entry in &file_name_entries {
+        checksum = entry_checksum(checksum, file_name_entry.as_bytes(), false);
+    }
+    file_entry.set_checksum = checksum;
+
+    let mut bytes = Vec::new();
+    bytes.extend(file_entry.as_bytes());
+    bytes.extend(stream_extension_entry.as_bytes());
+    for file_name_entry in &file_name_entries {
+        bytes.extend(file_name_entry.as_bytes());
+    }
+
+    bytes
+}
+
+/// Overwrites the entry set of `name` the way the guest would, the set must not span sectors
+#[cfg(test)]
+fn guest_rewrite_entry_set(heap: &mut ClusterHeap, dir_cluster: u32, name: &str, entry_set: &[u8]) {
+    let (_, positions) = heap.find_entry_set(dir_cluster, name).unwrap();
+    let (cluster_index, first_index) = positions[0];
+    let entries_per_sector = heap.bytes_per_sector as usize / 32;
+    let sector = (first_index / entries_per_sector) as u32;
+    let offset = first_index % entries_per_sector * 32;
+    let mut buffer = vec![0; heap.bytes_per_sector as usize];
+    heap.read_sector_in_cluster(cluster_index, sector, &mut buffer);
+    buffer[offset..offset + entry_set.len()].copy_from_slice(entry_set);
+    assert!(heap.write_sector_in_cluster(cluster_index, sector, &buffer));
+}
+
+#[test]
+fn guest_directory_entries() {
+    const BYTES_PER_SECTOR: usize = 512;
+    let mut heap = ClusterHeap::new(BYTES_PER_SECTOR as _, 8, 512);
+    let root_cluster = heap.root_directory_cluster();
+
+    let mut root_sector = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(root_cluster, 0, &mut root_sector);
+
+    // create
+    let file_set = guest_entry_set("file.txt", 5, false);
+    root_sector[96..96 + file_set.len()].copy_from_slice(&file_set);
+    assert!(heap.write_sector_in_cluster(root_cluster, 0, &root_sector));
+    assert_eq!(
+        heap.take_directory_events(),
+        [DirectoryEvent::Created {
+            dir_cluster: root_cluster,
+            name: "file.txt".into(),
+            first_cluster: Some(5),
+        }]
+    );
+    let mut read_back = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(root_cluster, 0, &mut read_back);
+    assert_eq!(root_sector, read_back);
+
+    // half written entry set is ignored
+    let mut corrupted = root_sector;
+    corrupted[96 + 2] ^= 0xFF;
+    assert!(heap.write_sector_in_cluster(root_cluster, 0, &corrupted));
+    assert!(heap.take_directory_events().is_empty());
+    assert!(heap.write_sector_in_cluster(root_cluster, 0, &root_sector));
+    assert!(heap.take_directory_events().is_empty());
+
+    // rename
+    let renamed_set = guest_entry_set("renamed.txt", 5, false);
+    root_sector[96..96 + renamed_set.len()].copy_from_slice(&renamed_set);
+    assert!(heap.write_sector_in_cluster(root_cluster, 0, &root_sector));
+    assert_eq!(
+        heap.take_directory_events(),
+        [DirectoryEvent::Renamed {
+            dir_cluster: root_cluster,
+            old_name: "file.txt".into(),
+            new_name: "renamed.txt".into(),
+            first_cluster: 5,
+        }]
+    );
+
+    // delete, in use bit is cleared
+    for entry in root_sector[96..96 + renamed_set.len()].chunks_exact_mut(32) {
+        entry[0] &= 0x7F;
+    }
+    assert!(heap.write_sector_in_cluster(root_cluster, 0, &root_sector));
+    assert_eq!(
+        heap.take_directory_events(),
+        [DirectoryEvent::Deleted {
+            dir_cluster: root_cluster,
+            name: "renamed.txt".into(),
+            first_cluster: Some(5),
+        }]
+    );
+
+    // directory created by the guest
+    let dir_set = guest_entry_set("dir", 6, true);
+    root_sector[192..192 + dir_set.len()].copy_from_slice(&dir_set);
+    assert!(heap.write_sector_in_cluster(root_cluster, 0, &root_sector));
+    heap.take_directory_events();
+    assert_eq!(heap.parent_lookup.get(&6), Some(&root_cluster));
+
+    let mut dir_sector = [0; BYTES_PER_SECTOR];
+    let file_set = guest_entry_set("nested", 7, false);
+    dir_sector[..file_set.len()].copy_from_slice(&file_set);
+    assert!(heap.write_sector_in_cluster(6, 0, &dir_sector));
+    assert_eq!(
+        heap.take_directory_events(),
+        [DirectoryEvent::Created {
+            dir_cluster: 6,
+            name: "nested".into(),
+            first_cluster: Some(7),
+        }]
+    );
+
+    // guest extends the directory with another cluster
+    let mut fat_sector = [0u32; BYTES_PER_SECTOR / 4];
+    heap.fat.read_sector_first(0, bytemuck::cast_slice_mut(&mut fat_sector));
+    fat_sector[6 + 2] = 8 + 2;
+    fat_sector[8 + 2] = 0xFFFFFFFF;
+    heap.write_fat_sector(false, 0, bytemuck::cast_slice(&fat_sector));
+    assert!(heap.is_directory_cluster(8));
+    assert_eq!(heap.directory_of(8), 6);
+}
+
+#[test]
+fn contiguous_guest_directory() {
+    const BYTES_PER_SECTOR: usize = 512;
+    let mut heap = ClusterHeap::new(BYTES_PER_SECTOR as _, 8, 512);
+    let root_cluster = heap.root_directory_cluster();
+
+    // directory spans clusters 6 and 7 without a FAT chain
+    let mut root_sector = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(root_cluster, 0, &mut root_sector);
+    let dir_set = guest_entry_set_with_length("dir", 6, true, 8192, true);
+    root_sector[96..96 + dir_set.len()].copy_from_slice(&dir_set);
+    assert!(heap.write_sector_in_cluster(root_cluster, 0, &root_sector));
+    heap.take_directory_events();
+    assert_eq!(heap.directory_of(7), 6);
+
+    let mut dir_sector = [0; BYTES_PER_SECTOR];
+    let nested_set = guest_entry_set("nested", 8, true);
+    dir_sector[..nested_set.len()].copy_from_slice(&nested_set);
+    assert!(heap.write_sector_in_cluster(7, 0, &dir_sector));
+    assert_eq!(
+        heap.take_directory_events(),
+        [DirectoryEvent::Created {
+            dir_cluster: 6,
+            name: "nested".into(),
+            first_cluster: Some(8),
+        }]
+    );
+    assert_eq!(heap.parent_lookup.get(&8), Some(&6));
+    let (_, positions) = heap.find_entry_set(6, "nested").unwrap();
+    assert_eq!(positions[0], (7, 0));
+
+    assert!(heap.remove(6, "nested").is_ok());
+    assert!(heap.find_entry_set(6, "nested").is_none());
+    assert!(heap.entry_sets(6).is_empty());
+}
+
+#[test]
+fn write_back() {
+    const BYTES_PER_SECTOR: usize = 512;
+    let path = TempPath::new("write-back");
+    std::fs::write(&path, [1; 100]).unwrap();
+
+    let mut heap = ClusterHeap::new(BYTES_PER_SECTOR as _, 8, 512);
+    heap.set_mapping_mode(MappingMode::WriteBack);
+    let root_cluster = heap.root_directory_cluster();
+    let file_cluster = heap.map_file_with_name(root_cluster, &path, "file").unwrap();
+    assert_eq!(file_cluster, 4);
+
+    // overwrite within the file, the rest of the sector is past the end of file
+    assert!(heap.write_sector_in_cluster(file_cluster, 0, &[2; BYTES_PER_SECTOR]));
+    assert_eq!(std::fs::read(&path).unwrap(), [2; 100]);
+    let mut buffer = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(file_cluster, 0, &mut buffer);
+    assert_eq!(buffer, [2; BYTES_PER_SECTOR]);
+
+    // guest allocates the next cluster, writes to it and then extends the file
+    let mut bitmap_sector = [0; BYTES_PER_SECTOR];
+    bitmap_sector[0] = 0b00111111;
+    assert!(heap.write_sector(0, &bitmap_sector));
+    assert!(heap.write_sector_in_cluster(file_cluster + 1, 0, &[3; BYTES_PER_SECTOR]));
+
+    let mut root_sector = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(root_cluster, 0, &mut root_sector);
+    let mut stream_extension: StreamExtensionDirectoryEntry =
+        bytemuck::pod_read_unaligned(&root_sector[128..160]);
+    stream_extension.data_length = 4096 + 100;
+    stream_extension.valid_data_length = 4096 + 100;
+    root_sector[128..160].copy_from_slice(stream_extension.as_bytes());
+    let mut checksum = 0;
+    for (index, entry) in root_sector[96..192].chunks_exact(32).enumerate() {
+        checksum = entry_checksum(checksum, entry, index == 0);
+    }
+    root_sector[98..100].copy_from_slice(&checksum.to_le_bytes());
+    assert!(heap.write_sector_in_cluster(root_cluster, 0, &root_sector));
+
+    let mut expected = vec![2; 512];
+    expected.extend([0; 4096 - 512]);
+    expected.extend([3; 100]);
+    assert_eq!(std::fs::read(&path).unwrap(), expected);
+
+    buffer = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(file_cluster + 1, 0, &mut buffer);
+    assert_eq!(buffer, [3; BYTES_PER_SECTOR]);
+
+    // guest deletes the file and reuses its first cluster, the host file is left alone
+    for entry in root_sector[96..192].chunks_exact_mut(32) {
+        entry[0] &= 0x7F;
+    }
+    assert!(heap.write_sector_in_cluster(root_cluster, 0, &root_sector));
+    bitmap_sector[0] = 0b00001111;
+    assert!(heap.write_sector(0, &bitmap_sector));
+    assert!(!heap.cluster_lookup.contains_key(&file_cluster));
+    assert!(!heap.cluster_lookup.contains_key(&(file_cluster + 1)));
+
+    bitmap_sector[0] = 0b00011111;
+    assert!(heap.write_sector(0, &bitmap_sector));
+    assert!(heap.write_sector_in_cluster(file_cluster, 0, &[4; BYTES_PER_SECTOR]));
+    assert!(heap.write_sector_in_cluster(file_cluster, 7, &[4; BYTES_PER_SECTOR]));
+    let new_set = guest_entry_set("new", file_cluster, false);
+    root_sector[192..192 + new_set.len()].copy_from_slice(&new_set);
+    assert!(heap.write_sector_in_cluster(root_cluster, 0, &root_sector));
+    assert_eq!(std::fs::read(&path).unwrap(), expected);
+}
+
+#[test]
+fn freed_mapped_clusters() {
+    const BYTES_PER_SECTOR: usize = 512;
+    let path = TempPath::new("freed-mapped-clusters");
+    std::fs::write(&path, [1; 3 * 4096]).unwrap();
+
+    let mut heap = ClusterHeap::new(BYTES_PER_SECTOR as _, 8, 512);
+    heap.set_mapping_mode(MappingMode::WriteBack);
+    let root_cluster = heap.root_directory_cluster();
+    assert_eq!(heap.map_file_with_name(root_cluster, &path, "file"), Ok(4));
+
+    // guest frees the middle cluster, the run is split around it
+    let mut bitmap_sector = [0; BYTES_PER_SECTOR];
+    bitmap_sector[0] = 0b01011111;
+    assert!(heap.write_sector(0, &bitmap_sector));
+    assert_eq!(heap.cluster_lookup.get(&4), Some(&4));
+    assert!(!heap.cluster_lookup.contains_key(&5));
+    assert_eq!(heap.cluster_lookup.get(&6), Some(&6));
+
+    assert!(heap.write_sector_in_cluster(6, 0, &[2; BYTES_PER_SECTOR]));
+    let mut expected = vec![1; 2 * 4096];
+    expected.extend([2; BYTES_PER_SECTOR]);
+    expected.extend([1; 4096 - BYTES_PER_SECTOR]);
+    assert_eq!(std::fs::read(&path).unwrap(), expected);
+
+    // freeing the first cluster leaves the rest of the run mapped
+    bitmap_sector[0] = 0b01001111;
+    assert!(heap.write_sector(0, &bitmap_sector));
+    assert!(!heap.heap.contains_key(&4));
+    let mut buffer = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(6, 0, &mut buffer);
+    assert_eq!(buffer, [2; BYTES_PER_SECTOR]);
+}
+
+#[test]
+fn copy_on_write() {
+    const BYTES_PER_SECTOR: usize = 512;
+    let path = TempPath::new("copy-on-write");
+    let delta_path = TempPath::new("copy-on-write-delta");
+    std::fs::write(&path, [1; 1000]).unwrap();
+
+    let new_heap = || {
+        let mut heap = ClusterHeap::new(BYTES_PER_SECTOR as _, 8, 512);
+        heap.set_mapping_mode(MappingMode::CopyOnWrite);
+        let root_cluster = heap.root_directory_cluster();
+        assert_eq!(heap.map_file_with_name(root_cluster, &path, "file"), Ok(4));
+        heap.open_delta_store(&delta_path).map(|_| heap)
+    };
+
+    // file keeps the mode it was mapped with
+    let mut heap = new_heap().unwrap();
+    heap.set_mapping_mode(MappingMode::WriteBack);
+    assert!(heap.write_sector_in_cluster(4, 1, &[2; BYTES_PER_SECTOR]));
+    assert_eq!(std::fs::read(&path).unwrap(), [1; 1000]);
+
+    let mut buffer = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(4, 0, &mut buffer);
+    assert_eq!(buffer, [1; BYTES_PER_SECTOR]);
+    heap.read_sector_in_cluster(4, 1, &mut buffer);
+    assert_eq!(buffer, [2; BYTES_PER_SECTOR]);
+    drop(heap);
+
+    // later session reapplies the delta
+    let mut heap = new_heap().unwrap();
+    buffer = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(4, 1, &mut buffer);
+    assert_eq!(buffer, [2; BYTES_PER_SECTOR]);
+
+    // store is cut short while in use, reads fall back to the host file
+    std::fs::OpenOptions::new().write(true).open(&delta_path).unwrap().set_len(100).unwrap();
+    buffer = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(4, 0, &mut buffer);
+    assert_eq!(buffer, [1; BYTES_PER_SECTOR]);
+    drop(heap);
+    std::fs::remove_file(&delta_path).unwrap();
+    let mut heap = new_heap().unwrap();
+    assert!(heap.write_sector_in_cluster(4, 1, &[2; BYTES_PER_SECTOR]));
+
+    heap.discard_delta_store().unwrap();
+    buffer = [0; BYTES_PER_SECTOR];
+    heap.read_sector_in_cluster(4, 1, &mut buffer);
+    assert_eq!(&buffer[..1000 - BYTES_PER_SECTOR], [1; 1000 - BYTES_PER_SECTOR]);
+    assert_eq!(&buffer[1000 - BYTES_PER_SECTOR..], [0; 2 * BYTES_PER_SECTOR - 1000]);
+    drop(heap);
+
+    // host file changed since the delta was written
+    std::fs::write(&path, [1; 2000]).unwrap();
+    assert!(matches!(new_heap(), Err(DeltaStoreError::MappingMismatch)));
+}
+
+#[test]
+fn map_directory() {
+    let host_dir = TempPath::new("map-directory");
+    std::fs::create_dir_all(host_dir.join("sub")).unwrap();
+    std::fs::create_dir_all(host_dir.join("target")).unwrap();
+    for path in ["a.txt", "A.TXT", "sub/keep.rs", "sub/skip.o", "target/excluded"] {
+        std::fs::write(host_dir.join(path), path).unwrap();
+    }
+    // names illegal in exFAT and symbolic links can't be created on every host
+    #[cfg(unix)]
+    {
+        std::fs::write(host_dir.join("bad:name"), "bad:name").unwrap();
+        std::os::unix::fs::symlink(host_dir.join("a.txt"), host_dir.join("link")).unwrap();
+    }
+
+    let options = MapDirectoryOptions::default()
+        .exclude("*.o")
+        .unwrap()
+        .exclude("target")
+        .unwrap()
+        .symlinks(SymlinkPolicy::Skip);
+
+    let mut heap = ClusterHeap::new(512, 8, 512);
+    let root_cluster = heap.root_directory_cluster();
+    let report = heap.map_directory(root_cluster, &host_dir, &options).unwrap();
+
+    let mut expected_skipped = vec![(host_dir.join("a.txt"), SkipReason::NameCollision)];
+    #[cfg(unix)]
+    expected_skipped.extend([
+        (host_dir.join("bad:name"), SkipReason::IllegalName),
+        (host_dir.join("link"), SkipReason::Symlink),
+    ]);
+    assert_eq!(report.skipped, expected_skipped);
+    assert_eq!(report.directories.len(), 2);
+    assert_eq!(report.files.len(), 2);
+
+    let mut paths: Vec<PathBuf> = heap.tree().into_iter().map(|entry| entry.path).collect();
+    paths.sort();
+    assert_eq!(paths, [PathBuf::from("A.TXT"), "sub".into(), Path::new("sub").join("keep.rs")]);
+
+    // only matching files are mapped, directories are still walked
+    let mut heap = ClusterHeap::new(512, 8, 512);
+    let report = heap
+        .map_directory(root_cluster, &host_dir, &options.clone().include("*.rs").unwrap())
+        .unwrap();
+    assert_eq!(report.files, [(host_dir.join("sub").join("keep.rs"), 5)]);
+
+    // following the link maps the file it points to, erroring stops mapping
+    #[cfg(unix)]
+    {
+        let mut heap = ClusterHeap::new(512, 8, 512);
+        let report = heap
+            .map_directory(root_cluster, &host_dir, &options.clone().symlinks(SymlinkPolicy::Follow))
+            .unwrap();
+        assert_eq!(report.files.len(), 3);
+
+        let mut heap = ClusterHeap::new(512, 8, 512);
+        let ret = heap.map_directory(root_cluster, &host_dir, &options.symlinks(SymlinkPolicy::Error));
+        assert_eq!(ret.unwrap_err(), FileDirectoryEntryError::Symlink);
+    }
+}
+
+#[test]
+fn timestamps() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let host_dir = TempPath::new("timestamps");
+    let path = host_dir.join("file");
+    std::fs::create_dir(&host_dir).unwrap();
+    std::fs::write(&path, [1; 100]).unwrap();
+    let modified = UNIX_EPOCH + Duration::from_secs(1_686_832_497);
+    File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+
+    let mut heap = ClusterHeap::new(512, 8, 512);
+    let root_cluster = heap.root_directory_cluster();
+    heap.map_file_with_name(root_cluster, &path, "file").unwrap();
+    let directory_times = EntryTimes {
+        created: modified,
+        modified,
+        accessed: modified,
+    };
+    heap.add_directory_with_times(root_cluster, "dir", &directory_times).unwrap();
+    heap.add_directory(root_cluster, "plain").unwrap();
+
+    let times = EntryTimes::from_metadata(&std::fs::metadata(&path).unwrap()).unwrap();
+    assert_eq!(times.modified, modified);
+    let mut expected_file = FileDirectoryEntry::new_file();
+    expected_file.set_times(&times);
+    let mut expected_directory = FileDirectoryEntry::new_directory();
+    expected_directory.set_times(&directory_times);
+
+    // create, last modified and last accessed timestamps, 10ms increments and UTC offsets
+    let entry_sets = heap.entry_sets(root_cluster);
+    assert_eq!(entry_sets[0].file.as_bytes()[8..25], expected_file.as_bytes()[8..25]);
+    assert_eq!(entry_sets[1].file.as_bytes()[8..25], expected_directory.as_bytes()[8..25]);
+    assert_ne!(expected_directory.as_bytes()[8..25], [0; 17]);
+    // without explicit times they stay zeroed
+    assert_eq!(entry_sets[2].file.as_bytes()[8..25], [0; 17]);
+
+    // mapped directories get the times of the host directory
+    std::fs::create_dir(host_dir.join("sub")).unwrap();
+    let mut heap = ClusterHeap::new(512, 8, 512);
+    heap.map_directory(root_cluster, &host_dir, &MapDirectoryOptions::default()).unwrap();
+    let times = EntryTimes::from_metadata(&std::fs::metadata(host_dir.join("sub")).unwrap()).unwrap();
+    expected_directory.set_times(&times);
+    let entry_sets = heap.entry_sets(root_cluster);
+    assert_eq!(entry_sets[1].file.as_bytes()[8..25], expected_directory.as_bytes()[8..25]);
+}
@@ -1,18 +1,44 @@
 use std::{
-    io::{self, Read, Seek, SeekFrom},
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
     path::Path,
 };
 
 use crate::utils::{unsigned_align_to, unsigned_rounded_up_div};
 
 mod boot_region;
+mod builder;
 pub(crate) mod data_region;
+mod delta;
 mod fat_region;
 mod heap;
+pub mod nbd;
+mod overlay;
+mod partition;
 mod utils;
+#[cfg(target_os = "linux")]
+mod watch;
 
+pub use data_region::allocation_bitmap::AllocationStrategy;
 use data_region::file::FileDirectoryEntryError;
+pub use data_region::file::EntryTimes;
+pub use data_region::volume_label::VolumeLabelError;
+use data_region::volume_label::VolumeLabelDirectoryEntry;
 use heap::ClusterHeap;
+pub use boot_region::{BootRegionError, FlashParameters, OemParameters, VolumeFlags};
+pub use builder::VexfatBuilder;
+pub use delta::DeltaStoreError;
+pub use heap::{
+    DirectoryEvent, ExportReport, MapDirectoryOptions, MapDirectoryReport, MappingMode, SkipReason, SymlinkPolicy,
+};
+use overlay::Overlay;
+pub use partition::{PartitionError, PartitionTable, PartitionedBlockDevice};
+#[cfg(target_os = "linux")]
+pub use watch::DirectoryWatcher;
+
+/// Cluster indices above are reserved for FAT entry values
+const MAX_CLUSTER_COUNT: u32 = 0xFFFF_FFF5;
 
 #[cfg(target_endian = "big")]
 compile_error!("Big-endian not supported");
@@ -25,8 +51,22 @@ pub enum VexfatError {
     /// Must be between 0..=25
     InvalidSectorsPerClusterShift,
 
-    /// Must be divisible by 2
+    /// Must be divisible by 2 and at most 2^32 - 11
     InvalidClusterCount,
+
+    /// Must be 1 or 2
+    InvalidNumberOfFats,
+
+    InvalidVolumeLabel(VolumeLabelError),
+
+    /// Volume must be at least 1 MiB, with enough clusters for the allocation bitmap, upcase table and root directory
+    VolumeTooSmall,
+
+    /// Needs more clusters than exFAT allows, or the FAT does not fit into 32-bit sector offsets
+    VolumeTooLarge,
+
+    /// Must be a non-zero multiple of the sector size, less than 2^32 sectors
+    InvalidAlignment,
 }
 
 #[derive(Debug, PartialEq)]
@@ -34,8 +74,69 @@ pub enum ReadError {
     OutOfBounds,
 }
 
+#[derive(Debug, PartialEq)]
+pub enum WriteError {
+    OutOfBounds,
+}
+
+/// Sectors in the main and backup boot regions
+const BOOT_REGIONS_LENGTH: u64 = 24;
+
+/// Offsets and lengths of the regions, in sectors
+struct Layout {
+    fat_offset: u64,
+    fat_length: u64,
+    cluster_heap_offset: u64,
+    volume_length: u64,
+}
+
+impl Layout {
+    /// FAT and cluster heap start at a multiple of their alignment, 1 for no alignment,
+    /// with two FATs the second one is aligned as well
+    fn new(
+        bytes_per_sector_shift: u8,
+        sectors_per_cluster_shift: u8,
+        cluster_count: u64,
+        number_of_fats: u8,
+        fat_alignment: u64,
+        cluster_heap_alignment: u64,
+    ) -> Self {
+        let bytes_per_sector = 1u64 << bytes_per_sector_shift;
+        let sectors_per_cluster = 1u64 << sectors_per_cluster_shift;
+
+        let min_fat_length = unsigned_rounded_up_div((cluster_count + 2) * 4, bytes_per_sector);
+        let mut fat_length = unsigned_align_to(min_fat_length, sectors_per_cluster);
+        if number_of_fats > 1 {
+            fat_length = unsigned_align_to(fat_length, fat_alignment);
+        }
+
+        let fat_offset = unsigned_align_to(BOOT_REGIONS_LENGTH, fat_alignment);
+        let cluster_heap_offset =
+            unsigned_align_to(fat_offset + fat_length * u64::from(number_of_fats), cluster_heap_alignment);
+        let volume_length = cluster_heap_offset + cluster_count * sectors_per_cluster;
+
+        Self {
+            fat_offset,
+            fat_length,
+            cluster_heap_offset,
+            volume_length,
+        }
+    }
+}
+
+/// Regions past the main and backup boot regions
+enum Region {
+    FatAlignment,
+    FirstFat(u64),
+    SecondFat(u64),
+    ClusterHeapAlignment,
+    ClusterHeap(u64),
+    ExcessSpace,
+}
+
 pub struct VirtualExFatBlockDevice {
     // boot sector
+    partition_offset: u64,
     volume_length: u64,
     fat_offset: u32,
     fat_length: u32,
@@ -43,11 +144,22 @@ pub struct VirtualExFatBlockDevice {
     cluster_count: u32,
     first_cluster_of_root_directory: u32,
     volume_serial_number: u32,
+    volume_flags: VolumeFlags,
     bytes_per_sector_shift: u8,
     sectors_per_cluster_shift: u8,
     number_of_fats: u8,
 
+    boot_code: [u8; 390],
+    /// Of each of the 8 extended boot sectors
+    extended_boot_code: Vec<Vec<u8>>,
+    /// `None` fills the OEM parameters sector with `0xFF`
+    oem_parameters: Option<Vec<OemParameters>>,
+
+    /// Main boot region, backup one is the same, reset when a field it is rendered from changes
+    boot_region: Option<Box<[u8]>>,
+
     heap: ClusterHeap,
+    overlay: Overlay,
 
     current_sector: u64,
     current_offset_in_sector: u64,
@@ -59,256 +171,416 @@ impl VirtualExFatBlockDevice {
     }
 
     pub fn new_with_serial_number(bytes_per_sector_shift: u8, sectors_per_cluster_shift: u8, cluster_count: u32, volume_serial_number: u32) -> Result<Self, VexfatError> {
-        assert!(cluster_count % 2 == 0);
+        Self::new_with_alignment(bytes_per_sector_shift, sectors_per_cluster_shift, cluster_count, 1, 1, 1, volume_serial_number)
+    }
 
-        const NUMBER_OF_FATS: u8 = 1;
+    /// With two FATs, the guest can switch between them and their allocation bitmaps with the `ActiveFat` volume flag,
+    /// alignments are in sectors
+    pub(crate) fn new_with_alignment(
+        bytes_per_sector_shift: u8,
+        sectors_per_cluster_shift: u8,
+        cluster_count: u32,
+        number_of_fats: u8,
+        fat_alignment: u64,
+        cluster_heap_alignment: u64,
+        volume_serial_number: u32,
+    ) -> Result<Self, VexfatError> {
+        if !(9..=12).contains(&bytes_per_sector_shift) {
+            return Err(VexfatError::InvalidBytesPerSectorShift);
+        }
+        if sectors_per_cluster_shift > 25 - bytes_per_sector_shift {
+            return Err(VexfatError::InvalidSectorsPerClusterShift);
+        }
+        if !cluster_count.is_multiple_of(2) || cluster_count > MAX_CLUSTER_COUNT {
+            return Err(VexfatError::InvalidClusterCount);
+        }
+        if !(1..=2).contains(&number_of_fats) {
+            return Err(VexfatError::InvalidNumberOfFats);
+        }
+        let valid_alignment = |alignment| (1..=u64::from(u32::MAX)).contains(&alignment);
+        if !valid_alignment(fat_alignment) || !valid_alignment(cluster_heap_alignment) {
+            return Err(VexfatError::InvalidAlignment);
+        }
 
-        let min_fat_length =
-            unsigned_rounded_up_div((cluster_count + 2) * 4, 1 << bytes_per_sector_shift);
+        let bytes_per_sector = 1u64 << bytes_per_sector_shift;
 
-        let fat_length = unsigned_align_to(min_fat_length, 1 << sectors_per_cluster_shift); // sectors
-        let fat_offset = 24; // sectors, no alignment
-        let cluster_heap_offset = fat_offset + fat_length; // sectors, no alignment
-        let volume_length = u64::from(cluster_heap_offset)
-            + (u64::from(cluster_count) * (1 << sectors_per_cluster_shift)); // sectors
+        let Layout {
+            fat_offset,
+            fat_length,
+            cluster_heap_offset,
+            volume_length,
+        } = Layout::new(
+            bytes_per_sector_shift,
+            sectors_per_cluster_shift,
+            cluster_count.into(),
+            number_of_fats,
+            fat_alignment,
+            cluster_heap_alignment,
+        );
 
-        let min_volume_length = (1 << 20) / (1 << bytes_per_sector_shift);
-        let min_fat_offset = 24;
-        let min_cluster_heap_offset = fat_offset + (fat_length * u32::from(NUMBER_OF_FATS));
+        let min_volume_length = (1 << 20) / bytes_per_sector;
+        if volume_length < min_volume_length {
+            return Err(VexfatError::VolumeTooSmall);
+        }
 
-        assert!(volume_length >= min_volume_length);
-        assert!(fat_offset >= min_fat_offset);
-        assert!(fat_length >= min_fat_length);
-        assert!(cluster_heap_offset >= min_cluster_heap_offset);
+        // offsets and lengths in the boot sector are 32-bit
+        if cluster_heap_offset > u64::from(u32::MAX) {
+            return Err(VexfatError::VolumeTooLarge);
+        }
+        let fat_offset = fat_offset as u32;
+        let fat_length = fat_length as u32;
+        let cluster_heap_offset = cluster_heap_offset as u32;
 
-        let max_fat_offset = cluster_heap_offset - (fat_length * u32::from(NUMBER_OF_FATS));
-        let max_fat_length = (cluster_heap_offset - fat_offset) / u32::from(NUMBER_OF_FATS);
-        let max_cluster_heap_offset: u32 = u64::min(
-            u64::from(u32::MAX),
-            volume_length - (u64::from(cluster_count) * (1 << sectors_per_cluster_shift)),
-        )
-        .try_into()
-        .unwrap();
-        assert!(fat_offset <= max_fat_offset);
-        assert!(fat_length <= max_fat_length);
-        assert!(cluster_heap_offset <= max_cluster_heap_offset);
+        let bytes_per_cluster = 1 << (bytes_per_sector_shift + sectors_per_cluster_shift);
+        if ClusterHeap::system_clusters_count(bytes_per_cluster, cluster_count, number_of_fats) > cluster_count {
+            return Err(VexfatError::VolumeTooSmall);
+        }
 
-        let heap = ClusterHeap::new(
+        let heap = ClusterHeap::new_with_number_of_fats(
             1 << bytes_per_sector_shift,
             1 << sectors_per_cluster_shift,
             cluster_count,
+            number_of_fats,
         );
 
         let first_cluster_of_root_directory = heap.root_directory_cluster() + 2;
-        assert!(first_cluster_of_root_directory >= 2);
-        assert!(first_cluster_of_root_directory <= cluster_count + 1);
 
         Ok(Self {
+            partition_offset: 0,
             volume_length,
             cluster_heap_offset,
             cluster_count,
             first_cluster_of_root_directory,
             volume_serial_number,
+            volume_flags: VolumeFlags::new_with_raw_value(0),
             fat_offset,
             fat_length,
             bytes_per_sector_shift,
             sectors_per_cluster_shift,
-            number_of_fats: NUMBER_OF_FATS,
+            number_of_fats,
+            boot_code: [0; 390],
+            extended_boot_code: vec![Vec::new(); 8],
+            oem_parameters: None,
+            boot_region: None,
             heap,
+            overlay: Overlay::default(),
             current_sector: 0,
             current_offset_in_sector: 0,
         })
     }
 
+    /// Main boot sector as it is emulated, regardless of the overlay
+    fn read_boot_sector(&self, buffer: &mut [u8]) {
+        let mut region: boot_region::BootSector = bytemuck::Zeroable::zeroed();
+        region.jump_boot = [0xEB, 0x76, 0x90];
+        region.filesystem_name = [b'E', b'X', b'F', b'A', b'T', b' ', b' ', b' '];
+        region.partition_offset = self.partition_offset;
+        region.volume_length = self.volume_length;
+        region.fat_offset = self.fat_offset;
+        region.fat_length = self.fat_length;
+        region.cluster_heap_offset = self.cluster_heap_offset;
+        region.cluster_count = self.cluster_count;
+        region.first_cluster_of_root_directory = self.first_cluster_of_root_directory;
+        region.volume_serial_number = self.volume_serial_number;
+        region.filesystem_revision = 256; // 1.00
+        region.volume_flags = self.volume_flags.with_active_fat(self.heap.fat.is_second_active());
+        region.bytes_per_sector_shift = self.bytes_per_sector_shift;
+        region.sectors_per_cluster_shift = self.sectors_per_cluster_shift;
+        region.number_of_fats = self.number_of_fats;
+        region.drive_select = 0x80;
+        region.percent_in_use = self.heap.percent_in_use();
+        region.boot_code = self.boot_code;
+        region.boot_signature = [0x55, 0xAA];
+
+        buffer[..512].copy_from_slice(bytemuck::bytes_of(&region));
+    }
+
+    /// Main boot region, rendered on first read
+    fn boot_region(&mut self) -> &[u8] {
+        if self.boot_region.is_none() {
+            let bytes_per_sector = usize::from(self.bytes_per_sector());
+            let mut boot_region = vec![0; 12 * bytes_per_sector];
+            let mut sectors = boot_region.chunks_exact_mut(bytes_per_sector);
+
+            // main boot sector
+            self.read_boot_sector(sectors.next().unwrap());
+
+            // main extended boot sectors
+            for (extended_boot_code, sector) in self.extended_boot_code.iter().zip(sectors.by_ref()) {
+                sector[..extended_boot_code.len()].copy_from_slice(extended_boot_code);
+                sector[bytes_per_sector - 4..].copy_from_slice(&[0x00, 0x00, 0x55, 0xAA]);
+            }
+
+            // main OEM parameters, unused ones are null parameters
+            let sector = sectors.next().unwrap();
+            match &self.oem_parameters {
+                Some(oem_parameters) => {
+                    let oem_parameters: &[u8] = bytemuck::cast_slice(oem_parameters);
+                    sector[..oem_parameters.len()].copy_from_slice(oem_parameters);
+                }
+                None => sector.fill(0xFF),
+            }
+
+            // main boot checksum, main reserved sector is left zeroed
+            let (sectors, checksum_sector) = boot_region.split_at_mut(11 * bytes_per_sector);
+            let checksum = boot_region::boot_checksum(sectors);
+            for four_bytes in checksum_sector.chunks_exact_mut(4) {
+                four_bytes.copy_from_slice(&checksum.to_le_bytes());
+            }
+
+            self.boot_region = Some(boot_region.into_boxed_slice());
+        }
+
+        self.boot_region.as_deref().unwrap()
+    }
+
     /// `buffer` is assumed to be zeroed
     pub fn read_sector(&mut self, sector_index: u64, buffer: &mut [u8]) -> Result<(), ReadError> {
         assert_eq!(buffer.len(), usize::from(self.bytes_per_sector()));
 
+        if self.overlay.read_sector(sector_index, buffer) {
+            return Ok(());
+        }
+
         match sector_index {
-            // main boot region
-            0 => {
-                // main boot sector
-                let region: &mut boot_region::BootSector = bytemuck::from_bytes_mut(&mut buffer[..512]);
-                region.jump_boot = [0xEB, 0x76, 0x90];
-                region.filesystem_name = [b'E', b'X', b'F', b'A', b'T', b' ', b' ', b' '];
-                region.volume_length = self.volume_length;
-                region.fat_offset = self.fat_offset;
-                region.fat_length = self.fat_length;
-                region.cluster_heap_offset = self.cluster_heap_offset;
-                region.cluster_count = self.cluster_count;
-                region.first_cluster_of_root_directory = self.first_cluster_of_root_directory;
-                region.volume_serial_number = self.volume_serial_number;
-                region.filesystem_revision = 256; // 1.00
-                region.bytes_per_sector_shift = self.bytes_per_sector_shift;
-                region.sectors_per_cluster_shift = self.sectors_per_cluster_shift;
-                region.number_of_fats = self.number_of_fats;
-                region.drive_select = 0x80;
-                region.percent_in_use = 0xFF; // not available
-                region.boot_signature = [0x55, 0xAA];
+            // main and backup boot regions
+            0..=23 => {
+                let bytes_per_sector = buffer.len();
+                let sector = (sector_index % 12) as usize;
+                let boot_region = self.boot_region();
+                buffer.copy_from_slice(&boot_region[sector * bytes_per_sector..][..bytes_per_sector]);
+
+                if sector == 0 {
+                    // not part of the checksum, so not cached either
+                    buffer[106..108].copy_from_slice(&self.volume_flags().raw_value().to_le_bytes());
+                    buffer[112] = self.heap.percent_in_use();
+                }
 
                 Ok(())
             }
-            1..=8 => {
-                // main extended boot sectors
-                buffer[510] = 0x55;
-                buffer[511] = 0xAA;
 
-                Ok(())
-            }
-            9 => {
-                // main OEM parameters
-                for byte in buffer.iter_mut() {
-                    *byte = 0xFF;
+            _ => match self.region(sector_index).ok_or(ReadError::OutOfBounds)? {
+                Region::FatAlignment => Ok(()),
+                Region::FirstFat(fat_sector) => {
+                    self.heap.fat.read_sector_first(fat_sector, buffer);
+                    Ok(())
                 }
-
-                Ok(())
-            }
-            10 => {
-                // main reserved
-                Ok(())
-            }
-            11 => {
-                // main boot checksum
-                let mut checksum = 0u32;
-
-                for sector in 0..11 {
-                    let mut buffer = vec![0; usize::from(self.bytes_per_sector())];
-                    self.read_sector(sector, &mut buffer).unwrap();
-
-                    for (index, byte) in buffer.iter().enumerate() {
-                        // skip `volume_flags` and `percent_in_use`
-                        if sector == 0 && (index == 106 || index == 107 || index == 112) {
-                            continue;
-                        }
-
-                        checksum = (if checksum & 1 > 0 { 0x80000000 } else { 0 })
-                            + (checksum >> 1)
-                            + u32::from(*byte);
-                    }
+                Region::SecondFat(fat_sector) => {
+                    self.heap.fat.read_sector_second(fat_sector, buffer);
+                    Ok(())
                 }
-
-                let buffer: &mut [u32] = bytemuck::cast_slice_mut(buffer);
-                for four_bytes in buffer.iter_mut() {
-                    *four_bytes = checksum;
+                Region::ClusterHeapAlignment => Ok(()),
+                Region::ClusterHeap(heap_sector) => {
+                    self.heap.read_sector(heap_sector, buffer);
+                    Ok(())
                 }
+                Region::ExcessSpace => Ok(()),
+            },
+        }
+    }
 
-                Ok(())
-            }
+    /// Writes that can't be applied to the emulated structures are kept in the overlay
+    pub fn write_sector(&mut self, sector_index: u64, buffer: &[u8]) -> Result<(), WriteError> {
+        assert_eq!(buffer.len(), usize::from(self.bytes_per_sector()));
 
-            // backup boot region
-            12 => {
-                // backup boot sector
-                self.read_sector(0, buffer)
-            }
-            13..=20 => {
-                // backup extended boot sectors
-                self.read_sector(sector_index - 12, buffer)
-            }
-            21 => {
-                // backup OEM parameters
-                self.read_sector(9, buffer)
-            }
-            22 => {
-                // backup reserved
-                self.read_sector(10, buffer)
-            }
-            23 => {
-                // backup boot checksum
-                self.read_sector(11, buffer)
+        let applied = match sector_index {
+            0 => {
+                let boot_sector: boot_region::BootSector = bytemuck::pod_read_unaligned(&buffer[..512]);
+                self.volume_flags = boot_sector.volume_flags;
+                self.heap.set_active_fat(boot_sector.volume_flags.active_fat());
+
+                // only volume flags and percent in use are expected to change,
+                // the sector is kept in the overlay if anything else did
+                let mut emulated = vec![0; buffer.len()];
+                self.read_boot_sector(&mut emulated);
+                emulated[106..108].copy_from_slice(&buffer[106..108]);
+                emulated[112] = buffer[112];
+                emulated == buffer
             }
 
-            _ => {
-                // FAT region
-
-                // FAT alignment
-                let fat_alignment_start_sector = 24;
-                let fat_alignment_size_sectors = u64::from(self.fat_offset) - 24;
-                let fat_alignment_end_sector =
-                    fat_alignment_start_sector + fat_alignment_size_sectors;
-                if sector_index >= fat_alignment_start_sector
-                    && sector_index < fat_alignment_end_sector
-                {
-                    return Ok(());
-                }
+            // main and backup boot regions
+            1..=23 => false,
 
-                // first FAT
-                let first_fat_start_sector = u64::from(self.fat_offset);
-                let first_fat_size_sectors = u64::from(self.fat_length);
-                let first_fat_end_sector = first_fat_start_sector + first_fat_size_sectors;
-                if sector_index >= first_fat_start_sector && sector_index < first_fat_end_sector {
-                    let fat_sector = sector_index - first_fat_start_sector;
-                    self.heap.fat.read_sector_first(fat_sector, buffer);
-                    return Ok(());
+            _ => match self.region(sector_index).ok_or(WriteError::OutOfBounds)? {
+                Region::FirstFat(fat_sector) => {
+                    self.heap.write_fat_sector(false, fat_sector, buffer);
+                    true
                 }
-
-                // second FAT
-                if self.number_of_fats > 1 {
-                    let second_fat_start_sector =
-                        u64::from(self.fat_offset) + u64::from(self.fat_length);
-                    let second_fat_size_sectors =
-                        u64::from(self.fat_length) * u64::from(self.number_of_fats - 1);
-                    let second_fat_end_sector = second_fat_start_sector + second_fat_size_sectors;
-                    if sector_index >= second_fat_start_sector
-                        && sector_index < second_fat_end_sector
-                    {
-                        let _fat_sector = sector_index - second_fat_start_sector;
-                        unimplemented!();
-                    }
+                Region::SecondFat(fat_sector) => {
+                    self.heap.write_fat_sector(true, fat_sector, buffer);
+                    true
                 }
-
-                // data region
-
-                // cluster heap alignment
-                let cluster_heap_alignment_start_sector = u64::from(self.fat_offset)
-                    + u64::from(self.fat_length) * u64::from(self.number_of_fats);
-                let cluster_heap_alignment_size_sectors =
-                    u64::from(self.cluster_heap_offset) - cluster_heap_alignment_start_sector;
-                let cluster_heap_alignment_end_sector =
-                    cluster_heap_alignment_start_sector + cluster_heap_alignment_size_sectors;
-                if sector_index >= cluster_heap_alignment_start_sector
-                    && sector_index < cluster_heap_alignment_end_sector
-                {
-                    return Ok(());
+                Region::ClusterHeap(heap_sector) => {
+                    self.heap.write_sector(heap_sector, buffer)
                 }
+                Region::FatAlignment
+                | Region::ClusterHeapAlignment
+                | Region::ExcessSpace => false,
+            },
+        };
+
+        if applied {
+            self.overlay.discard_sector(sector_index);
+        } else {
+            self.overlay.write_sector(sector_index, buffer);
+        }
 
-                // cluster heap
-                let cluster_heap_start_sector = u64::from(self.cluster_heap_offset);
-                let cluster_heap_size_sectors =
-                    u64::from(self.cluster_count) * u64::from(self.sectors_per_cluster());
-                let cluster_heap_end_sector = cluster_heap_start_sector + cluster_heap_size_sectors;
-                if sector_index >= cluster_heap_start_sector
-                    && sector_index < cluster_heap_end_sector
-                {
-                    let heap_sector = (sector_index - cluster_heap_start_sector) as u32;
-                    self.heap.read_sector(heap_sector, buffer);
-                    return Ok(());
-                }
+        Ok(())
+    }
 
-                // excess space
-                let excess_space_start_sector =
-                    u64::from(self.cluster_heap_offset) + cluster_heap_size_sectors;
-                let excess_space_size_sectors = self.volume_length - excess_space_start_sector;
-                let excess_space_end_sector = excess_space_start_sector + excess_space_size_sectors;
-                if sector_index >= excess_space_start_sector
-                    && sector_index < excess_space_end_sector
-                {
-                    return Ok(());
-                }
+    /// Figure out which region the sector past the boot regions belongs to
+    fn region(&self, sector_index: u64) -> Option<Region> {
+        // FAT region
+
+        // FAT alignment
+        let fat_alignment_start_sector = 24;
+        let fat_alignment_size_sectors = u64::from(self.fat_offset) - 24;
+        let fat_alignment_end_sector = fat_alignment_start_sector + fat_alignment_size_sectors;
+        if sector_index >= fat_alignment_start_sector && sector_index < fat_alignment_end_sector {
+            return Some(Region::FatAlignment);
+        }
 
-                Err(ReadError::OutOfBounds)
+        // first FAT
+        let first_fat_start_sector = u64::from(self.fat_offset);
+        let first_fat_size_sectors = u64::from(self.fat_length);
+        let first_fat_end_sector = first_fat_start_sector + first_fat_size_sectors;
+        if sector_index >= first_fat_start_sector && sector_index < first_fat_end_sector {
+            return Some(Region::FirstFat(sector_index - first_fat_start_sector));
+        }
+
+        // second FAT
+        if self.number_of_fats > 1 {
+            let second_fat_start_sector = u64::from(self.fat_offset) + u64::from(self.fat_length);
+            let second_fat_size_sectors =
+                u64::from(self.fat_length) * u64::from(self.number_of_fats - 1);
+            let second_fat_end_sector = second_fat_start_sector + second_fat_size_sectors;
+            if sector_index >= second_fat_start_sector && sector_index < second_fat_end_sector {
+                return Some(Region::SecondFat(sector_index - second_fat_start_sector));
             }
         }
+
+        // data region
+
+        // cluster heap alignment
+        let cluster_heap_alignment_start_sector = u64::from(self.fat_offset)
+            + u64::from(self.fat_length) * u64::from(self.number_of_fats);
+        let cluster_heap_alignment_size_sectors =
+            u64::from(self.cluster_heap_offset) - cluster_heap_alignment_start_sector;
+        let cluster_heap_alignment_end_sector =
+            cluster_heap_alignment_start_sector + cluster_heap_alignment_size_sectors;
+        if sector_index >= cluster_heap_alignment_start_sector
+            && sector_index < cluster_heap_alignment_end_sector
+        {
+            return Some(Region::ClusterHeapAlignment);
+        }
+
+        // cluster heap
+        let cluster_heap_start_sector = u64::from(self.cluster_heap_offset);
+        let cluster_heap_size_sectors =
+            u64::from(self.cluster_count) * u64::from(self.sectors_per_cluster());
+        let cluster_heap_end_sector = cluster_heap_start_sector + cluster_heap_size_sectors;
+        if sector_index >= cluster_heap_start_sector && sector_index < cluster_heap_end_sector {
+            return Some(Region::ClusterHeap(sector_index - cluster_heap_start_sector));
+        }
+
+        // excess space
+        let excess_space_start_sector =
+            u64::from(self.cluster_heap_offset) + cluster_heap_size_sectors;
+        let excess_space_size_sectors = self.volume_length - excess_space_start_sector;
+        let excess_space_end_sector = excess_space_start_sector + excess_space_size_sectors;
+        if sector_index >= excess_space_start_sector && sector_index < excess_space_end_sector {
+            return Some(Region::ExcessSpace);
+        }
+
+        None
     }
 
-    /// Add directory into specified root directory, returns first cluster of inserted directory
+    /// Add directory into specified root directory, returns first cluster of inserted directory.
+    /// Timestamps of the directory are left zeroed.
     pub fn add_directory(&mut self, root_cluster: u32, name: &str) -> Result<u32, FileDirectoryEntryError> {
         self.heap.add_directory(root_cluster, name)
     }
 
+    /// Add directory into specified root directory with explicit times, returns first cluster of inserted directory
+    pub fn add_directory_with_times(&mut self, root_cluster: u32, name: &str, times: &EntryTimes) -> Result<u32, FileDirectoryEntryError> {
+        self.heap.add_directory_with_times(root_cluster, name, times)
+    }
+
     pub fn add_directory_in_root(&mut self, name: &str) -> Result<u32, FileDirectoryEntryError> {
         self.add_directory(self.root_directory_cluster(), name)
     }
 
+    /// Label is at most 11 UTF-16 code units, empty label removes it
+    pub fn set_volume_label(&mut self, label: &str) -> Result<(), VolumeLabelError> {
+        let entry = VolumeLabelDirectoryEntry::new(label)?;
+        self.heap.set_volume_label(entry)
+    }
+
+    pub fn set_boot_code(&mut self, boot_code: &[u8; 390]) {
+        self.boot_code = *boot_code;
+        self.boot_region = None;
+    }
+
+    /// `index` of the extended boot sector is 0..8,
+    /// code is at most `bytes_per_sector - 4` bytes and is padded with zeroes
+    pub fn set_extended_boot_code(&mut self, index: usize, extended_boot_code: &[u8]) -> Result<(), BootRegionError> {
+        if index >= self.extended_boot_code.len() {
+            return Err(BootRegionError::InvalidExtendedBootSector);
+        }
+        if extended_boot_code.len() > usize::from(self.bytes_per_sector()) - 4 {
+            return Err(BootRegionError::ExtendedBootCodeTooLong);
+        }
+
+        self.extended_boot_code[index] = extended_boot_code.to_vec();
+        self.boot_region = None;
+        Ok(())
+    }
+
+    /// Up to 10 parameters, the rest are null parameters
+    pub fn set_oem_parameters(&mut self, oem_parameters: &[OemParameters]) -> Result<(), BootRegionError> {
+        if oem_parameters.len() > 10 {
+            return Err(BootRegionError::TooManyOemParameters);
+        }
+
+        self.oem_parameters = Some(oem_parameters.to_vec());
+        self.boot_region = None;
+        Ok(())
+    }
+
+    /// Sector of the disk the volume starts at
+    pub(crate) fn set_partition_offset(&mut self, partition_offset: u64) {
+        self.partition_offset = partition_offset;
+        self.boot_region = None;
+    }
+
+    /// Files mapped afterwards are opened according to the mode, files mapped earlier keep theirs
+    pub fn set_mapping_mode(&mut self, mapping_mode: MappingMode) {
+        self.heap.set_mapping_mode(mapping_mode);
+    }
+
+    /// Clusters allocated afterwards are picked according to the strategy, [`AllocationStrategy::FirstFit`] by default
+    pub fn set_allocation_strategy(&mut self, allocation_strategy: AllocationStrategy) {
+        self.heap.set_allocation_strategy(allocation_strategy);
+    }
+
+    /// Keep guest writes to files mapped with [`MappingMode::CopyOnWrite`] in a delta store at `path`.
+    /// Only overwrites of the mapped file data are kept, length changes, clusters the guest allocates
+    /// and changes to directories are not.
+    ///
+    /// Files have to be mapped first. If the delta store already exists, it is reapplied,
+    /// unless the files, their lengths, modification times or clusters changed since it was created.
+    pub fn open_delta_store<P>(&mut self, path: P) -> Result<(), DeltaStoreError>
+    where
+        P: AsRef<Path>,
+    {
+        self.heap.open_delta_store(path)
+    }
+
+    /// Drop everything written to the delta store
+    pub fn discard_delta_store(&mut self) -> io::Result<()> {
+        self.heap.discard_delta_store()
+    }
+
     /// Map file into specified directory, returns first cluster of inserted file
     pub fn map_file<P>(&mut self, dir_cluster: u32, path: P) -> Result<u32, FileDirectoryEntryError>
     where
@@ -324,6 +596,127 @@ impl VirtualExFatBlockDevice {
         self.heap.map_file_with_name(dir_cluster, path, name)
     }
 
+    /// Map contents of a host directory recursively into specified directory
+    pub fn map_directory<P>(
+        &mut self,
+        dir_cluster: u32,
+        host_path: P,
+        options: &MapDirectoryOptions,
+    ) -> Result<MapDirectoryReport, FileDirectoryEntryError>
+    where
+        P: AsRef<Path>,
+    {
+        self.heap.map_directory(dir_cluster, host_path, options)
+    }
+
+    /// Remove file or empty directory from specified directory, its clusters are freed
+    pub fn remove(&mut self, dir_cluster: u32, name: &str) -> Result<(), FileDirectoryEntryError> {
+        let released = self.heap.remove(dir_cluster, name)?;
+        self.discard_clusters(&released);
+        Ok(())
+    }
+
+    /// Remove directory from specified directory along with everything in it
+    pub fn remove_dir_all(&mut self, dir_cluster: u32, name: &str) -> Result<(), FileDirectoryEntryError> {
+        let released = self.heap.remove_dir_all(dir_cluster, name)?;
+        self.discard_clusters(&released);
+        Ok(())
+    }
+
+    /// Rename file or directory, moving it from `dir_cluster` into `new_dir_cluster`
+    pub fn rename(&mut self, dir_cluster: u32, old_name: &str, new_dir_cluster: u32, new_name: &str) -> Result<(), FileDirectoryEntryError> {
+        self.heap.rename(dir_cluster, old_name, new_dir_cluster, new_name)
+    }
+
+    /// Re-read the length and times of the mapped host file after it changed on the host
+    pub fn refresh_file(&mut self, dir_cluster: u32, name: &str) -> Result<(), FileDirectoryEntryError> {
+        let released = self.heap.refresh_file(dir_cluster, name)?;
+        self.discard_clusters(&released);
+        Ok(())
+    }
+
+    /// Map another host file in place of the mapped file, what the guest wrote to the file is dropped
+    pub fn replace_file<P>(&mut self, dir_cluster: u32, name: &str, path: P) -> Result<(), FileDirectoryEntryError>
+    where
+        P: AsRef<Path>,
+    {
+        let replaced = self.heap.replace_file(dir_cluster, name, path)?;
+        self.discard_clusters(&replaced);
+        Ok(())
+    }
+
+    /// Guest writes kept in the overlay would otherwise show up in whatever reuses the clusters
+    fn discard_clusters(&mut self, clusters: &[u32]) {
+        let clusters: HashSet<u32> = clusters.iter().cloned().collect();
+        let cluster_heap_offset = u64::from(self.cluster_heap_offset);
+        let sectors_per_cluster = u64::from(self.sectors_per_cluster());
+
+        self.overlay.discard_sectors(|sector_index| {
+            sector_index
+                .checked_sub(cluster_heap_offset)
+                .and_then(|heap_sector| u32::try_from(heap_sector / sectors_per_cluster).ok())
+                .is_some_and(|cluster_index| clusters.contains(&cluster_index))
+        });
+    }
+
+    /// Changes the guest made to the directory tree since the last call
+    pub fn take_directory_events(&mut self) -> Vec<DirectoryEvent> {
+        self.heap.take_directory_events()
+    }
+
+    /// Write every file and directory on the volume into `target` directory,
+    /// reports what changed compared to the files and directories the host added.
+    ///
+    /// This is not a sync, files already in `target` are overwritten but nothing is removed from it,
+    /// entries reported as deleted stay where they are.
+    pub fn export<P>(&mut self, target: P) -> io::Result<ExportReport>
+    where
+        P: AsRef<Path>,
+    {
+        let target = target.as_ref();
+        fs::create_dir_all(target)?;
+
+        let tree = self.heap.tree();
+        let mut buffer = vec![0; usize::from(self.bytes_per_sector())];
+
+        for entry in tree.iter() {
+            let path = target.join(&entry.path);
+            if entry.directory {
+                fs::create_dir_all(&path)?;
+                continue;
+            }
+
+            let mut file = File::create(&path)?;
+            let mut remaining = entry.valid_data_length;
+            let sectors_per_cluster = u64::from(self.sectors_per_cluster());
+
+            'clusters: for &cluster_index in entry.clusters.iter() {
+                let first_sector =
+                    u64::from(self.cluster_heap_offset) + u64::from(cluster_index) * sectors_per_cluster;
+
+                for sector_index in first_sector..first_sector + sectors_per_cluster {
+                    if remaining == 0 {
+                        break 'clusters;
+                    }
+
+                    // goes through the overlay, so writes kept there are exported as well
+                    buffer.fill(0);
+                    self.read_sector(sector_index, &mut buffer)
+                        .map_err(|_| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+                    let length = u64::min(remaining, buffer.len() as u64);
+                    file.write_all(&buffer[..length as usize])?;
+                    remaining -= length;
+                }
+            }
+
+            // past valid data length reads as zeroes, the length written by the guest is capped at the allocation
+            let allocated_length = entry.clusters.len() as u64 * self.bytes_per_cluster();
+            file.set_len(entry.data_length.min(allocated_length))?;
+        }
+
+        Ok(self.heap.export_report(&tree))
+    }
+
     pub fn bytes_per_sector(&self) -> u16 {
         // 512 - 4096
         1 << self.bytes_per_sector_shift
@@ -338,6 +731,15 @@ impl VirtualExFatBlockDevice {
         u64::from(self.bytes_per_sector()) * u64::from(self.sectors_per_cluster())
     }
 
+    pub fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
+    /// As last written by the guest to the main boot sector
+    pub fn volume_flags(&self) -> VolumeFlags {
+        self.volume_flags.with_active_fat(self.heap.fat.is_second_active())
+    }
+
     /// Size of exFAT volume in sectors
     pub fn volume_length(&self) -> u64 {
         self.volume_length
@@ -432,6 +834,53 @@ impl Read for VirtualExFatBlockDevice {
     }
 }
 
+impl Write for VirtualExFatBlockDevice {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let bytes_per_sector = usize::from(self.bytes_per_sector());
+        let bytes_requested = buffer.len();
+        let mut bytes_written = 0;
+
+        while bytes_written < bytes_requested {
+            let offset_in_sector = self.current_offset_in_sector as usize;
+            let bytes_in_this_sector = bytes_per_sector - offset_in_sector;
+            let to_write = usize::min(bytes_in_this_sector, bytes_requested - bytes_written);
+
+            // partially written sectors have to be read first
+            let mut sector = vec![0; bytes_per_sector];
+            if to_write < bytes_per_sector {
+                if let Err(err) = self.read_sector(self.current_sector, &mut sector) {
+                    match err {
+                        ReadError::OutOfBounds => break,
+                    }
+                }
+            }
+
+            sector[offset_in_sector..offset_in_sector + to_write]
+                .copy_from_slice(&buffer[bytes_written..bytes_written + to_write]);
+
+            if let Err(err) = self.write_sector(self.current_sector, &sector) {
+                match err {
+                    WriteError::OutOfBounds => break,
+                }
+            }
+
+            self.current_offset_in_sector += to_write as u64;
+
+            let whole_sectors = self.current_offset_in_sector / bytes_per_sector as u64;
+            self.current_sector += whole_sectors;
+            self.current_offset_in_sector -= whole_sectors * bytes_per_sector as u64;
+
+            bytes_written += to_write;
+        }
+
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.heap.flush()
+    }
+}
+
 #[test]
 fn read_sector() {
     use crate::data_region::volume_label::VolumeLabelDirectoryEntry;
@@ -487,6 +936,220 @@ fn read_sector() {
     assert_eq!(ret, Err(ReadError::OutOfBounds));
 }
 
+#[test]
+fn large_volume() {
+    // 32 MiB clusters, largest cluster count exFAT allows, a little bit under 128 PiB
+    let mut vexfat = VirtualExFatBlockDevice::new(9, 16, MAX_CLUSTER_COUNT - 1).unwrap();
+    assert_eq!(vexfat.volume_size(), vexfat.volume_length * 512);
+    assert!(vexfat.volume_size() > 127 << 50);
+
+    // last sector of the last cluster, past 2^32 heap sectors
+    let last_sector = vexfat.volume_length - 1;
+
+    let data = [0xAB; 512];
+    vexfat.write_sector(last_sector, &data).unwrap();
+    assert!(!vexfat.overlay.read_sector(last_sector, &mut [0; 512]));
+
+    let mut buffer = [0; 512];
+    vexfat.read_sector(last_sector, &mut buffer).unwrap();
+    assert_eq!(buffer, data);
+
+    // same sector with the heap sector truncated to 32 bits is left untouched
+    let heap_sector = last_sector - u64::from(vexfat.cluster_heap_offset);
+    let truncated = u64::from(vexfat.cluster_heap_offset) + (heap_sector & u64::from(u32::MAX));
+    let mut buffer = [0; 512];
+    vexfat.read_sector(truncated, &mut buffer).unwrap();
+    assert_ne!(buffer, data);
+}
+
+#[test]
+fn second_fat() {
+    let mut vexfat = VexfatBuilder::with_volume_size(64 << 20)
+        .number_of_fats(2)
+        .volume_serial_number(0)
+        .build()
+        .unwrap();
+
+    let mut boot_sector = [0; 512];
+    vexfat.read_sector(0, &mut boot_sector).unwrap();
+    assert_eq!(boot_sector[110], 2); // number_of_fats
+    assert_eq!(&boot_sector[106..108], &[0, 0]); // volume_flags
+    assert_eq!(
+        u64::from(vexfat.cluster_heap_offset),
+        u64::from(vexfat.fat_offset) + 2 * u64::from(vexfat.fat_length)
+    );
+
+    // both FATs describe the same chains
+    let mut first_fat = [0; 512];
+    vexfat.read_sector(vexfat.fat_offset.into(), &mut first_fat).unwrap();
+    let mut second_fat = [0; 512];
+    vexfat
+        .read_sector(u64::from(vexfat.fat_offset + vexfat.fat_length), &mut second_fat)
+        .unwrap();
+    assert_eq!(first_fat, second_fat);
+
+    // second allocation bitmap entry follows the first one
+    let root_directory_sector = u64::from(vexfat.cluster_heap_offset)
+        + u64::from(vexfat.root_directory_cluster() * vexfat.sectors_per_cluster());
+    let mut buffer = [0; 512];
+    vexfat.read_sector(root_directory_sector, &mut buffer).unwrap();
+    assert_eq!(&buffer[32..34], &[0x81, 0]);
+    assert_eq!(&buffer[64..66], &[0x81, 1]);
+    assert_eq!(buffer[96], 0x82);
+
+    // guest allocates a cluster in the first allocation bitmap only
+    let cluster_index = 1000;
+    let mut first_bitmap = [0; 512];
+    vexfat.read_sector(vexfat.cluster_heap_offset.into(), &mut first_bitmap).unwrap();
+    first_bitmap[cluster_index / 8] |= 1 << (cluster_index % 8);
+    vexfat.write_sector(vexfat.cluster_heap_offset.into(), &first_bitmap).unwrap();
+
+    let cluster_sector =
+        u64::from(vexfat.cluster_heap_offset) + cluster_index as u64 * u64::from(vexfat.sectors_per_cluster());
+    vexfat.write_sector(cluster_sector, &[0xAB; 512]).unwrap();
+
+    let second_bitmap_sector = u64::from(vexfat.cluster_heap_offset + vexfat.sectors_per_cluster());
+    let mut second_bitmap = [0; 512];
+    vexfat.read_sector(second_bitmap_sector, &mut second_bitmap).unwrap();
+    assert_eq!(second_bitmap[cluster_index / 8], 0);
+
+    // switching to the second FAT drops the cluster, as it is free there
+    boot_sector[106] |= 1; // ActiveFat
+    vexfat.write_sector(0, &boot_sector).unwrap();
+    assert!(vexfat.heap.fat.is_second_active());
+
+    let mut buffer = [0; 512];
+    vexfat.read_sector(cluster_sector, &mut buffer).unwrap();
+    assert_eq!(buffer, [0; 512]);
+
+    let mut buffer = [0; 512];
+    vexfat.read_sector(0, &mut buffer).unwrap();
+    assert_eq!(&buffer[106..108], &[1, 0]);
+}
+
+#[test]
+fn boot_region() {
+    for (bytes_per_sector_shift, sectors_per_cluster_shift) in [(9, 3), (12, 0)] {
+        let mut vexfat =
+            VirtualExFatBlockDevice::new_with_serial_number(bytes_per_sector_shift, sectors_per_cluster_shift, 512, 0)
+                .unwrap();
+        let bytes_per_sector = usize::from(vexfat.bytes_per_sector());
+
+        let mut main = vec![0; 12 * bytes_per_sector];
+        let mut backup = vec![0; 12 * bytes_per_sector];
+        for (sector_index, sector) in main.chunks_exact_mut(bytes_per_sector).enumerate() {
+            vexfat.read_sector(sector_index as u64, sector).unwrap();
+        }
+        for (sector_index, sector) in backup.chunks_exact_mut(bytes_per_sector).enumerate() {
+            vexfat.read_sector(sector_index as u64 + 12, sector).unwrap();
+        }
+        assert_eq!(main, backup);
+        assert!(vexfat.boot_region.is_some());
+
+        let checksum = boot_region::boot_checksum(&main[..11 * bytes_per_sector]);
+        for four_bytes in main[11 * bytes_per_sector..].chunks_exact(4) {
+            assert_eq!(four_bytes, checksum.to_le_bytes());
+        }
+    }
+}
+
+#[test]
+fn boot_code() {
+    let mut vexfat = VirtualExFatBlockDevice::new(9, 3, 512).unwrap();
+
+    let mut checksum = [0; 512];
+    vexfat.read_sector(11, &mut checksum).unwrap();
+
+    vexfat.set_boot_code(&[0xF4; 390]);
+    vexfat.set_extended_boot_code(0, &[0x90; 508]).unwrap();
+    vexfat.set_extended_boot_code(7, &[0xCC]).unwrap();
+    assert_eq!(vexfat.set_extended_boot_code(8, &[]), Err(BootRegionError::InvalidExtendedBootSector));
+    assert_eq!(vexfat.set_extended_boot_code(1, &[0; 509]), Err(BootRegionError::ExtendedBootCodeTooLong));
+
+    let flash_parameters = FlashParameters {
+        erase_block_size: 128 << 10,
+        ..Default::default()
+    };
+    vexfat.set_oem_parameters(&[flash_parameters.into()]).unwrap();
+    assert_eq!(
+        vexfat.set_oem_parameters(&[flash_parameters.into(); 11]),
+        Err(BootRegionError::TooManyOemParameters)
+    );
+
+    let mut buffer = [0; 512];
+    vexfat.read_sector(0, &mut buffer).unwrap();
+    assert_eq!(&buffer[120..510], &[0xF4; 390]);
+    assert_eq!(&buffer[510..], &[0x55, 0xAA]);
+
+    let mut buffer = [0; 512];
+    vexfat.read_sector(1, &mut buffer).unwrap();
+    assert_eq!(&buffer[..508], &[0x90; 508]);
+    assert_eq!(&buffer[508..], &[0x00, 0x00, 0x55, 0xAA]);
+
+    let mut buffer = [0; 512];
+    vexfat.read_sector(20, &mut buffer).unwrap();
+    assert_eq!(buffer[0], 0xCC);
+    assert_eq!(&buffer[1..508], &[0; 507]);
+
+    let mut buffer = [0; 512];
+    vexfat.read_sector(9, &mut buffer).unwrap();
+    assert_eq!(&buffer[..16], &FlashParameters::GUID);
+    assert_eq!(&buffer[16..20], &(128u32 << 10).to_le_bytes());
+    assert_eq!(&buffer[48..], &[0; 464]);
+
+    // checksum covers them
+    let mut buffer = [0; 512];
+    vexfat.read_sector(23, &mut buffer).unwrap();
+    assert_ne!(buffer, checksum);
+}
+
+#[test]
+fn boot_sector_flags() {
+    let mut vexfat = VirtualExFatBlockDevice::new(9, 3, 512).unwrap();
+
+    let mut boot_sector = [0; 512];
+    vexfat.read_sector(0, &mut boot_sector).unwrap();
+    assert_eq!(boot_sector[112], 0); // percent_in_use, 4 clusters out of 512
+
+    let mut checksum = [0; 512];
+    vexfat.read_sector(11, &mut checksum).unwrap();
+
+    // guest allocates half of the clusters
+    let mut bitmap = [0; 512];
+    bitmap[..32].fill(0xFF);
+    vexfat.write_sector(vexfat.cluster_heap_offset.into(), &bitmap).unwrap();
+    let mut buffer = [0; 512];
+    vexfat.read_sector(0, &mut buffer).unwrap();
+    assert_eq!(buffer[112], 50);
+
+    // volume flags are persisted, percent in use is still computed
+    boot_sector[106] = 0b0010; // VolumeDirty
+    boot_sector[112] = 10;
+    vexfat.write_sector(0, &boot_sector).unwrap();
+    assert!(vexfat.volume_flags().volume_dirty());
+    assert!(!vexfat.overlay.read_sector(0, &mut [0; 512]));
+
+    let mut buffer = [0; 512];
+    vexfat.read_sector(0, &mut buffer).unwrap();
+    assert_eq!(buffer[106], 0b0010);
+    assert_eq!(buffer[112], 50);
+
+    // neither is part of the checksum
+    let mut buffer = [0; 512];
+    vexfat.read_sector(11, &mut buffer).unwrap();
+    assert_eq!(buffer, checksum);
+
+    // anything else is kept in the overlay
+    boot_sector[106] = 0;
+    boot_sector[120] = 0xF4;
+    vexfat.write_sector(0, &boot_sector).unwrap();
+    assert!(!vexfat.volume_flags().volume_dirty());
+
+    let mut buffer = [0; 512];
+    vexfat.read_sector(0, &mut buffer).unwrap();
+    assert_eq!(buffer, boot_sector);
+}
+
 #[test]
 fn read() {
     let mut vexfat = VirtualExFatBlockDevice::new_with_serial_number(9, 3, 512, 0).unwrap();
@@ -567,3 +1230,257 @@ fn file() {
     vexfat.read_exact(&mut buffer).unwrap();
     assert_eq!(cargo_manifest, buffer);
 }
+
+#[test]
+fn write_sector() {
+    let mut vexfat = VirtualExFatBlockDevice::new_with_serial_number(9, 3, 512, 0).unwrap();
+
+    // FAT
+    let mut buffer = [0; 512];
+    vexfat.read_sector(vexfat.fat_offset.into(), &mut buffer).unwrap();
+    buffer[24..28].copy_from_slice(&0xFFFFFFFFu32.to_le_bytes());
+    vexfat.write_sector(vexfat.fat_offset.into(), &buffer).unwrap();
+    let mut read_back = [0; 512];
+    vexfat.read_sector(vexfat.fat_offset.into(), &mut read_back).unwrap();
+    assert_eq!(buffer, read_back);
+
+    // allocation bitmap
+    let mut buffer = [0; 512];
+    buffer[0] = 0b00111111;
+    vexfat.write_sector(vexfat.cluster_heap_offset.into(), &buffer).unwrap();
+    let mut read_back = [0; 512];
+    vexfat.read_sector(vexfat.cluster_heap_offset.into(), &mut read_back).unwrap();
+    assert_eq!(buffer, read_back);
+
+    // boot sector ends up in the overlay, cluster allocated by the guest in the heap
+    let unallocated_sector = u64::from(vexfat.cluster_heap_offset) + 5 * 8;
+    for sector in [0, unallocated_sector] {
+        vexfat.write_sector(sector, &[0xAB; 512]).unwrap();
+        let mut read_back = [0; 512];
+        vexfat.read_sector(sector, &mut read_back).unwrap();
+        assert_eq!(read_back, [0xAB; 512]);
+    }
+
+    // out of bounds
+    let ret = vexfat.write_sector(vexfat.volume_length(), &[0; 512]);
+    assert_eq!(ret, Err(WriteError::OutOfBounds));
+}
+
+#[test]
+fn write() {
+    let mut vexfat = VirtualExFatBlockDevice::new_with_serial_number(9, 3, 512, 0).unwrap();
+    let unallocated_offset = (u64::from(vexfat.cluster_heap_offset) + 5 * 8) * 512;
+
+    // straddle the sector boundary
+    vexfat.seek(SeekFrom::Start(unallocated_offset + 510)).unwrap();
+    vexfat.write_all(&[1, 2, 3, 4]).unwrap();
+    assert_eq!(vexfat.current_sector, (unallocated_offset / 512) + 1);
+    assert_eq!(vexfat.current_offset_in_sector, 2);
+
+    vexfat.seek(SeekFrom::Start(unallocated_offset + 508)).unwrap();
+    let mut buffer = [0; 8];
+    vexfat.read_exact(&mut buffer).unwrap();
+    assert_eq!(buffer, [0, 0, 1, 2, 3, 4, 0, 0]);
+
+    // writing past the end of the volume
+    vexfat.seek(SeekFrom::End(-1)).unwrap();
+    assert_eq!(vexfat.write(&[1, 2]).unwrap(), 1);
+    assert_eq!(vexfat.write(&[1, 2]).unwrap(), 0);
+}
+
+#[test]
+fn export() {
+    let cargo_manifest_path = format!("{}/Cargo.toml", env!("CARGO_MANIFEST_DIR"));
+    let cargo_manifest = std::fs::read(&cargo_manifest_path).unwrap();
+    let readme_path = format!("{}/README.md", env!("CARGO_MANIFEST_DIR"));
+
+    let mut vexfat = VirtualExFatBlockDevice::new_with_serial_number(9, 3, 512, 0).unwrap();
+    let dir_cluster = vexfat.add_directory_in_root("dir").unwrap();
+    let file_cluster = vexfat.map_file(dir_cluster, &cargo_manifest_path).unwrap();
+    vexfat.map_file(vexfat.root_directory_cluster(), &readme_path).unwrap();
+
+    // guest overwrites the start of the manifest, it is kept in the overlay
+    let file_sector = u64::from(vexfat.cluster_heap_offset) + u64::from(file_cluster) * 8;
+    vexfat.write_sector(file_sector, &[0xAB; 512]).unwrap();
+
+    // guest deletes the readme and creates a new file
+    let root_sector = u64::from(vexfat.cluster_heap_offset) + u64::from(vexfat.root_directory_cluster()) * 8;
+    let mut buffer = [0; 512];
+    vexfat.read_sector(root_sector, &mut buffer).unwrap();
+    let readme_entry = 6 * 32; // after the system entries and the directory
+    assert_eq!(buffer[readme_entry], 0x85);
+    buffer[readme_entry] &= 0x7F;
+    let new_entry_set = heap::guest_entry_set("new", 20, false);
+    buffer[9 * 32..9 * 32 + new_entry_set.len()].copy_from_slice(&new_entry_set);
+    vexfat.write_sector(root_sector, &buffer).unwrap();
+    let new_file_sector = u64::from(vexfat.cluster_heap_offset) + 20 * 8;
+    vexfat.write_sector(new_file_sector, &[0xCD; 512]).unwrap();
+
+    // file claiming to be far larger than its allocation
+    let mut huge_entry_set = heap::guest_entry_set("huge", 22, false);
+    let mut stream_extension: data_region::file::StreamExtensionDirectoryEntry =
+        bytemuck::pod_read_unaligned(&huge_entry_set[32..64]);
+    stream_extension.data_length = 1 << 50;
+    huge_entry_set[32..64].copy_from_slice(bytemuck::bytes_of(&stream_extension));
+    let checksum = huge_entry_set
+        .chunks_exact(32)
+        .enumerate()
+        .fold(0, |checksum, (index, entry)| data_region::file::entry_checksum(checksum, entry, index == 0));
+    huge_entry_set[2..4].copy_from_slice(&checksum.to_le_bytes());
+    buffer[12 * 32..12 * 32 + huge_entry_set.len()].copy_from_slice(&huge_entry_set);
+    vexfat.write_sector(root_sector, &buffer).unwrap();
+
+    let target = utils::TempPath::new("export");
+    std::fs::create_dir_all(&target).unwrap();
+    std::fs::write(target.join("README.md"), []).unwrap();
+    let report = vexfat.export(&target).unwrap();
+    assert_eq!(
+        report,
+        ExportReport {
+            new: vec!["huge".into(), "new".into()],
+            modified: vec![Path::new("dir").join("Cargo.toml")],
+            deleted: vec!["README.md".into()],
+        }
+    );
+
+    let mut expected_manifest = cargo_manifest.clone();
+    expected_manifest.iter_mut().take(512).for_each(|byte| *byte = 0xAB);
+    assert_eq!(std::fs::read(target.join("dir").join("Cargo.toml")).unwrap(), expected_manifest);
+    let mut expected_new = vec![0; 4096];
+    expected_new[..512].fill(0xCD);
+    assert_eq!(std::fs::read(target.join("new")).unwrap(), expected_new);
+    // contiguous allocation ends with the cluster heap
+    assert_eq!(std::fs::metadata(target.join("huge")).unwrap().len(), (512 - 22) * 4096);
+
+    // deleted entries already in the target are left alone
+    assert!(target.join("README.md").exists());
+}
+
+#[test]
+fn remove() {
+    let cargo_manifest_path = format!("{}/Cargo.toml", env!("CARGO_MANIFEST_DIR"));
+    let readme_path = format!("{}/README.md", env!("CARGO_MANIFEST_DIR"));
+    let readme = std::fs::read(&readme_path).unwrap();
+
+    let mut vexfat = VirtualExFatBlockDevice::new_with_serial_number(9, 3, 512, 0).unwrap();
+    let root_cluster = vexfat.root_directory_cluster();
+    let file_cluster = vexfat.map_file(root_cluster, &cargo_manifest_path).unwrap();
+
+    // guest overwrites the read-only file, it is kept in the overlay
+    let file_sector = u64::from(vexfat.cluster_heap_offset) + u64::from(file_cluster) * 8;
+    vexfat.write_sector(file_sector, &[0xAB; 512]).unwrap();
+
+    vexfat.remove(root_cluster, "cargo.toml").unwrap();
+    assert_eq!(vexfat.remove(root_cluster, "Cargo.toml"), Err(FileDirectoryEntryError::NotFound));
+
+    // the cluster is reused, the overlay does not shadow the new file
+    assert_eq!(vexfat.map_file(root_cluster, &readme_path), Ok(file_cluster));
+    let mut buffer = [0; 512];
+    vexfat.read_sector(file_sector, &mut buffer).unwrap();
+    assert_eq!(buffer[..64], readme[..64]);
+}
+
+#[test]
+fn volume_label() {
+    let mut vexfat = VirtualExFatBlockDevice::new_with_serial_number(9, 3, 512, 0).unwrap();
+    let root_sector = u64::from(vexfat.cluster_heap_offset) + u64::from(vexfat.root_directory_cluster()) * 8;
+
+    vexfat.set_volume_label("USB DRIVE").unwrap();
+    let mut buffer = [0; 512];
+    vexfat.read_sector(root_sector, &mut buffer).unwrap();
+    assert_eq!(buffer[0..2], [0x83, 9]);
+    let label: Vec<u16> = "USB DRIVE".encode_utf16().collect();
+    assert_eq!(buffer[2..20], *bytemuck::cast_slice::<u16, u8>(&label));
+
+    // guest marks the label as not in use, it can still be set again
+    buffer[0] = 0x03;
+    vexfat.write_sector(root_sector, &buffer).unwrap();
+    vexfat.set_volume_label("STICK").unwrap();
+    let mut buffer = [0; 512];
+    vexfat.read_sector(root_sector, &mut buffer).unwrap();
+    assert_eq!(buffer[0..2], [0x83, 5]);
+    assert_eq!(buffer[12..24], [0; 12]);
+
+    // guest deletes the label entry, the freed entry is taken
+    buffer[0] = 0x05;
+    vexfat.write_sector(root_sector, &buffer).unwrap();
+    vexfat.set_volume_label("AGAIN").unwrap();
+    vexfat.read_sector(root_sector, &mut buffer).unwrap();
+    assert_eq!(buffer[0..2], [0x83, 5]);
+
+    // guest puts another entry in its place, a new label entry is added
+    buffer[0..32].copy_from_slice(&[0xA0; 32]);
+    vexfat.write_sector(root_sector, &buffer).unwrap();
+    vexfat.set_volume_label("ADDED").unwrap();
+    vexfat.read_sector(root_sector, &mut buffer).unwrap();
+    assert_eq!(buffer[0], 0xA0);
+    let label_entry = buffer.chunks(32).position(|entry| entry[0] == 0x83).unwrap();
+    assert!(label_entry > 0);
+    assert_eq!(buffer[label_entry * 32 + 1], 5);
+
+    assert_eq!(vexfat.set_volume_label("LONGER THAN 11"), Err(VolumeLabelError::TooLong));
+}
+
+#[cfg(test)]
+use proptest::prelude::*;
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn geometry_never_panics(
+        bytes_per_sector_shift in 0u8..16,
+        sectors_per_cluster_shift in 0u8..32,
+        cluster_count in 0u32..(1 << 20),
+    ) {
+        let ret = VirtualExFatBlockDevice::new_with_serial_number(bytes_per_sector_shift, sectors_per_cluster_shift, cluster_count, 0);
+        let valid_shifts = (9..=12).contains(&bytes_per_sector_shift)
+            && sectors_per_cluster_shift <= 25 - bytes_per_sector_shift;
+        match ret {
+            Ok(_) => prop_assert!(valid_shifts && cluster_count.is_multiple_of(2)),
+            Err(VexfatError::InvalidBytesPerSectorShift) => prop_assert!(!(9..=12).contains(&bytes_per_sector_shift)),
+            Err(VexfatError::InvalidSectorsPerClusterShift) => prop_assert!(!valid_shifts),
+            Err(VexfatError::InvalidClusterCount) => prop_assert!(!cluster_count.is_multiple_of(2)),
+            Err(VexfatError::VolumeTooSmall) => prop_assert!(valid_shifts),
+            Err(err) => prop_assert!(false, "unexpected {err:?}"),
+        }
+    }
+
+    #[test]
+    fn valid_geometry(
+        (bytes_per_sector_shift, sectors_per_cluster_shift) in (9u8..=12).prop_flat_map(|shift| (Just(shift), 0..=25 - shift)),
+        half_cluster_count in 0u32..(1 << 19),
+    ) {
+        let cluster_count = half_cluster_count * 2;
+        let mut vexfat = match VirtualExFatBlockDevice::new_with_serial_number(bytes_per_sector_shift, sectors_per_cluster_shift, cluster_count, 0) {
+            Ok(vexfat) => vexfat,
+            Err(VexfatError::VolumeTooSmall) => {
+                let bytes_per_cluster = 1u64 << (bytes_per_sector_shift + sectors_per_cluster_shift);
+                let too_few_clusters = ClusterHeap::system_clusters_count(bytes_per_cluster as u32, cluster_count, 1) > cluster_count;
+                prop_assert!(too_few_clusters || u64::from(cluster_count) * bytes_per_cluster < 1 << 20);
+                return Ok(());
+            }
+            Err(err) => return Err(TestCaseError::fail(format!("unexpected {err:?}"))),
+        };
+
+        let sectors_per_cluster = 1u64 << sectors_per_cluster_shift;
+        prop_assert_eq!(
+            vexfat.volume_length(),
+            u64::from(vexfat.cluster_heap_offset) + u64::from(cluster_count) * sectors_per_cluster
+        );
+        prop_assert!(vexfat.volume_size() >= 1 << 20);
+        prop_assert!(u64::from(vexfat.fat_length) * u64::from(vexfat.bytes_per_sector()) >= (u64::from(cluster_count) + 2) * 4);
+        prop_assert!(vexfat.fat_offset + vexfat.fat_length <= vexfat.cluster_heap_offset);
+        prop_assert!(vexfat.first_cluster_of_root_directory <= cluster_count + 1);
+
+        // boot sector reflects the geometry, checksum and the ends of the volume can be read
+        let mut buffer = vec![0; usize::from(vexfat.bytes_per_sector())];
+        vexfat.read_sector(0, &mut buffer).unwrap();
+        let boot_sector: &boot_region::BootSector = bytemuck::from_bytes(&buffer[..512]);
+        prop_assert_eq!({ boot_sector.volume_length }, vexfat.volume_length());
+        prop_assert_eq!({ boot_sector.cluster_count }, cluster_count);
+        for sector in [11, 23, vexfat.volume_length() - 1] {
+            buffer.fill(0);
+            prop_assert!(vexfat.read_sector(sector, &mut buffer).is_ok());
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! Server side of the NBD fixed newstyle protocol,
+//! see <https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md>
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const NBDMAGIC: u64 = 0x4e42_444d_4147_4943;
+const IHAVEOPT: u64 = 0x4948_4156_454F_5054;
+const OPTION_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+const FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+const FLAG_HAS_FLAGS: u16 = 1 << 0;
+const FLAG_READ_ONLY: u16 = 1 << 1;
+const FLAG_SEND_FLUSH: u16 = 1 << 2;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_LIST: u32 = 3;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNSUP: u32 = (1 << 31) | 1;
+const REP_ERR_INVALID: u32 = (1 << 31) | 3;
+
+const INFO_EXPORT: u16 = 0;
+const INFO_BLOCK_SIZE: u16 = 3;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+
+const EPERM: u32 = 1;
+const EIO: u32 = 5;
+const EINVAL: u32 = 22;
+
+/// Largest request payload accepted, larger requests are refused
+const MAX_PAYLOAD: u32 = 32 << 20;
+
+#[derive(Debug)]
+pub enum NbdError {
+    IoError(io::Error),
+
+    /// Client sent something other than the expected magic number
+    InvalidMagic,
+
+    /// Option or request payload is larger than the server accepts
+    PayloadTooLarge,
+}
+
+impl From<io::Error> for NbdError {
+    fn from(err: io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
+
+/// Export `device` over `stream` until the client disconnects.
+/// Any export name the client asks for is accepted.
+pub fn serve<D, S>(device: &mut D, mut stream: S, read_only: bool, block_size: u32) -> Result<(), NbdError>
+where
+    D: Read + Write + Seek,
+    S: Read + Write,
+{
+    let size = device.seek(SeekFrom::End(0))?;
+    let mut transmission_flags = FLAG_HAS_FLAGS | FLAG_SEND_FLUSH;
+    if read_only {
+        transmission_flags |= FLAG_READ_ONLY;
+    }
+
+    // handshake
+    stream.write_all(&NBDMAGIC.to_be_bytes())?;
+    stream.write_all(&IHAVEOPT.to_be_bytes())?;
+    stream.write_all(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes())?;
+    stream.flush()?;
+    let client_flags = read_u32(&mut stream)?;
+    let no_zeroes = client_flags & FLAG_C_NO_ZEROES != 0;
+
+    // option haggling
+    loop {
+        if read_u64(&mut stream)? != IHAVEOPT {
+            return Err(NbdError::InvalidMagic);
+        }
+        let option = read_u32(&mut stream)?;
+        let length = read_u32(&mut stream)?;
+        if length > MAX_PAYLOAD {
+            return Err(NbdError::PayloadTooLarge);
+        }
+        let mut data = vec![0; length as usize];
+        stream.read_exact(&mut data)?;
+
+        match option {
+            OPT_EXPORT_NAME => {
+                stream.write_all(&size.to_be_bytes())?;
+                stream.write_all(&transmission_flags.to_be_bytes())?;
+                if !no_zeroes {
+                    stream.write_all(&[0; 124])?;
+                }
+                stream.flush()?;
+                break;
+            }
+            OPT_ABORT => {
+                write_option_reply(&mut stream, option, REP_ACK, &[])?;
+                return Ok(());
+            }
+            OPT_LIST => {
+                // single unnamed export
+                write_option_reply(&mut stream, option, REP_SERVER, &0u32.to_be_bytes())?;
+                write_option_reply(&mut stream, option, REP_ACK, &[])?;
+            }
+            OPT_INFO | OPT_GO => {
+                let name_length = data
+                    .get(..4)
+                    .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()) as usize);
+                if name_length.is_none_or(|name_length| data.len() < 4 + name_length + 2) {
+                    write_option_reply(&mut stream, option, REP_ERR_INVALID, &[])?;
+                    continue;
+                }
+
+                let mut export = Vec::new();
+                export.extend(INFO_EXPORT.to_be_bytes());
+                export.extend(size.to_be_bytes());
+                export.extend(transmission_flags.to_be_bytes());
+                write_option_reply(&mut stream, option, REP_INFO, &export)?;
+
+                let mut block_sizes = Vec::new();
+                block_sizes.extend(INFO_BLOCK_SIZE.to_be_bytes());
+                block_sizes.extend(1u32.to_be_bytes());
+                block_sizes.extend(block_size.to_be_bytes());
+                block_sizes.extend(MAX_PAYLOAD.to_be_bytes());
+                write_option_reply(&mut stream, option, REP_INFO, &block_sizes)?;
+
+                write_option_reply(&mut stream, option, REP_ACK, &[])?;
+                if option == OPT_GO {
+                    break;
+                }
+            }
+            _ => write_option_reply(&mut stream, option, REP_ERR_UNSUP, &[])?,
+        }
+    }
+
+    // transmission
+    let mut buffer = Vec::new();
+    loop {
+        if read_u32(&mut stream)? != REQUEST_MAGIC {
+            return Err(NbdError::InvalidMagic);
+        }
+        let _command_flags = read_u16(&mut stream)?;
+        let command = read_u16(&mut stream)?;
+        let handle = read_u64(&mut stream)?;
+        let offset = read_u64(&mut stream)?;
+        let length = read_u32(&mut stream)?;
+        let in_bounds = offset.checked_add(u64::from(length)).is_some_and(|end| end <= size);
+
+        match command {
+            CMD_READ => {
+                if !in_bounds || length > MAX_PAYLOAD {
+                    write_simple_reply(&mut stream, EINVAL, handle, &[])?;
+                    continue;
+                }
+
+                buffer.resize(length as usize, 0);
+                let error = match device
+                    .seek(SeekFrom::Start(offset))
+                    .and_then(|_| device.read_exact(&mut buffer))
+                {
+                    Ok(()) => 0,
+                    Err(_) => EIO,
+                };
+                let data = if error == 0 { buffer.as_slice() } else { &[] };
+                write_simple_reply(&mut stream, error, handle, data)?;
+            }
+            CMD_WRITE => {
+                // payload has to be consumed even if the write is refused
+                if length > MAX_PAYLOAD {
+                    return Err(NbdError::PayloadTooLarge);
+                }
+                buffer.resize(length as usize, 0);
+                stream.read_exact(&mut buffer)?;
+
+                let error = if read_only {
+                    EPERM
+                } else if !in_bounds {
+                    EINVAL
+                } else {
+                    match device
+                        .seek(SeekFrom::Start(offset))
+                        .and_then(|_| device.write_all(&buffer))
+                    {
+                        Ok(()) => 0,
+                        Err(_) => EIO,
+                    }
+                };
+                write_simple_reply(&mut stream, error, handle, &[])?;
+            }
+            CMD_DISC => {
+                device.flush()?;
+                return Ok(());
+            }
+            CMD_FLUSH => {
+                let error = match device.flush() {
+                    Ok(()) => 0,
+                    Err(_) => EIO,
+                };
+                write_simple_reply(&mut stream, error, handle, &[])?;
+            }
+            _ => write_simple_reply(&mut stream, EINVAL, handle, &[])?,
+        }
+    }
+}
+
+fn write_option_reply<S: Write>(stream: &mut S, option: u32, reply_type: u32, data: &[u8]) -> io::Result<()> {
+    stream.write_all(&OPTION_REPLY_MAGIC.to_be_bytes())?;
+    stream.write_all(&option.to_be_bytes())?;
+    stream.write_all(&reply_type.to_be_bytes())?;
+    stream.write_all(&(data.len() as u32).to_be_bytes())?;
+    stream.write_all(data)?;
+    stream.flush()
+}
+
+fn write_simple_reply<S: Write>(stream: &mut S, error: u32, handle: u64, data: &[u8]) -> io::Result<()> {
+    stream.write_all(&SIMPLE_REPLY_MAGIC.to_be_bytes())?;
+    stream.write_all(&error.to_be_bytes())?;
+    stream.write_all(&handle.to_be_bytes())?;
+    stream.write_all(data)?;
+    stream.flush()
+}
+
+fn read_u16<S: Read>(stream: &mut S) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    stream.read_exact(&mut bytes)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+fn read_u32<S: Read>(stream: &mut S) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    stream.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64<S: Read>(stream: &mut S) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    stream.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+#[cfg(unix)]
+#[test]
+fn serve_unix_socket() {
+    use std::os::unix::net::UnixStream;
+
+    use crate::VirtualExFatBlockDevice;
+
+    fn request<S: Write>(stream: &mut S, command: u16, handle: u64, offset: u64, length: u32) {
+        stream.write_all(&REQUEST_MAGIC.to_be_bytes()).unwrap();
+        stream.write_all(&0u16.to_be_bytes()).unwrap();
+        stream.write_all(&command.to_be_bytes()).unwrap();
+        stream.write_all(&handle.to_be_bytes()).unwrap();
+        stream.write_all(&offset.to_be_bytes()).unwrap();
+        stream.write_all(&length.to_be_bytes()).unwrap();
+    }
+
+    fn simple_reply<S: Read>(stream: &mut S, handle: u64) -> u32 {
+        assert_eq!(read_u32(stream).unwrap(), SIMPLE_REPLY_MAGIC);
+        let error = read_u32(stream).unwrap();
+        assert_eq!(read_u64(stream).unwrap(), handle);
+        error
+    }
+
+    let mut vexfat = VirtualExFatBlockDevice::new_with_serial_number(9, 3, 512, 0).unwrap();
+    let volume_size = vexfat.volume_size();
+    let mut boot_sector = [0; 512];
+    vexfat.read_sector(0, &mut boot_sector).unwrap();
+
+    let (server, mut client) = UnixStream::pair().unwrap();
+    std::thread::scope(|scope| {
+        let server = scope.spawn(|| serve(&mut vexfat, server, false, 512));
+
+        // handshake
+        assert_eq!(read_u64(&mut client).unwrap(), NBDMAGIC);
+        assert_eq!(read_u64(&mut client).unwrap(), IHAVEOPT);
+        assert_eq!(read_u16(&mut client).unwrap(), FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES);
+        client.write_all(&(FLAG_FIXED_NEWSTYLE as u32 | FLAG_C_NO_ZEROES).to_be_bytes()).unwrap();
+
+        // unknown option is refused, haggling continues
+        client.write_all(&IHAVEOPT.to_be_bytes()).unwrap();
+        client.write_all(&0xFFu32.to_be_bytes()).unwrap();
+        client.write_all(&0u32.to_be_bytes()).unwrap();
+        assert_eq!(read_u64(&mut client).unwrap(), OPTION_REPLY_MAGIC);
+        assert_eq!(read_u32(&mut client).unwrap(), 0xFF);
+        assert_eq!(read_u32(&mut client).unwrap(), REP_ERR_UNSUP);
+        assert_eq!(read_u32(&mut client).unwrap(), 0);
+
+        // go with an empty export name and no info requests
+        client.write_all(&IHAVEOPT.to_be_bytes()).unwrap();
+        client.write_all(&OPT_GO.to_be_bytes()).unwrap();
+        client.write_all(&6u32.to_be_bytes()).unwrap();
+        client.write_all(&[0; 6]).unwrap();
+        let mut replies = Vec::new();
+        loop {
+            assert_eq!(read_u64(&mut client).unwrap(), OPTION_REPLY_MAGIC);
+            assert_eq!(read_u32(&mut client).unwrap(), OPT_GO);
+            let reply_type = read_u32(&mut client).unwrap();
+            let mut data = vec![0; read_u32(&mut client).unwrap() as usize];
+            client.read_exact(&mut data).unwrap();
+            if reply_type == REP_ACK {
+                break;
+            }
+            assert_eq!(reply_type, REP_INFO);
+            replies.push(data);
+        }
+        assert_eq!(replies[0][..2], INFO_EXPORT.to_be_bytes());
+        assert_eq!(replies[0][2..10], volume_size.to_be_bytes());
+        assert_eq!(replies[0][10..], (FLAG_HAS_FLAGS | FLAG_SEND_FLUSH).to_be_bytes());
+
+        // read the boot sector
+        request(&mut client, CMD_READ, 1, 0, 512);
+        assert_eq!(simple_reply(&mut client, 1), 0);
+        let mut buffer = [0; 512];
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(buffer, boot_sector);
+
+        // write into the overlay and read it back, unaligned
+        request(&mut client, CMD_WRITE, 2, 510, 4);
+        client.write_all(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(simple_reply(&mut client, 2), 0);
+        request(&mut client, CMD_READ, 3, 508, 8);
+        assert_eq!(simple_reply(&mut client, 3), 0);
+        let mut buffer = [0; 8];
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(buffer, [0, 0, 1, 2, 3, 4, 0, 0]);
+
+        // past the end of the volume
+        request(&mut client, CMD_READ, 4, volume_size - 1, 2);
+        assert_eq!(simple_reply(&mut client, 4), EINVAL);
+
+        request(&mut client, CMD_DISC, 5, 0, 0);
+        server.join().unwrap().unwrap();
+    });
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+
+/// Copy-on-write store for written sectors which can't be applied to the emulated structures
+#[derive(Debug, Default)]
+pub struct Overlay {
+    sectors: HashMap<u64, Box<[u8]>>,
+}
+
+impl Overlay {
+    /// Returns `false` if the sector was never written to
+    pub fn read_sector(&self, sector_index: u64, buffer: &mut [u8]) -> bool {
+        match self.sectors.get(&sector_index) {
+            Some(sector) => {
+                buffer.copy_from_slice(sector);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn write_sector(&mut self, sector_index: u64, buffer: &[u8]) {
+        match self.sectors.get_mut(&sector_index) {
+            Some(sector) => sector.copy_from_slice(buffer),
+            None => {
+                self.sectors.insert(sector_index, buffer.into());
+            }
+        }
+    }
+
+    pub fn discard_sector(&mut self, sector_index: u64) {
+        self.sectors.remove(&sector_index);
+    }
+
+    /// Drops every sector for which `discard` returns `true`
+    pub fn discard_sectors<F>(&mut self, mut discard: F)
+    where
+        F: FnMut(u64) -> bool,
+    {
+        self.sectors.retain(|&sector_index, _| !discard(sector_index));
+    }
+}
+
+#[test]
+fn overlay() {
+    let mut overlay = Overlay::default();
+
+    let mut buffer = [0; 512];
+    assert!(!overlay.read_sector(1, &mut buffer));
+
+    overlay.write_sector(1, &[0xAB; 512]);
+    assert!(overlay.read_sector(1, &mut buffer));
+    assert_eq!(buffer, [0xAB; 512]);
+
+    overlay.discard_sector(1);
+    assert!(!overlay.read_sector(1, &mut buffer));
+
+    overlay.write_sector(2, &[0xAB; 512]);
+    overlay.write_sector(3, &[0xAB; 512]);
+    overlay.discard_sectors(|sector_index| sector_index > 2);
+    assert!(overlay.read_sector(2, &mut buffer));
+    assert!(!overlay.read_sector(3, &mut buffer));
+}
@@ -0,0 +1,459 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::overlay::Overlay;
+use crate::utils::unsigned_align_to;
+use crate::{ReadError, VirtualExFatBlockDevice, WriteError};
+
+/// Microsoft Basic Data partition type, {EBD0A0A2-B9E5-4433-87C0-68B6B72699C7} as stored on disk
+const BASIC_DATA_PARTITION_GUID: [u8; 16] = [
+    0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7,
+];
+
+/// MBR partition type of exFAT, shared with NTFS
+const EXFAT_PARTITION_TYPE: u8 = 0x07;
+
+/// MBR partition type of the protective MBR in front of GPT
+const PROTECTIVE_PARTITION_TYPE: u8 = 0xEE;
+
+const GPT_ENTRIES: u64 = 128;
+const GPT_ENTRY_SIZE: u64 = 128;
+const GPT_HEADER_SIZE: u32 = 92;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PartitionTable {
+    Mbr,
+    Gpt,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PartitionError {
+    /// Must be a non-zero multiple of the sector size
+    InvalidAlignment,
+
+    /// MBR can only describe partitions under 2^32 sectors
+    VolumeTooLarge,
+}
+
+/// Presents the volume as a disk with a single partition
+pub struct PartitionedBlockDevice {
+    table: PartitionTable,
+    /// Sector the volume starts at
+    partition_offset: u64,
+    /// Disk length in sectors, including the backup GPT
+    disk_length: u64,
+    disk_guid: [u8; 16],
+    mbr_signature: u32,
+    /// Partition entries of GPT, empty for MBR
+    gpt_entries: Vec<u8>,
+    gpt_entries_crc: u32,
+
+    volume: VirtualExFatBlockDevice,
+    /// Writes to the partition table and the gaps around the volume
+    overlay: Overlay,
+
+    current_sector: u64,
+    current_offset_in_sector: u64,
+}
+
+impl PartitionedBlockDevice {
+    /// Volume is aligned to 1 MiB
+    pub fn new(volume: VirtualExFatBlockDevice, table: PartitionTable) -> Result<Self, PartitionError> {
+        Self::new_with_alignment(volume, table, 1 << 20)
+    }
+
+    /// `alignment` of the volume in bytes
+    pub fn new_with_alignment(
+        mut volume: VirtualExFatBlockDevice,
+        table: PartitionTable,
+        alignment: u64,
+    ) -> Result<Self, PartitionError> {
+        let bytes_per_sector = u64::from(volume.bytes_per_sector());
+        if alignment == 0 || !alignment.is_multiple_of(bytes_per_sector) {
+            return Err(PartitionError::InvalidAlignment);
+        }
+
+        let entries_length = GPT_ENTRIES * GPT_ENTRY_SIZE / bytes_per_sector;
+        let (first_usable_sector, trailing_sectors) = match table {
+            PartitionTable::Mbr => (1, 0),
+            // protective MBR, header and entries, backup entries and header at the end
+            PartitionTable::Gpt => (2 + entries_length, entries_length + 1),
+        };
+
+        let partition_offset = unsigned_align_to(first_usable_sector, alignment / bytes_per_sector);
+        if table == PartitionTable::Mbr
+            && (partition_offset > u64::from(u32::MAX) || volume.volume_length() > u64::from(u32::MAX))
+        {
+            return Err(PartitionError::VolumeTooLarge);
+        }
+
+        volume.set_partition_offset(partition_offset);
+
+        let last_sector = partition_offset + volume.volume_length() - 1;
+        let gpt_entries = match table {
+            PartitionTable::Mbr => Vec::new(),
+            PartitionTable::Gpt => gpt_entries(&random_guid(), partition_offset, last_sector),
+        };
+
+        Ok(Self {
+            table,
+            partition_offset,
+            disk_length: last_sector + 1 + trailing_sectors,
+            disk_guid: random_guid(),
+            mbr_signature: rand::random(),
+            gpt_entries_crc: crc32(&gpt_entries),
+            gpt_entries,
+            volume,
+            overlay: Overlay::default(),
+            current_sector: 0,
+            current_offset_in_sector: 0,
+        })
+    }
+
+    pub fn volume(&mut self) -> &mut VirtualExFatBlockDevice {
+        &mut self.volume
+    }
+
+    pub fn into_volume(self) -> VirtualExFatBlockDevice {
+        self.volume
+    }
+
+    pub fn bytes_per_sector(&self) -> u16 {
+        self.volume.bytes_per_sector()
+    }
+
+    /// Sector the volume starts at
+    pub fn partition_offset(&self) -> u64 {
+        self.partition_offset
+    }
+
+    /// Size of the disk in sectors
+    pub fn disk_length(&self) -> u64 {
+        self.disk_length
+    }
+
+    /// Size of the disk in bytes
+    pub fn disk_size(&self) -> u64 {
+        self.disk_length * u64::from(self.bytes_per_sector())
+    }
+
+    /// `buffer` is assumed to be zeroed
+    pub fn read_sector(&mut self, sector_index: u64, buffer: &mut [u8]) -> Result<(), ReadError> {
+        assert_eq!(buffer.len(), usize::from(self.bytes_per_sector()));
+
+        if sector_index >= self.disk_length {
+            return Err(ReadError::OutOfBounds);
+        }
+        if self.overlay.read_sector(sector_index, buffer) {
+            return Ok(());
+        }
+
+        let volume_end = self.partition_offset + self.volume.volume_length();
+        if (self.partition_offset..volume_end).contains(&sector_index) {
+            return self.volume.read_sector(sector_index - self.partition_offset, buffer);
+        }
+
+        let entries_length = self.gpt_entries_length();
+        match (self.table, sector_index) {
+            (_, 0) => self.read_mbr(buffer),
+
+            // primary GPT
+            (PartitionTable::Gpt, 1) => self.read_gpt_header(buffer, false),
+            (PartitionTable::Gpt, sector) if sector < 2 + entries_length => {
+                self.read_gpt_entries(sector - 2, buffer)
+            }
+
+            // backup GPT
+            (PartitionTable::Gpt, sector) if sector == self.disk_length - 1 => self.read_gpt_header(buffer, true),
+            (PartitionTable::Gpt, sector) if sector >= volume_end => self.read_gpt_entries(sector - volume_end, buffer),
+
+            // alignment
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Writes outside of the volume are kept in the overlay
+    pub fn write_sector(&mut self, sector_index: u64, buffer: &[u8]) -> Result<(), WriteError> {
+        assert_eq!(buffer.len(), usize::from(self.bytes_per_sector()));
+
+        if sector_index >= self.disk_length {
+            return Err(WriteError::OutOfBounds);
+        }
+
+        let volume_end = self.partition_offset + self.volume.volume_length();
+        if (self.partition_offset..volume_end).contains(&sector_index) {
+            self.volume.write_sector(sector_index - self.partition_offset, buffer)
+        } else {
+            self.overlay.write_sector(sector_index, buffer);
+            Ok(())
+        }
+    }
+
+    fn gpt_entries_length(&self) -> u64 {
+        GPT_ENTRIES * GPT_ENTRY_SIZE / u64::from(self.bytes_per_sector())
+    }
+
+    fn read_mbr(&self, buffer: &mut [u8]) {
+        let (partition_type, first_sector, length) = match self.table {
+            PartitionTable::Mbr => (EXFAT_PARTITION_TYPE, self.partition_offset, self.volume.volume_length()),
+            PartitionTable::Gpt => (PROTECTIVE_PARTITION_TYPE, 1, self.disk_length - 1),
+        };
+
+        buffer[440..444].copy_from_slice(&self.mbr_signature.to_le_bytes());
+
+        let entry = &mut buffer[446..462];
+        entry[0] = 0x00; // not bootable
+        entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]); // CHS is not used
+        entry[4] = partition_type;
+        entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+        entry[8..12].copy_from_slice(&(first_sector as u32).to_le_bytes());
+        entry[12..16].copy_from_slice(&(length.min(u64::from(u32::MAX)) as u32).to_le_bytes());
+
+        buffer[510] = 0x55;
+        buffer[511] = 0xAA;
+    }
+
+    /// `sector` is relative to the start of the entries
+    fn read_gpt_entries(&self, sector: u64, buffer: &mut [u8]) {
+        let bytes_per_sector = buffer.len();
+        let offset = sector as usize * bytes_per_sector;
+        buffer.copy_from_slice(&self.gpt_entries[offset..offset + bytes_per_sector]);
+    }
+
+    fn read_gpt_header(&self, buffer: &mut [u8], backup: bool) {
+        let entries_length = self.gpt_entries_length();
+        let primary_sector = 1;
+        let backup_sector = self.disk_length - 1;
+        let (current_sector, alternate_sector, entries_sector) = match backup {
+            false => (primary_sector, backup_sector, 2),
+            true => (backup_sector, primary_sector, backup_sector - entries_length),
+        };
+
+        let header = &mut buffer[..GPT_HEADER_SIZE as usize];
+        header[0..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes()); // revision 1.0
+        header[12..16].copy_from_slice(&GPT_HEADER_SIZE.to_le_bytes());
+        // header checksum at 16..20 is computed with the field zeroed
+        header[24..32].copy_from_slice(&current_sector.to_le_bytes());
+        header[32..40].copy_from_slice(&alternate_sector.to_le_bytes());
+        header[40..48].copy_from_slice(&(2 + entries_length).to_le_bytes()); // first usable
+        header[48..56].copy_from_slice(&(self.disk_length - entries_length - 2).to_le_bytes()); // last usable
+        header[56..72].copy_from_slice(&self.disk_guid);
+        header[72..80].copy_from_slice(&entries_sector.to_le_bytes());
+        header[80..84].copy_from_slice(&(GPT_ENTRIES as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&self.gpt_entries_crc.to_le_bytes());
+
+        let checksum = crc32(header);
+        header[16..20].copy_from_slice(&checksum.to_le_bytes());
+    }
+}
+
+impl Seek for PartitionedBlockDevice {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let bytes_per_sector = u64::from(self.bytes_per_sector());
+        let current = self.current_sector * bytes_per_sector + self.current_offset_in_sector;
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.disk_size().checked_add_signed(offset),
+            SeekFrom::Current(offset) => current.checked_add_signed(offset),
+        };
+
+        let offset = offset.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek"))?;
+        self.current_sector = offset / bytes_per_sector;
+        self.current_offset_in_sector = offset % bytes_per_sector;
+
+        Ok(offset)
+    }
+}
+
+impl Read for PartitionedBlockDevice {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let bytes_per_sector = usize::from(self.bytes_per_sector());
+        let mut bytes_read = 0;
+
+        while bytes_read < buffer.len() {
+            let mut sector = vec![0; bytes_per_sector];
+            if self.read_sector(self.current_sector, &mut sector).is_err() {
+                break;
+            }
+
+            let offset_in_sector = self.current_offset_in_sector as usize;
+            let to_read = usize::min(bytes_per_sector - offset_in_sector, buffer.len() - bytes_read);
+            buffer[bytes_read..bytes_read + to_read]
+                .copy_from_slice(&sector[offset_in_sector..offset_in_sector + to_read]);
+
+            bytes_read += to_read;
+            self.seek(SeekFrom::Current(to_read as i64))?;
+        }
+
+        Ok(bytes_read)
+    }
+}
+
+impl Write for PartitionedBlockDevice {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let bytes_per_sector = usize::from(self.bytes_per_sector());
+        let mut bytes_written = 0;
+
+        while bytes_written < buffer.len() {
+            let offset_in_sector = self.current_offset_in_sector as usize;
+            let to_write = usize::min(bytes_per_sector - offset_in_sector, buffer.len() - bytes_written);
+
+            // partially written sectors have to be read first
+            let mut sector = vec![0; bytes_per_sector];
+            if to_write < bytes_per_sector && self.read_sector(self.current_sector, &mut sector).is_err() {
+                break;
+            }
+
+            sector[offset_in_sector..offset_in_sector + to_write]
+                .copy_from_slice(&buffer[bytes_written..bytes_written + to_write]);
+            if self.write_sector(self.current_sector, &sector).is_err() {
+                break;
+            }
+
+            bytes_written += to_write;
+            self.seek(SeekFrom::Current(to_write as i64))?;
+        }
+
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.volume.flush()
+    }
+}
+
+/// Version 4 GUID, as stored on disk
+fn random_guid() -> [u8; 16] {
+    let mut guid: [u8; 16] = rand::random();
+    guid[7] = (guid[7] & 0x0F) | 0x40;
+    guid[8] = (guid[8] & 0x3F) | 0x80;
+    guid
+}
+
+/// Single partition entry followed by unused ones
+fn gpt_entries(partition_guid: &[u8; 16], first_sector: u64, last_sector: u64) -> Vec<u8> {
+    let mut entries = vec![0; (GPT_ENTRIES * GPT_ENTRY_SIZE) as usize];
+
+    let entry = &mut entries[..GPT_ENTRY_SIZE as usize];
+    entry[0..16].copy_from_slice(&BASIC_DATA_PARTITION_GUID);
+    entry[16..32].copy_from_slice(partition_guid);
+    entry[32..40].copy_from_slice(&first_sector.to_le_bytes());
+    entry[40..48].copy_from_slice(&last_sector.to_le_bytes());
+    // attributes are left zeroed
+    for (name, code_unit) in entry[56..].chunks_exact_mut(2).zip("Basic data partition".encode_utf16()) {
+        name.copy_from_slice(&code_unit.to_le_bytes());
+    }
+
+    entries
+}
+
+/// CRC-32 used by GPT, same as in zlib
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+
+    !crc
+}
+
+#[test]
+fn checksum() {
+    assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    assert_eq!(crc32(&[]), 0);
+}
+
+#[test]
+fn mbr() {
+    let volume = VirtualExFatBlockDevice::new(9, 3, 512).unwrap();
+    let volume_length = volume.volume_length();
+    let mut disk = PartitionedBlockDevice::new(volume, PartitionTable::Mbr).unwrap();
+    assert_eq!(disk.partition_offset(), 2048);
+    assert_eq!(disk.disk_length(), 2048 + volume_length);
+
+    let mut buffer = [0; 512];
+    disk.read_sector(0, &mut buffer).unwrap();
+    assert_eq!(buffer[446 + 4], 0x07);
+    assert_eq!(&buffer[446 + 8..446 + 12], &2048u32.to_le_bytes());
+    assert_eq!(&buffer[446 + 12..446 + 16], &(volume_length as u32).to_le_bytes());
+    assert_eq!(&buffer[510..], &[0x55, 0xAA]);
+
+    // boot sector of the volume knows where it is
+    let mut buffer = [0; 512];
+    disk.read_sector(2048, &mut buffer).unwrap();
+    assert_eq!(&buffer[3..11], b"EXFAT   ");
+    assert_eq!(&buffer[64..72], &2048u64.to_le_bytes());
+
+    // partition table is writable, volume is not shifted
+    disk.write_sector(0, &[0xAB; 512]).unwrap();
+    let mut buffer = [0; 512];
+    disk.read_sector(0, &mut buffer).unwrap();
+    assert_eq!(buffer, [0xAB; 512]);
+
+    let mut buffer = [0; 512];
+    assert_eq!(disk.read_sector(disk.disk_length(), &mut buffer), Err(ReadError::OutOfBounds));
+
+    let ret = PartitionedBlockDevice::new_with_alignment(
+        VirtualExFatBlockDevice::new(9, 3, 512).unwrap(),
+        PartitionTable::Mbr,
+        1000,
+    );
+    assert!(matches!(ret, Err(PartitionError::InvalidAlignment)));
+}
+
+#[test]
+fn gpt() {
+    let volume = VirtualExFatBlockDevice::new(12, 0, 512).unwrap();
+    let volume_length = volume.volume_length();
+    let mut disk = PartitionedBlockDevice::new(volume, PartitionTable::Gpt).unwrap();
+    assert_eq!(disk.partition_offset(), 256);
+    assert_eq!(disk.disk_length(), 256 + volume_length + 4 + 1);
+
+    let mut mbr = [0; 4096];
+    disk.read_sector(0, &mut mbr).unwrap();
+    assert_eq!(mbr[446 + 4], 0xEE);
+    assert_eq!(&mbr[446 + 8..446 + 12], &1u32.to_le_bytes());
+
+    let mut primary = [0; 4096];
+    disk.read_sector(1, &mut primary).unwrap();
+    let mut backup = [0; 4096];
+    disk.read_sector(disk.disk_length() - 1, &mut backup).unwrap();
+    for header in [&primary, &backup] {
+        assert_eq!(&header[..8], b"EFI PART");
+        let mut zeroed_checksum = header[..92].to_vec();
+        zeroed_checksum[16..20].fill(0);
+        assert_eq!(&header[16..20], &crc32(&zeroed_checksum).to_le_bytes());
+    }
+    assert_eq!(&primary[24..32], &backup[32..40]);
+    assert_eq!(&primary[72..80], &2u64.to_le_bytes());
+    assert_eq!(&backup[72..80], &(disk.disk_length() - 5).to_le_bytes());
+
+    // both copies of the entries match the checksum in the header
+    let mut entries = vec![0; 4 * 4096];
+    for (sector, buffer) in entries.chunks_exact_mut(4096).enumerate() {
+        disk.read_sector(2 + sector as u64, buffer).unwrap();
+    }
+    assert_eq!(&primary[88..92], &crc32(&entries).to_le_bytes());
+    assert_eq!(&entries[..16], &BASIC_DATA_PARTITION_GUID);
+    assert_eq!(&entries[32..40], &256u64.to_le_bytes());
+    assert_eq!(&entries[40..48], &(256 + volume_length - 1).to_le_bytes());
+
+    let mut backup_entries = vec![0; 4 * 4096];
+    for (sector, buffer) in backup_entries.chunks_exact_mut(4096).enumerate() {
+        disk.read_sector(disk.disk_length() - 5 + sector as u64, buffer).unwrap();
+    }
+    assert_eq!(entries, backup_entries);
+
+    // byte level access goes through the same sectors
+    let mut buffer = [0; 8];
+    disk.seek(SeekFrom::Start(256 * 4096 + 3)).unwrap();
+    disk.read_exact(&mut buffer).unwrap();
+    assert_eq!(&buffer, b"EXFAT   ");
+}
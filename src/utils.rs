@@ -12,6 +12,51 @@ where
     unsigned_rounded_up_div(a, b).mul(b)
 }
 
+/// Path in the temporary directory, removed along with everything in it when dropped
+#[cfg(test)]
+pub struct TempPath(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempPath {
+    /// `name` has to be unique among the tests, leftovers of an earlier run are removed
+    pub fn new(name: &str) -> Self {
+        let temp_path = Self(std::env::temp_dir().join(format!("vexfatbd-{name}-{}", std::process::id())));
+        temp_path.remove();
+        temp_path
+    }
+
+    fn remove(&self) {
+        if self.0.is_dir() {
+            let _ = std::fs::remove_dir_all(&self.0);
+        } else {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempPath {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<std::path::Path> for TempPath {
+    fn as_ref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
 #[test]
 fn rounding_up() {
     assert_eq!(unsigned_rounded_up_div(5u32, 1), 5);
@@ -0,0 +1,396 @@
+//! Applies changes made to mapped host directories to the volume, using Linux inotify
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use crate::data_region::file::FileDirectoryEntryError;
+use crate::{EntryTimes, MapDirectoryOptions, MapDirectoryReport, VirtualExFatBlockDevice};
+
+/// Events which change the directory tree or the contents of a file
+const WATCH_MASK: WatchMask = WatchMask::CREATE
+    .union(WatchMask::DELETE)
+    .union(WatchMask::MODIFY)
+    .union(WatchMask::MOVED_FROM)
+    .union(WatchMask::MOVED_TO);
+
+/// Event read from inotify, names are relative to the watched directory
+struct Event {
+    wd: WatchDescriptor,
+    mask: EventMask,
+    cookie: u32,
+    name: OsString,
+}
+
+/// File or directory moved out of a watched directory, a moved to event with the same cookie may follow
+struct MovedFrom {
+    host_path: PathBuf,
+    dir_cluster: u32,
+    name: String,
+    directory: bool,
+    cookie: u32,
+}
+
+/// Watches host directories mapped by [`VirtualExFatBlockDevice::map_directory`],
+/// host creations, deletions, modifications and renames are turned into the respective volume operations.
+///
+/// Files created in a new directory before it is watched are mapped along with the directory,
+/// changes the host makes while the guest has the volume mounted are not coordinated with the guest.
+pub struct DirectoryWatcher {
+    inotify: Inotify,
+    options: MapDirectoryOptions,
+    /// Mapped host directory, filters are matched against paths relative to it
+    root: PathBuf,
+    /// Watched host directory and the cluster of the directory it is mapped to
+    directories: HashMap<WatchDescriptor, (PathBuf, u32)>,
+    /// Number of changes applied to the volume
+    change_count: u64,
+}
+
+impl DirectoryWatcher {
+    /// Watch every directory in the report, `options` should be the ones the directory was mapped with
+    pub fn new(report: &MapDirectoryReport, options: &MapDirectoryOptions) -> io::Result<Self> {
+        let root = report
+            .directories
+            .first()
+            .map(|(host_path, _)| host_path.clone())
+            .ok_or(io::ErrorKind::InvalidInput)?;
+
+        let mut watcher = Self {
+            inotify: Inotify::init()?,
+            options: options.clone(),
+            root,
+            directories: HashMap::new(),
+            change_count: 0,
+        };
+        for (host_path, dir_cluster) in &report.directories {
+            watcher.watch(host_path, *dir_cluster)?;
+        }
+
+        Ok(watcher)
+    }
+
+    /// Incremented with each change applied to the volume, e.g. to tell the guest the media changed
+    pub fn change_count(&self) -> u64 {
+        self.change_count
+    }
+
+    fn watch(&mut self, host_path: &Path, dir_cluster: u32) -> io::Result<()> {
+        let wd = self.inotify.watches().add(host_path, WATCH_MASK)?;
+        self.directories.insert(wd, (host_path.to_path_buf(), dir_cluster));
+        Ok(())
+    }
+
+    /// Stop watching the directory and everything in it
+    fn unwatch(&mut self, host_path: &Path) {
+        let watched: Vec<WatchDescriptor> = self
+            .directories
+            .iter()
+            .filter(|(_, (path, _))| path.starts_with(host_path))
+            .map(|(wd, _)| wd.clone())
+            .collect();
+
+        for wd in watched {
+            self.directories.remove(&wd);
+            // watch is already gone if the directory was deleted
+            let _ = self.inotify.watches().remove(wd);
+        }
+    }
+
+    /// Apply the pending host changes to the volume without blocking, returns the number of applied changes.
+    /// Changes which can't be represented on the volume, e.g. names illegal in exFAT, are left out.
+    ///
+    /// Fails if inotify dropped events because too many were pending, the volume no longer follows the host then
+    /// and the directory has to be mapped again. The lost changes count as one change.
+    pub fn apply(&mut self, vexfat: &mut VirtualExFatBlockDevice) -> io::Result<usize> {
+        let events = self.read_events()?;
+        let mut applied = 0;
+        let mut overflowed = false;
+
+        // renames within the watched tree are a moved from event followed by a moved to event with the same cookie
+        let mut moved_from: Option<MovedFrom> = None;
+
+        for event in events {
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                overflowed = true;
+                continue;
+            }
+            if event.mask.contains(EventMask::IGNORED) {
+                self.directories.remove(&event.wd);
+                continue;
+            }
+            let Some((host_dir, dir_cluster)) = self.directories.get(&event.wd).cloned() else {
+                continue;
+            };
+            let Some(name) = event.name.to_str().map(str::to_string) else {
+                continue;
+            };
+            let host_path = host_dir.join(&name);
+            let directory = event.mask.contains(EventMask::ISDIR);
+
+            if let Some(from) = moved_from.take() {
+                if event.mask.contains(EventMask::MOVED_TO) && event.cookie == from.cookie {
+                    applied += self.rename(vexfat, &from, &host_path, dir_cluster, &name)?;
+                    continue;
+                }
+
+                // moved out of the watched tree
+                applied += usize::from(self.remove_moved(vexfat, &from));
+            }
+
+            if event.mask.contains(EventMask::MOVED_FROM) {
+                moved_from = Some(MovedFrom {
+                    host_path,
+                    dir_cluster,
+                    name,
+                    directory,
+                    cookie: event.cookie,
+                });
+            } else if event.mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                applied += usize::from(self.add(vexfat, &host_path, dir_cluster, &name, directory)?);
+            } else if event.mask.contains(EventMask::DELETE) {
+                applied += usize::from(self.remove(vexfat, &host_path, dir_cluster, &name, directory));
+            } else if event.mask.contains(EventMask::MODIFY) && !directory {
+                applied += usize::from(vexfat.refresh_file(dir_cluster, &name).is_ok());
+            }
+        }
+
+        if let Some(from) = moved_from {
+            applied += usize::from(self.remove_moved(vexfat, &from));
+        }
+
+        self.change_count += applied as u64;
+        if overflowed {
+            self.change_count += 1;
+            return Err(io::Error::other("inotify event queue overflowed, host changes were lost"));
+        }
+        Ok(applied)
+    }
+
+    /// Every event pending right now
+    fn read_events(&mut self) -> io::Result<Vec<Event>> {
+        let mut buffer = [0; 4096];
+        let mut events = Vec::new();
+
+        loop {
+            match self.inotify.read_events(&mut buffer) {
+                Ok(read) => events.extend(read.map(|event| Event {
+                    wd: event.wd,
+                    mask: event.mask,
+                    cookie: event.cookie,
+                    name: event.name.map(OsString::from).unwrap_or_default(),
+                })),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(events),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Map file or directory created or moved into a watched directory, returns whether it was added
+    fn add(
+        &mut self,
+        vexfat: &mut VirtualExFatBlockDevice,
+        host_path: &Path,
+        dir_cluster: u32,
+        name: &str,
+        directory: bool,
+    ) -> io::Result<bool> {
+        let relative_path = host_path.strip_prefix(&self.root).unwrap_or(host_path);
+        if !self.options.is_included(relative_path, directory) {
+            return Ok(false);
+        }
+
+        if !directory {
+            return Ok(match vexfat.map_file_with_name(dir_cluster, host_path, name) {
+                Ok(_) => true,
+                // file was replaced on the host
+                Err(FileDirectoryEntryError::DuplicateName) => {
+                    vexfat.replace_file(dir_cluster, name, host_path).is_ok()
+                }
+                Err(_) => false,
+            });
+        }
+
+        let first_cluster = match std::fs::metadata(host_path)
+            .and_then(|metadata| EntryTimes::from_metadata(&metadata))
+            .map_err(FileDirectoryEntryError::IoError)
+            .and_then(|times| vexfat.add_directory_with_times(dir_cluster, name, &times))
+        {
+            Ok(first_cluster) => first_cluster,
+            Err(_) => return Ok(false),
+        };
+        // whatever the directory already contains is not reported by inotify
+        let report = match vexfat.map_directory(first_cluster, host_path, &self.options) {
+            Ok(report) => report,
+            Err(FileDirectoryEntryError::IoError(_)) => MapDirectoryReport {
+                directories: vec![(host_path.to_path_buf(), first_cluster)],
+                ..Default::default()
+            },
+            Err(_) => return Ok(true),
+        };
+        for (host_path, dir_cluster) in report.directories {
+            match self.watch(&host_path, dir_cluster) {
+                Ok(()) => {}
+                // gone already, its deletion is still pending
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Rename within the watched tree, returns the number of applied changes
+    fn rename(
+        &mut self,
+        vexfat: &mut VirtualExFatBlockDevice,
+        from: &MovedFrom,
+        host_path: &Path,
+        dir_cluster: u32,
+        name: &str,
+    ) -> io::Result<usize> {
+        // excluded under its new name
+        let relative_path = host_path.strip_prefix(&self.root).unwrap_or(host_path);
+        if !self.options.is_included(relative_path, from.directory) {
+            return Ok(usize::from(self.remove_moved(vexfat, from)));
+        }
+
+        let mut result = vexfat.rename(from.dir_cluster, &from.name, dir_cluster, name);
+        if matches!(result, Err(FileDirectoryEntryError::DuplicateName)) {
+            // renamed over an existing file or empty directory, which is replaced
+            self.remove(vexfat, host_path, dir_cluster, name, from.directory);
+            result = vexfat.rename(from.dir_cluster, &from.name, dir_cluster, name);
+        }
+
+        match result {
+            Ok(()) => {
+                self.rewatch(&from.host_path, host_path);
+                Ok(1)
+            }
+            // not on the volume under its old name, e.g. because it was excluded
+            Err(FileDirectoryEntryError::NotFound) => {
+                self.remove(vexfat, host_path, dir_cluster, name, from.directory);
+                self.add(vexfat, host_path, dir_cluster, name, from.directory).map(usize::from)
+            }
+            // new name can't be represented on the volume
+            Err(_) => Ok(usize::from(self.remove_moved(vexfat, from))),
+        }
+    }
+
+    /// Remove file or directory deleted or moved out of the watched tree, returns whether it was removed
+    fn remove(
+        &mut self,
+        vexfat: &mut VirtualExFatBlockDevice,
+        host_path: &Path,
+        dir_cluster: u32,
+        name: &str,
+        directory: bool,
+    ) -> bool {
+        if !directory {
+            return vexfat.remove(dir_cluster, name).is_ok();
+        }
+
+        self.unwatch(host_path);
+        vexfat.remove_dir_all(dir_cluster, name).is_ok()
+    }
+
+    /// Remove file or directory under the name it was moved from
+    fn remove_moved(&mut self, vexfat: &mut VirtualExFatBlockDevice, from: &MovedFrom) -> bool {
+        self.remove(vexfat, &from.host_path, from.dir_cluster, &from.name, from.directory)
+    }
+
+    /// Directory was renamed, watched paths in it follow
+    fn rewatch(&mut self, old_path: &Path, new_path: &Path) {
+        for (path, _) in self.directories.values_mut() {
+            if let Ok(relative_path) = path.strip_prefix(old_path) {
+                *path = new_path.join(relative_path);
+            }
+        }
+    }
+}
+
+impl AsRawFd for DirectoryWatcher {
+    /// Readable when there are changes to apply
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+}
+
+#[test]
+fn directory_watcher() {
+    let host_dir = crate::utils::TempPath::new("watch");
+    std::fs::create_dir_all(host_dir.join("sub")).unwrap();
+    std::fs::write(host_dir.join("file"), [1; 100]).unwrap();
+    std::fs::write(host_dir.join("gone"), [2; 100]).unwrap();
+
+    let mut vexfat = VirtualExFatBlockDevice::new_with_serial_number(9, 3, 512, 0).unwrap();
+    let root_cluster = vexfat.root_directory_cluster();
+    let options = MapDirectoryOptions::default().exclude("*.tmp").unwrap();
+    let report = vexfat.map_directory(root_cluster, &host_dir, &options).unwrap();
+    let mut watcher = DirectoryWatcher::new(&report, &options).unwrap();
+    assert_eq!(watcher.apply(&mut vexfat).unwrap(), 0);
+
+    std::fs::write(host_dir.join("file"), [1; 5000]).unwrap();
+    std::fs::rename(host_dir.join("file"), host_dir.join("sub").join("moved")).unwrap();
+    std::fs::remove_file(host_dir.join("gone")).unwrap();
+    std::fs::write(host_dir.join("ignored.tmp"), [3; 100]).unwrap();
+    std::fs::create_dir(host_dir.join("new")).unwrap();
+    std::fs::write(host_dir.join("new").join("inner"), [4; 100]).unwrap();
+    std::fs::rename(host_dir.join("sub"), host_dir.join("renamed")).unwrap();
+
+    let tree = |vexfat: &VirtualExFatBlockDevice| {
+        let mut tree: Vec<(PathBuf, u64)> = vexfat
+            .heap
+            .tree()
+            .into_iter()
+            .map(|entry| (entry.path, entry.data_length))
+            .collect();
+        tree.sort();
+        tree
+    };
+
+    assert!(watcher.apply(&mut vexfat).unwrap() > 0);
+    assert_eq!(
+        tree(&vexfat),
+        [
+            (PathBuf::from("new"), 4096),
+            (Path::new("new").join("inner"), 100),
+            (PathBuf::from("renamed"), 4096),
+            (Path::new("renamed").join("moved"), 5000),
+        ]
+    );
+
+    // the renamed directory is still watched
+    let change_count = watcher.change_count();
+    std::fs::write(host_dir.join("renamed").join("added"), [5; 100]).unwrap();
+    assert_eq!(watcher.apply(&mut vexfat).unwrap(), 2); // created and modified
+    assert_eq!(watcher.change_count(), change_count + 2);
+    assert!(vexfat.heap.tree().iter().any(|entry| entry.path == Path::new("renamed").join("added")));
+
+    // saved by writing an excluded temporary file and renaming it over the file
+    let renamed = host_dir.join("renamed");
+    std::fs::write(renamed.join("added.tmp"), [6; 300]).unwrap();
+    std::fs::rename(renamed.join("added.tmp"), renamed.join("added")).unwrap();
+    // renamed over another mapped file, and renamed to an excluded name
+    std::fs::rename(renamed.join("added"), renamed.join("moved")).unwrap();
+    std::fs::write(renamed.join("other"), [7; 50]).unwrap();
+    std::fs::rename(renamed.join("other"), renamed.join("other.tmp")).unwrap();
+    watcher.apply(&mut vexfat).unwrap();
+    assert_eq!(
+        tree(&vexfat),
+        [
+            (PathBuf::from("new"), 4096),
+            (Path::new("new").join("inner"), 100),
+            (PathBuf::from("renamed"), 4096),
+            (Path::new("renamed").join("moved"), 300),
+        ]
+    );
+
+    std::fs::remove_dir_all(&host_dir).unwrap();
+    watcher.apply(&mut vexfat).unwrap();
+    assert!(vexfat.heap.tree().is_empty());
+}